@@ -0,0 +1,24 @@
+#!/usr/bin/env bash
+# Deploys the standard ICRC-1/ICRC-2 share ledger of a batch and registers it with the backend.
+# Usage: ./scripts/deploy_share_ledger.sh <offer_id> [admin identity]
+# On a local replica the ledger canister is created with provisional cycles. Elsewhere, create it
+# beforehand (e.g. `dfx ledger create-canister`) and pass its id as SHARE_LEDGER_ID.
+set -euo pipefail
+
+OFFER_ID=$1
+ADMIN=${2:-$(dfx identity whoami)}
+TOKEN="shares:batch_$OFFER_ID"
+BACKEND=$(dfx canister id harvestx_backend)
+
+cargo build --release --target wasm32-unknown-unknown -p share_ledger >&2
+WASM=target/wasm32-unknown-unknown/release/share_ledger.wasm
+
+if [ -z "${SHARE_LEDGER_ID:-}" ]; then
+  SHARE_LEDGER_ID=$(dfx --identity "$ADMIN" canister call aaaaa-aa provisional_create_canister_with_cycles \
+    '(record { amount = null; settings = null })' | grep -o 'principal "[^"]*"' | cut -d'"' -f2)
+fi
+dfx --identity "$ADMIN" canister install "$SHARE_LEDGER_ID" --wasm "$WASM" \
+  --argument "(record { backend = principal \"$BACKEND\"; token_id = \"$TOKEN\" })" >&2
+dfx --identity "$ADMIN" canister call harvestx_backend register_share_ledger \
+  "(\"$TOKEN\", principal \"$SHARE_LEDGER_ID\")" | grep -q "success = true"
+echo "$SHARE_LEDGER_ID"
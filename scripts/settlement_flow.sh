@@ -0,0 +1,366 @@
+#!/usr/bin/env bash
+# End-to-end escrow settlement against the bundled mock ledger on a local replica.
+# Usage: dfx start --background --clean && ./scripts/settlement_flow.sh
+set -euo pipefail
+
+for id in hx-admin hx-farmer hx-investor hx-inspector hx-verifier hx-leaver; do
+  dfx identity new "$id" --storage-mode plaintext >/dev/null 2>&1 || true
+done
+ADMIN=$(dfx --identity hx-admin identity get-principal)
+
+dfx deploy mock_ledger
+dfx deploy mock_ckusdc
+dfx deploy harvestx_backend --argument "(opt record { admins = vec { principal \"$ADMIN\" } })"
+LEDGER=$(dfx canister id mock_ledger)
+CKUSDC=$(dfx canister id mock_ckusdc)
+BACKEND=$(dfx canister id harvestx_backend)
+
+call() {
+  local who=$1
+  shift
+  dfx --identity "$who" canister call harvestx_backend "$@"
+}
+
+# The install argument made hx-admin an admin; nobody can register as one
+call hx-admin get_current_user | grep -q "Admin"
+call hx-admin set_ledger_canister "(principal \"$LEDGER\")"
+call hx-farmer register_user '(record { role = variant { Admin }; display_name = "Farmer"; email = "farmer@harvestx.local" })' | grep -q "Admins are appointed"
+call hx-farmer register_user '(record { role = variant { Farmer }; display_name = "Farmer"; email = "farmer@harvestx.local" })'
+# Admins manage the other roles, but only controllers grant Admin
+FARMER=$(dfx --identity hx-farmer identity get-principal)
+call hx-admin update_user_role "(principal \"$FARMER\", variant { Admin })" | grep -q "Only controllers"
+echo "OK: admins bootstrapped from the install argument"
+call hx-investor register_user '(record { role = variant { Investor }; display_name = "Investor"; email = "investor@harvestx.local" })'
+# Farmers take on the Investor role themselves and keep Farmer
+call hx-farmer add_role '(variant { Investor })' | grep -q "Investor"
+call hx-farmer get_current_user | grep -q "Farmer"
+call hx-farmer remove_role '(variant { Investor })'
+call hx-farmer remove_role '(variant { Farmer })' | grep -q "at least one role"
+echo "OK: farmer held the Farmer and Investor roles"
+
+# The anonymous principal reads public data but cannot register or act
+if dfx --identity anonymous canister call harvestx_backend register_user '(record { role = variant { Investor }; display_name = "Anon"; email = "anon@harvestx.local" })' 2>/dev/null; then
+  echo "FAIL: anonymous caller registered" && exit 1
+fi
+dfx --identity anonymous canister call harvestx_backend get_available_offers | grep -q "success = true"
+echo "OK: anonymous caller rejected"
+
+OFFER_ID=$(call hx-farmer create_agricultural_offer '(record {
+  product_name = "Wheat";
+  product_type = variant { Grains };
+  total_quantity = 1000 : nat64;
+  price_per_kg = 50_000_000 : nat;
+  description = "Winter wheat";
+  harvest_date = "2026-07-01";
+  location = "Nile Delta";
+  quality_grade = variant { Grade1 };
+  minimum_investment = 10 : nat64;
+})' | grep -o 'offer_[0-9]\+' | head -1)
+
+# Offers for verified investors only turn the investor away until a verifier approves their KYC
+KYC_OFFER_ID=$(call hx-farmer create_agricultural_offer '(record {
+  product_name = "Barley";
+  product_type = variant { Grains };
+  total_quantity = 500 : nat64;
+  price_per_kg = 40_000_000 : nat;
+  description = "Spring barley";
+  harvest_date = "2026-08-01";
+  location = "Nile Delta";
+  quality_grade = variant { Grade1 };
+  minimum_investment = 10 : nat64;
+  verified_investors_only = opt true;
+})' | grep -o 'offer_[0-9]\+' | head -1)
+KYC_REQUEST="(record {
+  offer_id = \"$KYC_OFFER_ID\";
+  requested_quantity = 10 : nat64;
+  offered_price_per_kg = 40_000_000 : nat;
+  message = \"KYC test\";
+})"
+call hx-investor create_investment_request "$KYC_REQUEST" | grep -q "requires a verified KYC"
+VERIFIER=$(dfx --identity hx-verifier identity get-principal)
+INVESTOR=$(dfx --identity hx-investor identity get-principal)
+call hx-verifier register_user '(record { role = variant { Guest }; display_name = "Verifier"; email = "verifier@harvestx.local" })'
+call hx-verifier add_role '(variant { Verifier })'
+call hx-admin grant_role "(principal \"$VERIFIER\", variant { Verifier })" | grep -q "Verifier"
+DOC_HASH=$(echo -n "passport scan" | sha256sum | cut -d' ' -f1)
+call hx-investor submit_kyc "(vec { \"$DOC_HASH\" })" | grep -q "Pending"
+call hx-verifier get_pending_kyc_reviews | grep -q "$INVESTOR"
+call hx-verifier approve_kyc "(principal \"$INVESTOR\")" | grep -q "Verified"
+call hx-investor create_investment_request "$KYC_REQUEST" | grep -q "success = true"
+echo "OK: verified-investor offer enforced KYC"
+
+REQUEST_ID=$(call hx-investor create_investment_request "(record {
+  offer_id = \"$OFFER_ID\";
+  requested_quantity = 100 : nat64;
+  offered_price_per_kg = 50_000_000 : nat;
+  message = \"Local settlement test\";
+})" | grep -o 'req_[0-9]\+' | head -1)
+
+call hx-farmer respond_to_investment_request "(record { request_id = \"$REQUEST_ID\"; accept = true })"
+
+DEPOSIT=$(call hx-investor get_deposit_info "(\"$REQUEST_ID\")")
+SUB_HEX=$(echo "$DEPOSIT" | grep -o 'subaccount_hex = "[0-9a-f]*"' | cut -d'"' -f2)
+AMOUNT=$(echo "$DEPOSIT" | grep -o 'expected_amount = [0-9_]*' | awk '{print $3}' | tr -d _)
+SUB_BLOB=$(echo "$SUB_HEX" | sed 's/../\\&/g')
+
+# A short deposit must be rejected
+dfx canister call mock_ledger mint "(record { owner = principal \"$BACKEND\"; subaccount = opt blob \"$SUB_BLOB\" }, $((AMOUNT - 1)) : nat)"
+call hx-farmer settle_request "(\"$REQUEST_ID\")" | grep -q "Insufficient deposit"
+echo "OK: short deposit rejected"
+
+# Only the request's investor, the transaction's farmer and admins may settle
+call hx-inspector settle_request "(\"$REQUEST_ID\")" | grep -q "Access denied"
+call hx-admin get_permissions | grep -q "SettleRequest"
+call hx-farmer get_permissions | grep -q "Admin role required"
+echo "OK: permission matrix enforced"
+
+# Top up the escrow subaccount, overpaying, and settle
+OVERPAYMENT=1000000
+dfx canister call mock_ledger mint "(record { owner = principal \"$BACKEND\"; subaccount = opt blob \"$SUB_BLOB\" }, $((1 + OVERPAYMENT)) : nat)"
+SETTLED=$(call hx-farmer settle_request "(\"$REQUEST_ID\")")
+echo "$SETTLED" | grep -q "Tokenized"
+echo "OK: request $REQUEST_ID settled"
+
+# Delivery confirmation releases the escrow to the farmer
+TXN_ID=$(echo "$SETTLED" | grep -o 'txn_[0-9]\+' | head -1)
+call hx-investor confirm_delivery "(\"$TXN_ID\")" | grep -q "Completed"
+echo "OK: transaction $TXN_ID released to the farmer"
+
+# The release sent the overpayment back to the investor, net of the ledger fee, and emptied the escrow
+FEE=$(dfx canister call mock_ledger icrc1_fee | grep -o '[0-9_]\+' | head -1 | tr -d _)
+balance() {
+  dfx canister call mock_ledger icrc1_balance_of "(record { owner = principal \"$1\"; subaccount = $2 })" | grep -o '[0-9_]\+' | head -1 | tr -d _
+}
+[ "$(balance "$INVESTOR" null)" = "$((OVERPAYMENT - FEE))" ]
+[ "$(balance "$BACKEND" "opt blob \"$SUB_BLOB\"")" = "0" ]
+echo "OK: overpayment refunded to the investor"
+
+# The escrow record keeps the deposit and the payout, with ledger block indices
+ESCROW=$(call hx-farmer get_escrow "(\"$REQUEST_ID\")")
+echo "$ESCROW" | grep -q "Released"
+echo "$ESCROW" | grep -q "Deposit"
+echo "$ESCROW" | grep -q "Refund"
+echo "OK: escrow history recorded for $REQUEST_ID"
+
+# Settlement moved 100 shares of the batch to the investor, who can pass them on with ICRC-1
+SHARES="shares:batch_$OFFER_ID"
+call hx-investor shares_icrc1_balance_of "(\"$SHARES\", record { owner = principal \"$INVESTOR\"; subaccount = null })" | grep -q "(100 : nat)"
+call hx-investor shares_icrc1_transfer "(\"$SHARES\", record { to = record { owner = principal \"$FARMER\"; subaccount = null }; amount = 40 : nat })" | grep -q "Ok"
+call hx-investor shares_icrc1_balance_of "(\"$SHARES\", record { owner = principal \"$INVESTOR\"; subaccount = null })" | grep -q "(60 : nat)"
+echo "OK: shares of $SHARES transferred"
+
+# The farmer's unsold shares stay locked while the offer is active: only the 40 received are spendable
+call hx-farmer shares_icrc1_transfer "(\"$SHARES\", record { to = record { owner = principal \"$INVESTOR\"; subaccount = null }; amount = 41 : nat })" | grep -q "InsufficientFunds"
+echo "OK: unsold shares locked"
+
+# ICRC-2 on shares: the investor lets a custodian (here the admin identity) move 10 shares
+CUSTODIAN=$(dfx --identity hx-admin identity get-principal)
+call hx-investor shares_icrc2_approve "(\"$SHARES\", record { spender = record { owner = principal \"$CUSTODIAN\"; subaccount = null }; amount = 10 : nat; expected_allowance = opt (0 : nat) })" | grep -q "Ok"
+call hx-admin shares_icrc2_transfer_from "(\"$SHARES\", record { from = record { owner = principal \"$INVESTOR\"; subaccount = null }; to = record { owner = principal \"$CUSTODIAN\"; subaccount = null }; amount = 10 : nat })" | grep -q "Ok"
+call hx-admin shares_icrc2_transfer_from "(\"$SHARES\", record { from = record { owner = principal \"$INVESTOR\"; subaccount = null }; to = record { owner = principal \"$CUSTODIAN\"; subaccount = null }; amount = 1 : nat })" | grep -q "InsufficientAllowance"
+call hx-investor shares_icrc1_balance_of "(\"$SHARES\", record { owner = principal \"$INVESTOR\"; subaccount = null })" | grep -q "(50 : nat)"
+echo "OK: share allowance spent by the custodian"
+
+# Wallets move shares through the batch's own ICRC-1 ledger, with the standard methods
+SHARE_LEDGER=$(./scripts/deploy_share_ledger.sh "$OFFER_ID" hx-admin)
+share_ledger() {
+  local who=$1
+  shift
+  dfx --identity "$who" canister call --candid src/share_ledger/share_ledger.did "$SHARE_LEDGER" "$@"
+}
+call hx-investor get_share_ledger "(\"$SHARES\")" | grep -q "$SHARE_LEDGER"
+# Reads are answered once the ledger replayed the backend's block log
+until share_ledger hx-investor icrc1_total_supply >/dev/null 2>&1; do sleep 1; done
+share_ledger hx-investor icrc1_name | grep -q "HarvestX"
+share_ledger hx-investor icrc1_supported_standards | grep -q "ICRC-1"
+share_ledger hx-investor icrc1_balance_of "(record { owner = principal \"$INVESTOR\"; subaccount = null })" | grep -q "(50 : nat)"
+share_ledger hx-investor icrc1_transfer "(record { to = record { owner = principal \"$CUSTODIAN\"; subaccount = null }; amount = 5 : nat })" | grep -q "Ok"
+call hx-investor shares_icrc1_balance_of "(\"$SHARES\", record { owner = principal \"$INVESTOR\"; subaccount = null })" | grep -q "(45 : nat)"
+share_ledger hx-investor icrc1_balance_of "(record { owner = principal \"$INVESTOR\"; subaccount = null })" | grep -q "(45 : nat)"
+# Only the registered ledger may act on a holder's behalf
+call hx-investor share_ledger_transfer "(principal \"$FARMER\", record { to = record { owner = principal \"$INVESTOR\"; subaccount = null }; amount = 1 : nat })" | grep -q "not a registered share ledger"
+echo "OK: shares moved through the share ledger $SHARE_LEDGER"
+
+# Every share operation is in the ICRC-3 log: issue, settlement, transfer, approval, transfer_from
+BLOCKS=$(call hx-investor icrc3_get_blocks '(vec { record { start = 0 : nat; length = 100 : nat } })')
+for btype in 1mint 1xfer 2approve 2xfer; do
+  echo "$BLOCKS" | grep -q "\"$btype\""
+done
+echo "$BLOCKS" | grep -q "phash"
+echo "OK: share operations recorded in the block log"
+
+# The farmer records the harvest; the investor, a share holder, can trace the batch
+NOW=$(($(date +%s) * 1000000000))
+call hx-farmer record_provenance_event "(record {
+  batch_id = \"batch_$OFFER_ID\";
+  kind = variant { Harvest };
+  occurred_at = $NOW : nat64;
+  location = \"Nile Delta, field 4\";
+  attachment_hash = opt \"$(printf 'harvest report' | sha256sum | cut -d' ' -f1)\";
+  notes = null;
+})" | grep -q "Harvest"
+call hx-investor record_provenance_event "(record { batch_id = \"batch_$OFFER_ID\"; kind = variant { Storage }; occurred_at = $NOW : nat64; location = \"Cairo\"; attachment_hash = null; notes = null })" | grep -q "Only the batch owner"
+call hx-investor get_batch_provenance "(\"batch_$OFFER_ID\")" | grep -q "field 4"
+call hx-investor get_traceable_batches | grep -q "batch_$OFFER_ID"
+echo "OK: batch provenance recorded and traced"
+
+# An inspector appointed by the admin certifies the batch; the offer shows the grade until revoked
+INSPECTOR=$(dfx --identity hx-inspector identity get-principal)
+call hx-inspector register_user '(record { role = variant { Inspector }; display_name = "Inspector"; email = "inspector@harvestx.local" })' | grep -q "appointed by an admin"
+call hx-inspector register_user '(record { role = variant { Guest }; display_name = "Inspector"; email = "inspector@harvestx.local" })'
+call hx-inspector add_role '(variant { Inspector })'
+call hx-inspector get_current_user | grep -A2 "pending_roles" | grep -q "Inspector"
+call hx-admin get_pending_role_requests | grep -q "$INSPECTOR"
+call hx-admin grant_role "(principal \"$INSPECTOR\", variant { Inspector })" | grep -q "Inspector"
+CERT_ID=$(call hx-inspector issue_quality_certificate "(record {
+  subject = variant { Batch = \"batch_$OFFER_ID\" };
+  grade = variant { Premium };
+  expires_at = $((NOW + 86400 * 1000000000)) : nat64;
+  report_hash = null;
+  notes = opt \"Moisture 12%\";
+})" | grep -o 'cert_[0-9]\+' | head -1)
+call hx-investor get_offer_by_id "(\"$OFFER_ID\")" | grep -A1 "verified_grade" | grep -q "Premium"
+call hx-inspector revoke_quality_certificate "(\"$CERT_ID\", \"Sample contaminated\")" | grep -q "revoked_at = opt"
+call hx-investor get_offer_by_id "(\"$OFFER_ID\")" | grep -q "verified_grade = null"
+echo "OK: batch certified by the inspector, then revoked"
+
+# The offer's batch is an ICRC-7 token owned by the farmer; ownership moves with icrc7_transfer
+BATCH_TID=${OFFER_ID#offer_}
+call hx-investor icrc7_owner_of "(vec { $BATCH_TID : nat })" | grep -q "$FARMER"
+call hx-investor icrc7_token_metadata "(vec { $BATCH_TID : nat })" | grep -q "harvestx:total_quantity_kg"
+call hx-investor icrc7_transfer "(vec { record { to = record { owner = principal \"$INVESTOR\"; subaccount = null }; token_id = $BATCH_TID : nat } })" | grep -q "Unauthorized"
+call hx-farmer icrc7_transfer "(vec { record { to = record { owner = principal \"$CUSTODIAN\"; subaccount = null }; token_id = $BATCH_TID : nat } })" | grep -q "Ok"
+call hx-investor icrc7_tokens_of "(record { owner = principal \"$CUSTODIAN\"; subaccount = null }, null, null)" | tr -d _ | grep -q "$BATCH_TID"
+call hx-investor icrc3_get_blocks '(vec { record { start = 0 : nat; length = 100 : nat } })' | grep -q '"7xfer"'
+echo "OK: batch NFT transferred and logged"
+
+# Anyone can verify the batch, with a certified record
+call hx-inspector get_batch_verification "(\"batch_$OFFER_ID\")" | grep -q "$CUSTODIAN"
+# ... without the provenance timeline, which only the batch's stakeholders read
+PUBLIC_RECORD=$(call hx-inspector get_batch_verification "(\"batch_$OFFER_ID\")")
+echo "$PUBLIC_RECORD" | grep -q "provenance_hash"
+if echo "$PUBLIC_RECORD" | grep -q "field 4"; then exit 1; fi
+VERIFIED=$(call hx-inspector verify_batch "(\"batch_$OFFER_ID\")")
+echo "$VERIFIED" | grep -q "success = true"
+echo "$VERIFIED" | grep -q "hash_tree"
+echo "OK: batch verification certified"
+
+# The same data as JSON over HTTP: batches come certified, offers and stats through an update call
+HTTP="http://$BACKEND.localhost:$(dfx info webserver-port)"
+curl -sfD - "$HTTP/batches/batch_$OFFER_ID" | grep -qi "^ic-certificate"
+curl -sf "$HTTP/batches/batch_$OFFER_ID" | grep -q "\"owner\":\"$CUSTODIAN\""
+curl -sf "$HTTP/offers/$OFFER_ID" | grep -q "\"id\":\"$OFFER_ID\""
+curl -sf "$HTTP/offers" | grep -q "$OFFER_ID"
+curl -sf "$HTTP/stats" | grep -q "total_offers"
+[ "$(curl -s -o /dev/null -w '%{http_code}' "$HTTP/offers/offer_missing")" = "404" ]
+echo "OK: HTTP API served"
+
+# ICRC-2 path: the investor approves the backend, the farmer's acceptance pulls the funds
+REQUEST_ID=$(call hx-investor create_investment_request "(record {
+  offer_id = \"$OFFER_ID\";
+  requested_quantity = 50 : nat64;
+  offered_price_per_kg = 50_000_000 : nat;
+  message = \"ICRC-2 settlement test\";
+  payment_method = opt variant { Icrc2Approval };
+})" | grep -o 'req_[0-9]\+' | head -1)
+AMOUNT=$(call hx-investor get_deposit_info "(\"$REQUEST_ID\")" | grep -o 'expected_amount = [0-9_]*' | awk '{print $3}' | tr -d _)
+INVESTOR_BALANCE=$(balance "$INVESTOR" null)
+
+dfx canister call mock_ledger mint "(record { owner = principal \"$INVESTOR\"; subaccount = null }, $((AMOUNT + 2 * FEE)) : nat)"
+dfx --identity hx-investor canister call mock_ledger icrc2_approve "(record {
+  spender = record { owner = principal \"$BACKEND\"; subaccount = null };
+  amount = $((AMOUNT + FEE)) : nat;
+})"
+call hx-farmer respond_to_investment_request "(record { request_id = \"$REQUEST_ID\"; accept = true })" | grep -q "Accepted"
+call hx-investor get_investor_transactions | grep -A12 "$REQUEST_ID" | grep -q "Tokenized"
+# The pull was charged once, with the deduplication arguments kept on the request
+[ "$(balance "$INVESTOR" null)" = "$INVESTOR_BALANCE" ]
+call hx-investor get_investor_requests | grep -A20 "$REQUEST_ID" | grep -q "payment_pull = opt record"
+echo "OK: request $REQUEST_ID paid via ICRC-2 and tokenized on acceptance"
+
+# Refunds: a deposit made ahead of a rejection goes back to the investor, net of the ledger fee
+REQUEST_ID=$(call hx-investor create_investment_request "(record {
+  offer_id = \"$OFFER_ID\";
+  requested_quantity = 20 : nat64;
+  offered_price_per_kg = 50_000_000 : nat;
+  message = \"Refund test\";
+})" | grep -o 'req_[0-9]\+' | head -1)
+DEPOSIT=$(call hx-investor get_deposit_info "(\"$REQUEST_ID\")")
+SUB_BLOB=$(echo "$DEPOSIT" | grep -o 'subaccount_hex = "[0-9a-f]*"' | cut -d'"' -f2 | sed 's/../\\&/g')
+AMOUNT=$(echo "$DEPOSIT" | grep -o 'expected_amount = [0-9_]*' | awk '{print $3}' | tr -d _)
+dfx canister call mock_ledger mint "(record { owner = principal \"$BACKEND\"; subaccount = opt blob \"$SUB_BLOB\" }, $AMOUNT : nat)"
+
+call hx-farmer respond_to_investment_request "(record { request_id = \"$REQUEST_ID\"; accept = false })" | grep -q "Rejected"
+call hx-investor get_refund "(\"$REQUEST_ID\")" | grep -q "Completed"
+# Retrying returns the recorded refund instead of paying twice
+call hx-investor refund_request "(\"$REQUEST_ID\")" | grep -q "Completed"
+call hx-investor get_escrow "(\"$REQUEST_ID\")" | grep -q "Refunded"
+echo "OK: rejected request $REQUEST_ID refunded"
+
+# Stablecoin offers: amounts are in the token's own units (6 decimals for ckUSDC)
+call hx-admin add_payment_token "(\"ckUSDC\", principal \"$CKUSDC\")" | grep -q "decimals = 6"
+USDC_OFFER_ID=$(call hx-farmer create_agricultural_offer '(record {
+  product_name = "Olives";
+  product_type = variant { Fruits };
+  total_quantity = 500 : nat64;
+  price_per_kg = 2_000_000 : nat;
+  description = "Table olives";
+  harvest_date = "2026-10-15";
+  location = "Siwa";
+  quality_grade = variant { Premium };
+  minimum_investment = 10 : nat64;
+  payment_token = opt "ckUSDC";
+})' | grep -o 'offer_[0-9]\+' | head -1)
+REQUEST_ID=$(call hx-investor create_investment_request "(record {
+  offer_id = \"$USDC_OFFER_ID\";
+  requested_quantity = 10 : nat64;
+  offered_price_per_kg = 2_000_000 : nat;
+  message = \"ckUSDC settlement test\";
+})" | grep -o 'req_[0-9]\+' | head -1)
+call hx-farmer respond_to_investment_request "(record { request_id = \"$REQUEST_ID\"; accept = true })"
+DEPOSIT=$(call hx-investor get_deposit_info "(\"$REQUEST_ID\")")
+SUB_BLOB=$(echo "$DEPOSIT" | grep -o 'subaccount_hex = "[0-9a-f]*"' | cut -d'"' -f2 | sed 's/../\\&/g')
+AMOUNT=$(echo "$DEPOSIT" | grep -o 'expected_amount = [0-9_]*' | awk '{print $3}' | tr -d _)
+[ "$AMOUNT" = "20000000" ]
+dfx canister call mock_ckusdc mint "(record { owner = principal \"$BACKEND\"; subaccount = opt blob \"$SUB_BLOB\" }, $AMOUNT : nat)"
+call hx-farmer settle_request "(\"$REQUEST_ID\")" | grep -q "Tokenized"
+echo "OK: ckUSDC request $REQUEST_ID settled"
+
+# Users fix their profile, leave, and have their personal data erased; requests stay on record
+LEAVER=$(dfx --identity hx-leaver identity get-principal)
+call hx-leaver register_user '(record { role = variant { Investor }; display_name = "Leaver"; email = "leaver@harvestx" })'
+call hx-leaver update_profile '(record { email = opt "leaver-at-harvestx.local"; display_name = null })' | grep -q "Invalid email"
+call hx-leaver update_profile '(record { email = opt "leaver@harvestx.local"; display_name = null })' | grep -q "leaver@harvestx.local"
+LEAVER_REQUEST_ID=$(call hx-leaver create_investment_request "(record {
+  offer_id = \"$USDC_OFFER_ID\";
+  requested_quantity = 10 : nat64;
+  offered_price_per_kg = 2_000_000 : nat;
+  message = \"Call me on +20 100 000 0000\";
+})" | grep -o 'req_[0-9]\+' | head -1)
+call hx-leaver deactivate_account | grep -q "deactivated_at = opt"
+call hx-leaver create_investment_request "(record {
+  offer_id = \"$USDC_OFFER_ID\";
+  requested_quantity = 10 : nat64;
+  offered_price_per_kg = 2_000_000 : nat;
+  message = \"\";
+})" | grep -q "role required"
+call hx-leaver reactivate_account | grep -q "deactivated_at = null"
+call hx-leaver request_erasure | grep -q "erased_at = opt"
+call hx-leaver get_current_user | grep -q 'email = ""'
+call hx-leaver reactivate_account | grep -q "cannot be reactivated"
+call hx-farmer get_requests_for_offer "(\"$USDC_OFFER_ID\")" | grep -A12 "$LEAVER_REQUEST_ID" | grep -q 'message = ""'
+echo "OK: account deactivated and erased, request $LEAVER_REQUEST_ID kept"
+
+# Banned principals are turned away everywhere, public reads included
+call hx-farmer ban_user "(principal \"$LEAVER\", \"Spam\")" | grep -q "Admin role required"
+call hx-admin ban_user "(principal \"$LEAVER\", \"Spam\")" | grep -q "Spam"
+if call hx-leaver get_current_user 2>/dev/null; then
+  echo "FAIL: banned caller read their profile" && exit 1
+fi
+if call hx-leaver get_available_offers 2>/dev/null; then
+  echo "FAIL: banned caller read public offers" && exit 1
+fi
+call hx-admin get_banned_users | grep -q "$LEAVER"
+call hx-admin unban_user "(principal \"$LEAVER\")"
+call hx-leaver get_current_user | grep -q "success = true"
+echo "OK: principal banned, then unbanned"
@@ -0,0 +1,113 @@
+#!/usr/bin/env bash
+# Upgrade test: installs an older backend revision, fills it with records in the old storage format
+# (f64 amounts, unversioned Candid), upgrades to the working tree and checks that the migrations
+# converted everything and that the upgraded canister keeps working.
+# Usage: dfx start --background --clean && ./scripts/upgrade_test.sh [old-git-ref]
+set -euo pipefail
+
+# Last revision storing f64 amounts and records without a versioned envelope
+OLD_REF=${1:-e877ba2}
+ROOT=$(git rev-parse --show-toplevel)
+OLD_TREE=$(mktemp -d)
+trap 'git -C "$ROOT" worktree remove --force "$OLD_TREE"' EXIT
+
+git -C "$ROOT" worktree add --detach "$OLD_TREE" "$OLD_REF"
+(cd "$OLD_TREE" && cargo build --release --target wasm32-unknown-unknown -p harvestx_backend)
+(cd "$ROOT" && cargo build --release --target wasm32-unknown-unknown -p harvestx_backend)
+OLD_WASM=$OLD_TREE/target/wasm32-unknown-unknown/release/harvestx_backend.wasm
+OLD_DID=$OLD_TREE/src/HarvestX_backend/HarvestX_backend.did
+NEW_WASM=$ROOT/target/wasm32-unknown-unknown/release/harvestx_backend.wasm
+
+dfx deploy mock_ledger
+dfx canister create harvestx_backend
+dfx canister install harvestx_backend --mode reinstall --yes --wasm "$OLD_WASM"
+LEDGER=$(dfx canister id mock_ledger)
+BACKEND=$(dfx canister id harvestx_backend)
+
+for id in hx-admin hx-farmer hx-investor; do
+  dfx identity new "$id" --storage-mode plaintext >/dev/null 2>&1 || true
+done
+
+old_call() {
+  local who=$1
+  shift
+  dfx --identity "$who" canister call --candid "$OLD_DID" harvestx_backend "$@"
+}
+
+call() {
+  local who=$1
+  shift
+  dfx --identity "$who" canister call harvestx_backend "$@"
+}
+
+upgrade() {
+  dfx canister install harvestx_backend --mode upgrade --yes --wasm "$NEW_WASM"
+}
+
+# --- Old revision: records with f64 prices and totals ---
+old_call hx-admin register_user '(record { role = variant { Admin }; display_name = "Admin"; email = "admin@harvestx.local" })'
+old_call hx-admin set_ledger_canister "(principal \"$LEDGER\")"
+old_call hx-farmer register_user '(record { role = variant { Farmer }; display_name = "Farmer"; email = "farmer@harvestx.local" })'
+old_call hx-investor register_user '(record { role = variant { Investor }; display_name = "Investor"; email = "investor@harvestx.local" })'
+
+OFFER_ID=$(old_call hx-farmer create_agricultural_offer '(record {
+  product_name = "Wheat";
+  product_type = variant { Grains };
+  total_quantity = 1000 : nat64;
+  price_per_kg = 0.5 : float64;
+  description = "Winter wheat";
+  harvest_date = "2099-07-01";
+  location = "Nile Delta";
+  quality_grade = variant { Grade1 };
+  minimum_investment = 10 : nat64;
+})' | grep -o 'offer_[0-9]\+' | head -1)
+
+# Funded request: accepted, deposited and settled before the upgrade
+FUNDED_ID=$(old_call hx-investor create_investment_request "(record {
+  offer_id = \"$OFFER_ID\";
+  requested_quantity = 100 : nat64;
+  offered_price_per_kg = 0.5 : float64;
+  message = \"Funded before upgrade\";
+})" | grep -o 'req_[0-9]\+' | head -1)
+old_call hx-farmer respond_to_investment_request "(record { request_id = \"$FUNDED_ID\"; accept = true })"
+SUB_BLOB=$(old_call hx-investor get_deposit_info "(\"$FUNDED_ID\")" | grep -o 'subaccount_hex = "[0-9a-f]*"' | cut -d'"' -f2 | sed 's/../\\&/g')
+dfx canister call mock_ledger mint "(record { owner = principal \"$BACKEND\"; subaccount = opt blob \"$SUB_BLOB\" }, 5_000_000_000 : nat)"
+TXN_ID=$(old_call hx-farmer settle_request "(\"$FUNDED_ID\")" | grep -o 'txn_[0-9]\+' | head -1)
+
+# Pending request, left untouched
+PENDING_ID=$(old_call hx-investor create_investment_request "(record {
+  offer_id = \"$OFFER_ID\";
+  requested_quantity = 20 : nat64;
+  offered_price_per_kg = 0.45 : float64;
+  message = \"Pending across the upgrade\";
+})" | grep -o 'req_[0-9]\+' | head -1)
+
+# --- Upgrade to the working tree ---
+upgrade
+
+call hx-farmer get_offer_by_id "(\"$OFFER_ID\")" | grep -q "price_per_kg = 50_000_000"
+echo "OK: offer price converted to base units"
+
+REQUESTS=$(call hx-investor get_investor_requests)
+echo "$REQUESTS" | grep -q "total_offered = 5_000_000_000"
+echo "$REQUESTS" | grep -q "offered_price_per_kg = 45_000_000"
+echo "$REQUESTS" | grep -q "total_offered = 900_000_000"
+echo "OK: request prices and totals converted exactly"
+
+call hx-admin get_canister_config | grep -q "$LEDGER"
+echo "OK: configuration kept"
+
+call hx-investor get_escrow "(\"$FUNDED_ID\")" | grep -q "Funded"
+call hx-investor get_escrow "(\"$PENDING_ID\")" | grep -q "AwaitingDeposit"
+echo "OK: escrow records backfilled"
+
+# Migrated records are still writable: release the funded escrow to the farmer
+call hx-investor confirm_delivery "(\"$TXN_ID\")" | grep -q "Completed"
+echo "OK: migrated transaction $TXN_ID released"
+
+# A second upgrade has no migrations left to run and leaves the data as it was
+BEFORE=$(call hx-investor get_investor_requests)
+upgrade
+AFTER=$(call hx-investor get_investor_requests)
+[ "$BEFORE" = "$AFTER" ]
+echo "OK: repeated upgrade is a no-op"
@@ -0,0 +1,355 @@
+use candid::Principal;
+
+use crate::auth;
+use crate::permissions::{self, Operation};
+use crate::types::*;
+use crate::{get_current_time, BANS, REQUESTS, USERS};
+
+// -----------------------------
+// Accounts
+// -----------------------------
+// Users edit their profile and may leave the platform. A deactivated account loses the powers of its
+// roles: it cannot create offers or requests, and its offers take no new requests, but it can still
+// finish what it started (settlements, deliveries, escrow releases, refunds), since those are
+// authorized by ownership. Erasure cannot be undone: it deactivates the account and scrubs its
+// personal data, i.e. the display name, email, KYC document hashes and rejection reason, and the
+// messages of the user's investment requests. Offers, requests, transactions, escrows and shares
+// keep referring to the principal, so every transaction stays intact. Admins ban principals, which
+// blocks them from every endpoint (see auth.rs).
+
+const MAX_DISPLAY_NAME_LEN: usize = 100;
+const MAX_EMAIL_LEN: usize = 254;
+const MAX_BAN_REASON_LEN: usize = 500;
+
+pub fn get_profile(principal: &Principal) -> Result<UserProfile, String> {
+    USERS
+        .with(|users| users.borrow().get(principal))
+        .ok_or_else(|| "User not found".to_string())
+}
+
+/// Stores the profile, stamping `updated_at`.
+pub fn save_profile(mut profile: UserProfile) -> UserProfile {
+    profile.updated_at = get_current_time();
+    USERS.with(|users| {
+        users
+            .borrow_mut()
+            .insert(profile.principal, profile.clone())
+    });
+    profile
+}
+
+pub fn is_banned(principal: &Principal) -> bool {
+    BANS.with(|bans| bans.borrow().contains_key(principal))
+}
+
+/// Fails for deactivated and erased accounts.
+pub fn ensure_active(profile: &UserProfile) -> Result<(), String> {
+    if profile.erased_at.is_some() {
+        return Err("Account erased".to_string());
+    }
+    if profile.deactivated_at.is_some() {
+        return Err("Account deactivated".to_string());
+    }
+    Ok(())
+}
+
+/// Whether `principal` takes part in the platform: not banned, and not deactivated if registered.
+pub fn is_active_user(principal: &Principal) -> bool {
+    !is_banned(principal)
+        && USERS
+            .with(|users| users.borrow().get(principal))
+            .is_none_or(|profile| ensure_active(&profile).is_ok())
+}
+
+pub fn validate_contact(display_name: &str, email: &str) -> Result<(), String> {
+    if display_name.len() > MAX_DISPLAY_NAME_LEN {
+        return Err(format!(
+            "Display name is longer than {} bytes",
+            MAX_DISPLAY_NAME_LEN
+        ));
+    }
+    if email.len() > MAX_EMAIL_LEN {
+        return Err(format!("Email is longer than {} bytes", MAX_EMAIL_LEN));
+    }
+    if !email.is_empty() && !email.contains('@') {
+        return Err("Invalid email address".to_string());
+    }
+    Ok(())
+}
+
+/// Changes the caller's display name and email.
+pub fn update(caller: Principal, request: UpdateProfileRequest) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    ensure_active(&profile)?;
+    if let Some(display_name) = request.display_name {
+        profile.display_name = display_name;
+    }
+    if let Some(email) = request.email {
+        profile.email = email;
+    }
+    validate_contact(&profile.display_name, &profile.email)?;
+    Ok(save_profile(profile))
+}
+
+pub fn deactivate(caller: Principal) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    ensure_active(&profile)?;
+    profile.deactivated_at = Some(get_current_time());
+    Ok(save_profile(profile))
+}
+
+pub fn reactivate(caller: Principal) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    if profile.erased_at.is_some() {
+        return Err("Erased accounts cannot be reactivated".to_string());
+    }
+    if profile.deactivated_at.is_none() {
+        return Err("Account is active".to_string());
+    }
+    profile.deactivated_at = None;
+    Ok(save_profile(profile))
+}
+
+/// Deactivates the caller's account for good and scrubs its personal data.
+pub fn erase(caller: Principal) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    if profile.erased_at.is_some() {
+        return Err("Account already erased".to_string());
+    }
+    let now = get_current_time();
+    profile.display_name.clear();
+    profile.email.clear();
+    profile.kyc.document_hashes.clear();
+    profile.kyc.rejection_reason = None;
+    profile.deactivated_at.get_or_insert(now);
+    profile.erased_at = Some(now);
+
+    REQUESTS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        let scrubbed: Vec<InvestmentRequest> = requests
+            .iter()
+            .filter(|(_, req)| req.investor == caller && !req.message.is_empty())
+            .map(|(_, mut req)| {
+                req.message.clear();
+                req.updated_at = now;
+                req
+            })
+            .collect();
+        for req in scrubbed {
+            requests.insert(req.id.clone(), req);
+        }
+    });
+
+    Ok(save_profile(profile))
+}
+
+/// Bans a principal from every endpoint.
+pub fn ban(admin: Principal, principal: Principal, reason: String) -> Result<Ban, String> {
+    permissions::authorize(&admin, Operation::ManageBans, &[])?;
+    if auth::is_anonymous(&principal) {
+        return Err("The anonymous principal cannot be banned".to_string());
+    }
+    if principal == admin {
+        return Err("Admins cannot ban themselves".to_string());
+    }
+    if permissions::is_super_admin(&principal) {
+        return Err("Controllers cannot be banned".to_string());
+    }
+    if permissions::has_role(&principal, &UserRole::Admin) && !permissions::is_super_admin(&admin) {
+        return Err("Only controllers can ban an admin".to_string());
+    }
+    if is_banned(&principal) {
+        return Err("Principal already banned".to_string());
+    }
+    if reason.trim().is_empty() {
+        return Err("A ban reason is required".to_string());
+    }
+    if reason.len() > MAX_BAN_REASON_LEN {
+        return Err(format!(
+            "Reason is longer than {} bytes",
+            MAX_BAN_REASON_LEN
+        ));
+    }
+
+    let ban = Ban {
+        principal,
+        reason,
+        banned_by: admin,
+        banned_at: get_current_time(),
+    };
+    BANS.with(|bans| bans.borrow_mut().insert(principal, ban.clone()));
+    Ok(ban)
+}
+
+/// Lifts a ban, returning it.
+pub fn unban(admin: Principal, principal: Principal) -> Result<Ban, String> {
+    permissions::authorize(&admin, Operation::ManageBans, &[])?;
+    BANS.with(|bans| bans.borrow_mut().remove(&principal))
+        .ok_or_else(|| "Principal is not banned".to_string())
+}
+
+pub fn bans(admin: Principal) -> Result<Vec<Ban>, String> {
+    permissions::authorize(&admin, Operation::ManageBans, &[])?;
+    Ok(BANS.with(|bans| bans.borrow().iter().map(|(_, ban)| ban).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, principal};
+    use crate::{share_ledgers, ESCROWS, SHARE_LEDGERS};
+
+    fn admin() -> Principal {
+        let admin = principal(100);
+        testing::register(admin, &[UserRole::Admin]);
+        admin
+    }
+
+    // An investor with a pending KYC submission and a request for 100 kg of an offer
+    fn investor_with_request() -> (Principal, InvestmentRequest) {
+        let farmer = principal(2);
+        testing::register(farmer, &[UserRole::Farmer]);
+        testing::offer("offer_1", farmer);
+        let investor = principal(1);
+        testing::register(investor, &[UserRole::Investor]);
+        crate::kyc::submit(investor, vec!["ab".repeat(32)]).unwrap();
+
+        testing::set_caller(investor);
+        let response = crate::create_investment_request(CreateInvestmentRequest {
+            offer_id: "offer_1".to_string(),
+            requested_quantity: 100,
+            offered_price_per_kg: Amount::from_base_units(10_000_000),
+            message: "Call me at +20 100 000 0000".to_string(),
+            payment_method: None,
+        });
+        (investor, response.data.unwrap())
+    }
+
+    #[test]
+    fn erasure_scrubs_personal_data_and_keeps_financial_records() {
+        let (investor, request) = investor_with_request();
+        let escrow = ESCROWS.with(|e| e.borrow().get(&request.id)).unwrap();
+
+        let profile = erase(investor).unwrap();
+        assert!(profile.display_name.is_empty());
+        assert!(profile.email.is_empty());
+        assert!(profile.kyc.document_hashes.is_empty());
+        assert_eq!(profile.kyc.rejection_reason, None);
+        assert_eq!(profile.erased_at, Some(testing::now()));
+        assert_eq!(profile.deactivated_at, Some(testing::now()));
+        assert_eq!(profile.roles, vec![UserRole::Investor]);
+
+        let erased = REQUESTS.with(|r| r.borrow().get(&request.id)).unwrap();
+        assert!(erased.message.is_empty());
+        assert_eq!(erased.investor, investor);
+        assert_eq!(erased.requested_quantity, request.requested_quantity);
+        assert_eq!(erased.total_offered, request.total_offered);
+        assert!(matches!(erased.status, RequestStatus::Pending));
+        let kept = ESCROWS.with(|e| e.borrow().get(&request.id)).unwrap();
+        assert_eq!(format!("{:?}", kept), format!("{:?}", escrow));
+
+        assert_eq!(erase(investor).unwrap_err(), "Account already erased");
+    }
+
+    #[test]
+    fn deactivated_accounts_may_come_back() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Farmer]);
+        assert_eq!(reactivate(user).unwrap_err(), "Account is active");
+
+        deactivate(user).unwrap();
+        assert!(!is_active_user(&user));
+        assert_eq!(deactivate(user).unwrap_err(), "Account deactivated");
+        let request = UpdateProfileRequest {
+            display_name: Some("Renamed".to_string()),
+            email: None,
+        };
+        assert_eq!(update(user, request).unwrap_err(), "Account deactivated");
+
+        let profile = reactivate(user).unwrap();
+        assert_eq!(profile.deactivated_at, None);
+        assert!(is_active_user(&user));
+    }
+
+    #[test]
+    fn erased_accounts_cannot_come_back() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Farmer]);
+        deactivate(user).unwrap();
+        erase(user).unwrap();
+        assert_eq!(
+            reactivate(user).unwrap_err(),
+            "Erased accounts cannot be reactivated"
+        );
+        assert!(!is_active_user(&user));
+    }
+
+    #[test]
+    fn banned_principals_are_refused() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Investor]);
+        let ledger = principal(50);
+        SHARE_LEDGERS.with(|l| {
+            l.borrow_mut()
+                .insert(ledger, "shares:batch_offer_1".to_string())
+        });
+
+        testing::set_caller(user);
+        assert_eq!(auth::not_banned(), Ok(()));
+        assert_eq!(
+            share_ledgers::forwarded_token(&ledger, &user),
+            Ok("shares:batch_offer_1".to_string())
+        );
+
+        let ban = ban(admin(), user, "Fraud".to_string()).unwrap();
+        assert_eq!(ban.banned_by, principal(100));
+        assert!(is_banned(&user));
+        assert!(!is_active_user(&user));
+        assert_eq!(auth::not_banned(), Err("Account banned".to_string()));
+        assert_eq!(auth::authenticated(), Err("Account banned".to_string()));
+        assert_eq!(
+            share_ledgers::forwarded_token(&ledger, &user),
+            Err("Account banned".to_string())
+        );
+        assert!(!auth::accepts_ingress(&user, "get_available_offers"));
+
+        unban(admin(), user).unwrap();
+        assert_eq!(auth::not_banned(), Ok(()));
+    }
+
+    #[test]
+    fn bans_spare_controllers_and_need_a_reason() {
+        let admin = admin();
+        let controller = principal(3);
+        testing::add_controller(controller);
+        let other_admin = principal(4);
+        testing::register(other_admin, &[UserRole::Admin]);
+        let user = principal(1);
+
+        assert_eq!(
+            ban(admin, admin, "Test".to_string()).unwrap_err(),
+            "Admins cannot ban themselves"
+        );
+        assert_eq!(
+            ban(admin, controller, "Test".to_string()).unwrap_err(),
+            "Controllers cannot be banned"
+        );
+        assert_eq!(
+            ban(admin, other_admin, "Test".to_string()).unwrap_err(),
+            "Only controllers can ban an admin"
+        );
+        assert_eq!(
+            ban(admin, user, " ".to_string()).unwrap_err(),
+            "A ban reason is required"
+        );
+        assert_eq!(
+            ban(user, user, "Test".to_string()).unwrap_err(),
+            "Admin role required"
+        );
+        ban(controller, other_admin, "Rogue admin".to_string()).unwrap();
+        assert_eq!(
+            ban(controller, other_admin, "Again".to_string()).unwrap_err(),
+            "Principal already banned"
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use candid::Principal;
+
+use crate::accounts;
+
+// -----------------------------
+// Authentication
+// -----------------------------
+// Every endpoint needs a caller identity except the public reads in ANONYMOUS_METHODS. Endpoints
+// enforce it with `guard = "authenticated"`, which covers queries and inter-canister calls too.
+// Principals banned by an admin are turned away by every endpoint: `authenticated` rejects them,
+// and the public reads carry `guard = "not_banned"`. `inspect_message` additionally drops anonymous
+// ingress messages to any other method, and every message from a banned principal, before they
+// reach consensus, so rejected calls cost the canister nothing.
+
+/// Methods the anonymous principal may call: public queries, and `http_request_update`, which the
+/// HTTP gateway calls anonymously. A method listed here carries the `not_banned` guard instead of
+/// `authenticated`.
+pub const ANONYMOUS_METHODS: &[&str] = &[
+    // Offers and platform data
+    "get_available_offers",
+    "get_offer_by_id",
+    "get_payment_tokens",
+    "get_platform_stats",
+    "get_canister_config",
+    "health_check",
+    // Share ledgers (ICRC-1, ICRC-2 and ICRC-3 reads)
+    "shares_icrc1_name",
+    "shares_icrc1_symbol",
+    "shares_icrc1_decimals",
+    "shares_icrc1_fee",
+    "shares_icrc1_metadata",
+    "shares_icrc1_total_supply",
+    "shares_icrc1_minting_account",
+    "shares_icrc1_balance_of",
+    "shares_icrc2_allowance",
+    "shares_icrc1_supported_standards",
+    "get_share_ledger",
+    "icrc3_get_blocks",
+    "icrc3_get_tip_certificate",
+    "icrc3_get_archives",
+    "icrc3_supported_block_types",
+    // Batch NFT collection (ICRC-7 reads)
+    "icrc7_collection_metadata",
+    "icrc7_symbol",
+    "icrc7_name",
+    "icrc7_description",
+    "icrc7_logo",
+    "icrc7_total_supply",
+    "icrc7_supply_cap",
+    "icrc7_max_query_batch_size",
+    "icrc7_max_update_batch_size",
+    "icrc7_default_take_value",
+    "icrc7_max_take_value",
+    "icrc7_max_memo_size",
+    "icrc7_atomic_batch_transfers",
+    "icrc7_tx_window",
+    "icrc7_permitted_drift",
+    "icrc7_token_metadata",
+    "icrc7_owner_of",
+    "icrc7_balance_of",
+    "icrc7_tokens",
+    "icrc7_tokens_of",
+    "icrc10_supported_standards",
+    // Public verification
+    "get_quality_certificates",
+    "get_batch_verification",
+    "verify_batch",
+    // HTTP API
+    "http_request",
+    "http_request_update",
+];
+
+pub fn is_anonymous(principal: &Principal) -> bool {
+    *principal == Principal::anonymous()
+}
+
+/// Guard for every endpoint that needs a caller identity.
+pub fn authenticated() -> Result<(), String> {
+    if is_anonymous(&crate::get_caller()) {
+        return Err("Authentication required".to_string());
+    }
+    not_banned()
+}
+
+/// Guard for the public reads in ANONYMOUS_METHODS.
+pub fn not_banned() -> Result<(), String> {
+    if accounts::is_banned(&crate::get_caller()) {
+        return Err("Account banned".to_string());
+    }
+    Ok(())
+}
+
+/// Whether an ingress message to `method` should be accepted for execution.
+pub fn accepts_ingress(caller: &Principal, method: &str) -> bool {
+    !accounts::is_banned(caller) && (!is_anonymous(caller) || ANONYMOUS_METHODS.contains(&method))
+}
@@ -0,0 +1,178 @@
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree};
+use serde::Serialize;
+use std::borrow::Cow;
+
+use crate::icrc3::{self, DataCertificate};
+use crate::{CERTIFIED_ASSETS, CERTIFIED_BATCHES};
+
+// -----------------------------
+// Certified data
+// -----------------------------
+// The canister certifies one hash tree, whose root forks into:
+// - "batches": batch id -> SHA-256 of the batch's verification record (see verification.rs)
+// - "http_assets": URL path -> SHA-256 of the HTTP response body (certification v1, see http.rs)
+// - the ICRC-3 tip of the block log: "last_block_hash" and "last_block_index" (LEB128)
+// Labels stay in this order (sorted) so that every witness reconstructs the same root. The tree is
+// rebuilt from stable state, so `refresh` must run after every change to a certified value and
+// after each install or upgrade.
+
+const BATCHES_LABEL: &[u8] = b"batches";
+const ASSETS_LABEL: &[u8] = b"http_assets";
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn tip_tree<'a>() -> HashTree<'a> {
+    match icrc3::tip() {
+        Some((index, hash)) => fork(
+            labeled(
+                b"last_block_hash",
+                HashTree::Leaf(Cow::Owned(hash.to_vec())),
+            ),
+            labeled(
+                b"last_block_index",
+                HashTree::Leaf(Cow::Owned(leb128(index))),
+            ),
+        ),
+        None => HashTree::Empty,
+    }
+}
+
+fn batches_hash() -> Hash {
+    CERTIFIED_BATCHES.with(|t| labeled_hash(BATCHES_LABEL, &t.borrow().root_hash()))
+}
+
+fn assets_hash() -> Hash {
+    CERTIFIED_ASSETS.with(|t| labeled_hash(ASSETS_LABEL, &t.borrow().root_hash()))
+}
+
+/// Sets the canister's certified data to the root hash of the current tree.
+pub fn refresh() {
+    let data = fork_hash(&batches_hash(), &assets_hash());
+    let root = fork_hash(&data, &tip_tree().reconstruct());
+    // Unit tests run natively, without the system API (see testing.rs)
+    #[cfg(not(test))]
+    ic_cdk::api::set_certified_data(&root);
+    #[cfg(test)]
+    crate::testing::set_certified_data(&root);
+}
+
+#[cfg(not(test))]
+fn data_certificate() -> Option<Vec<u8>> {
+    ic_cdk::api::data_certificate()
+}
+
+#[cfg(test)]
+fn data_certificate() -> Option<Vec<u8>> {
+    crate::testing::data_certificate()
+}
+
+/// Records the verification hash of a batch (None removes it). Takes effect at the next `refresh`.
+pub fn set_batch_hash(batch_id: &str, hash: Option<Hash>) {
+    CERTIFIED_BATCHES.with(|t| {
+        let mut tree = t.borrow_mut();
+        match hash {
+            Some(hash) => tree.insert(batch_id.to_string(), hash),
+            None => tree.delete(batch_id.as_bytes()),
+        }
+    });
+}
+
+pub fn batch_hash(batch_id: &str) -> Option<Hash> {
+    CERTIFIED_BATCHES.with(|t| t.borrow().get(batch_id.as_bytes()).copied())
+}
+
+/// Records the body hash of an HTTP response (None removes it). Takes effect at the next `refresh`.
+pub fn set_asset_hash(path: &str, hash: Option<Hash>) {
+    CERTIFIED_ASSETS.with(|t| {
+        let mut tree = t.borrow_mut();
+        match hash {
+            Some(hash) => tree.insert(path.to_string(), hash),
+            None => tree.delete(path.as_bytes()),
+        }
+    });
+}
+
+pub fn asset_hash(path: &str) -> Option<Hash> {
+    CERTIFIED_ASSETS.with(|t| t.borrow().get(path.as_bytes()).copied())
+}
+
+fn cbor<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut serializer = serde_cbor::Serializer::new(vec![]);
+    serializer
+        .self_describe()
+        .expect("failed to write CBOR tag");
+    value
+        .serialize(&mut serializer)
+        .expect("failed to serialize hash tree");
+    serializer.into_inner()
+}
+
+/// ICRC-3 tip certificate. Only available in query calls, and only once the log has a block.
+pub fn tip_certificate() -> Option<DataCertificate> {
+    let certificate = data_certificate()?;
+    let tree = tip_tree();
+    if matches!(tree, HashTree::Empty) {
+        return None;
+    }
+    let data = fork_hash(&batches_hash(), &assets_hash());
+    let witness = fork(HashTree::Pruned(data), tree);
+    Some(DataCertificate {
+        certificate,
+        hash_tree: cbor(&witness),
+    })
+}
+
+/// Certificate and witness of a batch's verification hash, at path ["batches", batch_id]. Only
+/// available in query calls.
+pub fn batch_certificate(batch_id: &str) -> Option<DataCertificate> {
+    let certificate = data_certificate()?;
+    let tip_hash = tip_tree().reconstruct();
+    let hash_tree = CERTIFIED_BATCHES.with(|t| {
+        let tree = t.borrow();
+        let witness = fork(
+            fork(
+                labeled(BATCHES_LABEL, tree.witness(batch_id.as_bytes())),
+                HashTree::Pruned(assets_hash()),
+            ),
+            HashTree::Pruned(tip_hash),
+        );
+        cbor(&witness)
+    });
+    Some(DataCertificate {
+        certificate,
+        hash_tree,
+    })
+}
+
+/// Certificate and witness of an HTTP response body, at path ["http_assets", path]. Only
+/// available in query calls.
+pub fn asset_certificate(path: &str) -> Option<DataCertificate> {
+    let certificate = data_certificate()?;
+    let tip_hash = tip_tree().reconstruct();
+    let hash_tree = CERTIFIED_ASSETS.with(|t| {
+        let tree = t.borrow();
+        let witness = fork(
+            fork(
+                HashTree::Pruned(batches_hash()),
+                labeled(ASSETS_LABEL, tree.witness(path.as_bytes())),
+            ),
+            HashTree::Pruned(tip_hash),
+        );
+        cbor(&witness)
+    });
+    Some(DataCertificate {
+        certificate,
+        hash_tree,
+    })
+}
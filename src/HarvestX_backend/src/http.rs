@@ -0,0 +1,340 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{available_offers, certification, inspection, platform_stats, BATCHES, OFFERS};
+
+// -----------------------------
+// HTTP API
+// -----------------------------
+// Read-only JSON over `http_request`, for partners without a Candid agent:
+// - GET /offers: active offers
+// - GET /offers/{id}: one offer
+// - GET /batches/{id}: one batch NFT
+// - GET /stats: platform statistics
+// A batch only changes when it is minted or transferred, so the SHA-256 of its body is kept in the
+// certified tree under ["http_assets", "/batches/{id}"] (response verification v1) and the query
+// answers with an IC-Certificate header. Offers and stats change with every request and with time
+// (expiry, verified grades), so the query asks the gateway to upgrade the call instead:
+// `http_request_update` answers through consensus, which needs no certificate.
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+}
+
+enum Route {
+    Offers,
+    Offer(String),
+    Batch(String),
+    Stats,
+}
+
+// Path of the URL, without query string or fragment
+fn path(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or_default()
+}
+
+fn route(path: &str) -> Option<Route> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["offers"] => Some(Route::Offers),
+        ["offers", id] if !id.is_empty() => Some(Route::Offer(id.to_string())),
+        ["batches", id] if !id.is_empty() => Some(Route::Batch(id.to_string())),
+        ["stats"] => Some(Route::Stats),
+        _ => None,
+    }
+}
+
+fn batch_path(batch_id: &str) -> String {
+    format!("/batches/{}", batch_id)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).expect("failed to serialize JSON response")
+}
+
+fn batch_body(batch_id: &str) -> Option<Vec<u8>> {
+    BATCHES
+        .with(|b| b.borrow().get(&batch_id.to_string()))
+        .map(|batch| to_json(&batch))
+}
+
+/// Updates the certified body hash of a batch after it changed. Takes effect at the next
+/// `certification::refresh`.
+pub fn update_batch_hash(batch_id: &str) {
+    let hash = batch_body(batch_id).map(|body| Sha256::digest(body).into());
+    certification::set_asset_hash(&batch_path(batch_id), hash);
+}
+
+fn response(status_code: u16, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ],
+        body,
+        upgrade: None,
+    }
+}
+
+fn error(status_code: u16, message: &str) -> HttpResponse {
+    response(
+        status_code,
+        to_json(&serde_json::json!({ "error": message })),
+    )
+}
+
+fn upgrade() -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![],
+        body: vec![],
+        upgrade: Some(true),
+    }
+}
+
+// Certified response for a batch, if its body matches the certified hash
+fn certified_batch(batch_id: &str) -> Option<HttpResponse> {
+    let path = batch_path(batch_id);
+    let body = batch_body(batch_id)?;
+    let hash: [u8; 32] = Sha256::digest(&body).into();
+    if certification::asset_hash(&path) != Some(hash) {
+        return None;
+    }
+    let certificate = certification::asset_certificate(&path)?;
+    let mut response = response(200, body);
+    response.headers.push((
+        "IC-Certificate".to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            STANDARD.encode(certificate.certificate),
+            STANDARD.encode(certificate.hash_tree)
+        ),
+    ));
+    Some(response)
+}
+
+/// Answers a query: certified batches directly, everything else through an upgrade to an update call.
+pub fn serve_query(request: HttpRequest) -> HttpResponse {
+    let path = path(&request.url);
+    if request.method != "GET" {
+        return upgrade();
+    }
+    match route(path) {
+        // Only the canonical path is certified
+        Some(Route::Batch(batch_id)) if path == batch_path(&batch_id) => {
+            certified_batch(&batch_id).unwrap_or_else(upgrade)
+        }
+        _ => upgrade(),
+    }
+}
+
+/// Answers an upgraded call.
+pub fn serve_update(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        let mut response = error(405, "Method not allowed");
+        response
+            .headers
+            .push(("Allow".to_string(), "GET".to_string()));
+        return response;
+    }
+    match route(path(&request.url)) {
+        Some(Route::Offers) => response(200, to_json(&available_offers())),
+        Some(Route::Offer(offer_id)) => match OFFERS.with(|o| o.borrow().get(&offer_id)) {
+            Some(offer) => response(200, to_json(&inspection::with_verified_grade(offer))),
+            None => error(404, "Offer not found"),
+        },
+        Some(Route::Batch(batch_id)) => match batch_body(&batch_id) {
+            Some(body) => response(200, body),
+            None => error(404, "Batch not found"),
+        },
+        Some(Route::Stats) => response(200, to_json(&platform_stats())),
+        None => error(404, "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, principal};
+    use crate::types::*;
+    use crate::{verification, BatchMetadata, BatchNFT};
+
+    const BATCH_ID: &str = "batch_offer_1";
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn json(response: &HttpResponse) -> serde_json::Value {
+        serde_json::from_slice(&response.body).expect("response body is not JSON")
+    }
+
+    fn mint_batch() -> BatchNFT {
+        let batch = BatchNFT {
+            id: BATCH_ID.to_string(),
+            owner: principal(1),
+            metadata: BatchMetadata {
+                product_name: "Wheat".to_string(),
+                product_type: ProductType::Grains,
+                quality_grade: QualityGrade::Premium,
+                location: "Nile Delta".to_string(),
+                harvest_date: "2030-06-01".to_string(),
+                total_quantity: 1_000,
+                additional: None,
+            },
+            minted_at: testing::now(),
+        };
+        BATCHES.with(|b| b.borrow_mut().insert(batch.id.clone(), batch.clone()));
+        verification::certify(BATCH_ID);
+        batch
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        for url in [
+            "/",
+            "/nope",
+            "/offers/a/b",
+            "/batches",
+            "/batches/",
+            "/stats/x",
+        ] {
+            let response = serve_update(get(url));
+            assert_eq!(response.status_code, 404, "{}", url);
+            assert_eq!(json(&response)["error"], "Not found");
+        }
+        let response = serve_update(get("/offers/offer_9"));
+        assert_eq!(response.status_code, 404);
+        assert_eq!(json(&response)["error"], "Offer not found");
+        let response = serve_update(get("/batches/batch_offer_9"));
+        assert_eq!(response.status_code, 404);
+        assert_eq!(json(&response)["error"], "Batch not found");
+    }
+
+    #[test]
+    fn only_get_is_allowed() {
+        let request = HttpRequest {
+            method: "POST".to_string(),
+            ..get("/offers")
+        };
+        assert_eq!(serve_query(request.clone()).upgrade, Some(true));
+        let response = serve_update(request);
+        assert_eq!(response.status_code, 405);
+        assert_eq!(header(&response, "Allow"), Some("GET"));
+    }
+
+    #[test]
+    fn batches_are_served_certified() {
+        let batch = mint_batch();
+        for url in [
+            "/batches/batch_offer_1",
+            "/batches/batch_offer_1?format=json",
+        ] {
+            let response = serve_query(get(url));
+            assert_eq!(response.status_code, 200);
+            assert_eq!(response.upgrade, None);
+            assert_eq!(response.body, to_json(&batch));
+            assert_eq!(json(&response)["id"], BATCH_ID);
+            let certificate = header(&response, "IC-Certificate").unwrap();
+            assert!(certificate.starts_with("certificate=:"));
+            assert!(certificate.contains(":, tree=:"));
+        }
+
+        // The certified hash matches the body, under the current certified data
+        let hash: [u8; 32] = Sha256::digest(to_json(&batch)).into();
+        assert_eq!(
+            certification::asset_hash("/batches/batch_offer_1"),
+            Some(hash)
+        );
+        let certificate = certification::asset_certificate("/batches/batch_offer_1").unwrap();
+        assert_eq!(
+            certificate.certificate,
+            testing::data_certificate().unwrap()
+        );
+    }
+
+    #[test]
+    fn stale_or_non_canonical_batch_paths_are_upgraded() {
+        let mut batch = mint_batch();
+        assert_eq!(
+            serve_query(get("/batches/batch_offer_1/")).upgrade,
+            Some(true)
+        );
+        assert_eq!(
+            serve_query(get("/batches/batch_offer_9")).upgrade,
+            Some(true)
+        );
+
+        // A batch changed without being re-certified is not served from the query
+        batch.owner = principal(2);
+        BATCHES.with(|b| b.borrow_mut().insert(batch.id.clone(), batch.clone()));
+        assert_eq!(
+            serve_query(get("/batches/batch_offer_1")).upgrade,
+            Some(true)
+        );
+        let response = serve_update(get("/batches/batch_offer_1"));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(json(&response)["owner"], principal(2).to_text());
+    }
+
+    #[test]
+    fn offers_and_stats_are_upgraded() {
+        testing::offer("offer_1", principal(1));
+        for url in ["/offers", "/offers/offer_1", "/stats", "/nope"] {
+            let response = serve_query(get(url));
+            assert_eq!(response.upgrade, Some(true), "{}", url);
+            assert!(response.body.is_empty());
+        }
+        assert_eq!(serve_update(get("/offers")).upgrade, None);
+        let stats = serve_update(get("/stats"));
+        assert_eq!(stats.status_code, 200);
+        assert_eq!(json(&stats)["total_offers"], 1);
+    }
+
+    #[test]
+    fn offer_text_is_escaped_in_json_bodies() {
+        let mut offer = testing::offer("offer_1", principal(1));
+        offer.description = "Grade \"A\" wheat\\durum\n</script><b>".to_string();
+        offer.product_name = "Wheat\u{0007}".to_string();
+        OFFERS.with(|o| o.borrow_mut().insert(offer.id.clone(), offer.clone()));
+
+        let response = serve_update(get("/offers/offer_1"));
+        assert_eq!(response.status_code, 200);
+        assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+        let body = String::from_utf8(response.body.clone()).unwrap();
+        assert!(body.contains(r#""description":"Grade \"A\" wheat\\durum\n</script><b>""#));
+        assert!(body.contains(r#""product_name":"Wheat\u0007""#));
+        assert_eq!(json(&response)["description"], offer.description);
+
+        let offers = json(&serve_update(get("/offers")));
+        assert_eq!(offers[0]["description"], offer.description);
+    }
+}
@@ -0,0 +1,620 @@
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use ic_stable_structures::{storable::Bound, Storable};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+use crate::ledger::Account;
+use crate::types::{decode_record, encode_record, Versioned};
+use crate::{certification, get_current_time, SHARE_BLOCKS, TX_DEDUP};
+
+// -----------------------------
+// Block log (ICRC-3)
+// -----------------------------
+// Every share operation and batch NFT mint or transfer appends one block to an append-only log.
+// Blocks follow the ICRC-3 generic value schema and each carries the hash of its predecessor
+// ("phash"), so the log tip hash, certified by `certification`, commits to the whole history. One
+// log covers all batch share tokens, where "tx.token" names the token of each block, and the
+// batch collection (nft.rs), whose "7mint" and "7xfer" blocks carry the batch's "tx.tid".
+
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq, CandidType, Serialize, Deserialize)]
+pub enum Icrc3Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Icrc3Value>),
+    Map(Vec<(String, Icrc3Value)>),
+}
+
+impl Versioned for Icrc3Value {
+    const SCHEMA_VERSION: u8 = 1;
+}
+
+impl Storable for Icrc3Value {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        decode_record(&bytes).expect("decode share block failed")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Representation-independent hash of a value, as specified by ICRC-3.
+pub fn hash_value(value: &Icrc3Value) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    match value {
+        Icrc3Value::Blob(bytes) => hasher.update(bytes),
+        Icrc3Value::Text(text) => hasher.update(text.as_bytes()),
+        Icrc3Value::Nat(nat) => {
+            let mut buf = vec![];
+            nat.encode(&mut buf).expect("failed to encode nat");
+            hasher.update(buf);
+        }
+        Icrc3Value::Int(int) => {
+            let mut buf = vec![];
+            int.encode(&mut buf).expect("failed to encode int");
+            hasher.update(buf);
+        }
+        Icrc3Value::Array(values) => {
+            for value in values {
+                hasher.update(hash_value(value));
+            }
+        }
+        Icrc3Value::Map(entries) => {
+            let mut pairs: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = Sha256::digest(key.as_bytes()).to_vec();
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            pairs.sort();
+            for pair in pairs {
+                hasher.update(pair);
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+pub fn nat(n: u128) -> Icrc3Value {
+    Icrc3Value::Nat(Nat::from(n))
+}
+
+pub fn account_value(account: &Account) -> Icrc3Value {
+    let mut parts = vec![Icrc3Value::Blob(account.owner.as_slice().to_vec())];
+    if let Some(subaccount) = account.subaccount.filter(|s| *s != [0u8; 32]) {
+        parts.push(Icrc3Value::Blob(subaccount.to_vec()));
+    }
+    Icrc3Value::Array(parts)
+}
+
+/// One share operation, as recorded in a block's "tx" field.
+pub struct ShareTx<'a> {
+    // "1mint", "1burn", "1xfer", "2approve" or "2xfer"
+    pub btype: &'static str,
+    pub token_id: &'a str,
+    pub from: Option<&'a Account>,
+    pub to: Option<&'a Account>,
+    pub spender: Option<&'a Account>,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<&'a Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+}
+
+impl<'a> ShareTx<'a> {
+    pub fn new(btype: &'static str, token_id: &'a str, amount: u128) -> Self {
+        Self {
+            btype,
+            token_id,
+            from: None,
+            to: None,
+            spender: None,
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+            expected_allowance: None,
+            expires_at: None,
+        }
+    }
+
+    fn to_value(&self) -> Icrc3Value {
+        let mut tx = vec![
+            (
+                "token".to_string(),
+                Icrc3Value::Text(self.token_id.to_string()),
+            ),
+            ("amt".to_string(), nat(self.amount)),
+        ];
+        let accounts = [
+            ("from", self.from),
+            ("to", self.to),
+            ("spender", self.spender),
+        ];
+        for (field, account) in accounts {
+            if let Some(account) = account {
+                tx.push((field.to_string(), account_value(account)));
+            }
+        }
+        if let Some(fee) = self.fee {
+            tx.push(("fee".to_string(), nat(fee)));
+        }
+        if let Some(memo) = self.memo {
+            tx.push(("memo".to_string(), Icrc3Value::Blob(memo.clone())));
+        }
+        if let Some(created_at_time) = self.created_at_time {
+            tx.push(("ts".to_string(), nat(created_at_time as u128)));
+        }
+        if let Some(expected_allowance) = self.expected_allowance {
+            tx.push(("expected_allowance".to_string(), nat(expected_allowance)));
+        }
+        if let Some(expires_at) = self.expires_at {
+            tx.push(("expires_at".to_string(), nat(expires_at as u128)));
+        }
+        Icrc3Value::Map(tx)
+    }
+}
+
+pub fn log_length() -> u64 {
+    SHARE_BLOCKS.with(|b| b.borrow().len())
+}
+
+/// Index and hash of the last block, if any.
+pub fn tip() -> Option<(u64, [u8; 32])> {
+    SHARE_BLOCKS.with(|b| {
+        let blocks = b.borrow();
+        let last = blocks.len().checked_sub(1)?;
+        let block = blocks.get(last)?;
+        Some((last, hash_value(&block)))
+    })
+}
+
+/// Appends the block of a share operation. Returns its index, the operation's transaction index.
+pub fn append(tx: ShareTx) -> u128 {
+    append_block(tx.btype, tx.to_value())
+}
+
+/// Appends a block of type `btype`, chained to the current tip, and re-certifies the tip.
+/// Returns the block index.
+pub fn append_block(btype: &str, tx: Icrc3Value) -> u128 {
+    let mut block = vec![
+        ("btype".to_string(), Icrc3Value::Text(btype.to_string())),
+        ("ts".to_string(), nat(get_current_time() as u128)),
+        ("tx".to_string(), tx),
+    ];
+    if let Some((_, parent_hash)) = tip() {
+        block.push(("phash".to_string(), Icrc3Value::Blob(parent_hash.to_vec())));
+    }
+
+    let index = SHARE_BLOCKS.with(|b| {
+        b.borrow()
+            .append(&Icrc3Value::Map(block))
+            .expect("failed to append share block")
+    });
+    certification::refresh();
+    index as u128
+}
+
+// -----------------------------
+// Transaction deduplication
+// -----------------------------
+// Ledger calls carrying `created_at_time` (share transfers and approvals, batch NFT transfers) are
+// deduplicated within the ICRC-1 transaction window: a repeated call reports the block index of the
+// original instead of executing twice.
+
+pub const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+pub const PERMITTED_DRIFT_NANOS: u64 = 2 * 60 * 1_000_000_000;
+
+pub enum TimeError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+pub fn check_created_at_time(created_at_time: Option<u64>, now: u64) -> Result<(), TimeError> {
+    if let Some(created_at) = created_at_time {
+        if created_at.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+            return Err(TimeError::TooOld);
+        }
+        if created_at > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(TimeError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    Ok(())
+}
+
+// Forgets deduplication entries that have left the transaction window
+fn prune_dedup(now: u64) {
+    TX_DEDUP.with(|d| {
+        let mut dedup = d.borrow_mut();
+        let expired: Vec<String> = dedup
+            .iter()
+            .filter(|(_, (created_at, _))| {
+                created_at.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            dedup.remove(&key);
+        }
+    });
+}
+
+/// A call to deduplicate: its created_at_time and key in TX_DEDUP.
+pub struct Dedup {
+    created_at: u64,
+    key: String,
+}
+
+impl Dedup {
+    /// Remembers the block index of the call once it has succeeded.
+    pub fn record(self, index: u128) {
+        TX_DEDUP.with(|d| {
+            d.borrow_mut()
+                .insert(self.key, (self.created_at, index as u64))
+        });
+    }
+}
+
+/// Looks up a call by caller, `scope` (the token it acts on) and arguments. Fails with the block
+/// index of an identical call made within the window; otherwise returns the entry to record.
+/// Calls without `created_at_time` are not deduplicated.
+pub fn deduplicate<T: CandidType>(
+    caller: Principal,
+    scope: &str,
+    created_at_time: Option<u64>,
+    arg: &T,
+    now: u64,
+) -> Result<Option<Dedup>, u128> {
+    let created_at = match created_at_time {
+        Some(created_at) => created_at,
+        None => return Ok(None),
+    };
+    let bytes = candid::encode_args((caller, scope, arg)).expect("failed to encode ledger call");
+    let key = hex::encode(Sha256::digest(bytes));
+    prune_dedup(now);
+    if let Some((_, duplicate_of)) = TX_DEDUP.with(|d| d.borrow().get(&key)) {
+        return Err(duplicate_of as u128);
+    }
+    Ok(Some(Dedup { created_at, key }))
+}
+
+// -----------------------------
+// ICRC-3 API types
+// -----------------------------
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Icrc3Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(Debug, Clone, CandidType, Deserialize)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    // Always empty: the log is not archived
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: Nat,
+    pub end: Nat,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct BlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+fn to_u64(n: &Nat) -> u64 {
+    u64::try_from(n.0.clone()).unwrap_or(u64::MAX)
+}
+
+/// Blocks in the requested ranges, at most `MAX_BLOCKS_PER_RESPONSE` in total.
+pub fn get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = log_length();
+    let mut blocks = vec![];
+    SHARE_BLOCKS.with(|b| {
+        let log = b.borrow();
+        for range in args {
+            let start = to_u64(&range.start);
+            let end = start.saturating_add(to_u64(&range.length)).min(log_length);
+            for id in start..end {
+                if blocks.len() as u64 >= MAX_BLOCKS_PER_RESPONSE {
+                    return;
+                }
+                if let Some(block) = log.get(id) {
+                    blocks.push(BlockWithId {
+                        id: Nat::from(id),
+                        block,
+                    });
+                }
+            }
+        }
+    });
+
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        archived_blocks: vec![],
+    }
+}
+
+pub fn supported_block_types() -> Vec<BlockType> {
+    let icrc3_url = "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3";
+    let icrc7_url = "https://github.com/dfinity/ICRC/ICRCs/ICRC-7";
+    [
+        ("1mint", icrc3_url),
+        ("1burn", icrc3_url),
+        ("1xfer", icrc3_url),
+        ("2approve", icrc3_url),
+        ("2xfer", icrc3_url),
+        ("7mint", icrc7_url),
+        ("7xfer", icrc7_url),
+    ]
+    .iter()
+    .map(|(block_type, url)| BlockType {
+        block_type: block_type.to_string(),
+        url: url.to_string(),
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{TransferArg, TransferError};
+    use crate::shares;
+    use crate::testing::{self, principal};
+
+    fn hex_hash(value: &Icrc3Value) -> String {
+        hex::encode(hash_value(value))
+    }
+
+    fn blob(hex: &str) -> Icrc3Value {
+        Icrc3Value::Blob(hex::decode(hex).unwrap())
+    }
+
+    fn field<'a>(block: &'a Icrc3Value, name: &str) -> Option<&'a Icrc3Value> {
+        match block {
+            Icrc3Value::Map(entries) => entries
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn block(index: u64) -> Icrc3Value {
+        SHARE_BLOCKS.with(|b| b.borrow().get(index)).unwrap()
+    }
+
+    // Reference values from the "Value" section of the ICRC-3 standard
+    #[test]
+    fn hashes_values_as_the_standard_specifies() {
+        assert_eq!(
+            hex_hash(&nat(42)),
+            "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+        );
+        assert_eq!(
+            hex_hash(&Icrc3Value::Int(Int::from(-42))),
+            "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc"
+        );
+        assert_eq!(
+            hex_hash(&Icrc3Value::Text("Hello, World!".to_string())),
+            "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+        );
+        assert_eq!(
+            hex_hash(&blob("01020304")),
+            "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+        );
+        assert_eq!(
+            hex_hash(&Icrc3Value::Array(vec![
+                nat(3),
+                Icrc3Value::Text("foo".to_string()),
+                blob("0506"),
+            ])),
+            "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6"
+        );
+    }
+
+    #[test]
+    fn hashes_a_sample_block_as_the_standard_specifies() {
+        let block = Icrc3Value::Map(vec![
+            (
+                "from".to_string(),
+                blob("00abcdef0012340056789a00bcdef000012345678900abcdef01"),
+            ),
+            (
+                "to".to_string(),
+                blob("00ab0def0012340056789a00bcdef000012345678900abcdef01"),
+            ),
+            ("amount".to_string(), nat(42)),
+            ("created_at".to_string(), nat(1_699_218_263)),
+            ("memo".to_string(), nat(0)),
+        ]);
+        let expected = "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75";
+        assert_eq!(hex_hash(&block), expected);
+
+        // Map entries hash the same in any order
+        if let Icrc3Value::Map(mut entries) = block {
+            entries.reverse();
+            assert_eq!(hex_hash(&Icrc3Value::Map(entries)), expected);
+        }
+    }
+
+    #[test]
+    fn accounts_omit_the_default_subaccount() {
+        let owner = principal(1);
+        let default = Account {
+            owner,
+            subaccount: Some([0; 32]),
+        };
+        assert_eq!(
+            account_value(&default),
+            Icrc3Value::Array(vec![Icrc3Value::Blob(owner.as_slice().to_vec())])
+        );
+        let sub = Account {
+            owner,
+            subaccount: Some([1; 32]),
+        };
+        assert_eq!(
+            account_value(&sub),
+            Icrc3Value::Array(vec![
+                Icrc3Value::Blob(owner.as_slice().to_vec()),
+                Icrc3Value::Blob(vec![1; 32]),
+            ])
+        );
+    }
+
+    #[test]
+    fn blocks_link_to_their_parent() {
+        assert_eq!(tip(), None);
+        let account = shares::account_of(principal(1));
+        for amount in 1..=3 {
+            testing::advance_time(1);
+            append(ShareTx {
+                to: Some(&account),
+                ..ShareTx::new("1mint", "shares:batch_offer_1", amount)
+            });
+        }
+        assert_eq!(log_length(), 3);
+
+        let first = block(0);
+        assert_eq!(field(&first, "phash"), None);
+        assert_eq!(
+            field(&first, "btype"),
+            Some(&Icrc3Value::Text("1mint".into()))
+        );
+        assert_eq!(
+            field(&first, "ts"),
+            Some(&nat(testing::START_TIME as u128 + 1))
+        );
+        for index in 1..3 {
+            assert_eq!(
+                field(&block(index), "phash"),
+                Some(&Icrc3Value::Blob(hash_value(&block(index - 1)).to_vec()))
+            );
+        }
+        assert_eq!(tip(), Some((2, hash_value(&block(2)))));
+
+        let result = get_blocks(vec![GetBlocksArgs {
+            start: Nat::from(1u64),
+            length: Nat::from(10u64),
+        }]);
+        assert_eq!(result.log_length, Nat::from(3u64));
+        assert_eq!(result.blocks.len(), 2);
+        assert_eq!(result.blocks[0].block, block(1));
+    }
+
+    #[test]
+    fn repeated_calls_are_deduplicated() {
+        let farmer = principal(1);
+        let mut offer = testing::offer("offer_1", farmer);
+        offer.status = crate::types::OfferStatus::Completed;
+        crate::OFFERS.with(|o| o.borrow_mut().insert(offer.id.clone(), offer));
+        let token_id = shares::token_id("offer_1");
+        shares::issue(&token_id, &shares::account_of(farmer), 1_000);
+
+        let arg = |memo: u8, created_at_time: Option<u64>| TransferArg {
+            from_subaccount: None,
+            to: shares::account_of(principal(2)),
+            amount: 10,
+            fee: None,
+            memo: Some(vec![memo]),
+            created_at_time,
+        };
+        let now = testing::now();
+        let first = shares::transfer(farmer, &token_id, arg(1, Some(now))).unwrap();
+        assert!(matches!(
+            shares::transfer(farmer, &token_id, arg(1, Some(now))),
+            Err(TransferError::Duplicate { duplicate_of }) if duplicate_of == first
+        ));
+
+        // Another memo, another created_at_time or no created_at_time is a new transaction
+        let second = shares::transfer(farmer, &token_id, arg(2, Some(now))).unwrap();
+        let third = shares::transfer(farmer, &token_id, arg(1, Some(now - 1))).unwrap();
+        shares::transfer(farmer, &token_id, arg(1, None)).unwrap();
+        shares::transfer(farmer, &token_id, arg(1, None)).unwrap();
+        assert_eq!((second, third), (first + 1, first + 2));
+        assert_eq!(log_length(), 6);
+        assert_eq!(
+            shares::balance_of(&token_id, &shares::account_of(principal(2))),
+            50
+        );
+
+        // Once outside the window the call is too old to be replayed
+        testing::advance_time(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS + 1);
+        assert!(matches!(
+            shares::transfer(farmer, &token_id, arg(1, Some(now))),
+            Err(TransferError::TooOld)
+        ));
+        assert!(matches!(
+            shares::transfer(
+                farmer,
+                &token_id,
+                arg(1, Some(testing::now() + PERMITTED_DRIFT_NANOS + 1))
+            ),
+            Err(TransferError::CreatedInFuture { .. })
+        ));
+    }
+
+    #[test]
+    fn deduplication_forgets_calls_that_left_the_window() {
+        let caller = principal(1);
+        let now = testing::now();
+        let dedup = deduplicate(caller, "scope", Some(now), &7u8, now).unwrap();
+        dedup.unwrap().record(5);
+        assert_eq!(
+            deduplicate(caller, "scope", Some(now), &7u8, now).err(),
+            Some(5)
+        );
+        // Other callers, scopes and arguments do not collide
+        assert!(deduplicate(principal(2), "scope", Some(now), &7u8, now).is_ok());
+        assert!(deduplicate(caller, "other", Some(now), &7u8, now).is_ok());
+        assert!(deduplicate(caller, "scope", Some(now), &8u8, now).is_ok());
+
+        let later = now + TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS + 1;
+        assert!(deduplicate(caller, "scope", Some(now), &7u8, later).is_ok());
+        assert!(TX_DEDUP.with(|d| d.borrow().is_empty()));
+    }
+}
@@ -0,0 +1,178 @@
+use candid::Principal;
+
+use crate::permissions::{self, Operation, Ownership};
+use crate::provenance::is_sha256_hex;
+use crate::types::*;
+use crate::verification;
+use crate::{generate_id, get_current_time, BATCHES, CERTIFICATES, OFFERS};
+
+// -----------------------------
+// Quality certification
+// -----------------------------
+// Inspectors certify the grade of an offer or of its batch. A certificate is valid from its issue
+// until `expires_at` or its revocation, and an offer shows a verified grade only while one is
+// valid. Certificates are kept after they lapse, as the inspection history of the subject.
+
+// Free text is bounded so that a certificate stays within its 2048-byte stable-map slot
+const MAX_GRADE_LEN: usize = 64;
+const MAX_NOTES_LEN: usize = 500;
+const MAX_REASON_LEN: usize = 500;
+
+pub fn is_valid(certificate: &QualityCertificate, now: u64) -> bool {
+    certificate.revoked_at.is_none() && certificate.expires_at > now
+}
+
+// Farmer of the offer and owner of the batch, who may not certify their own produce
+fn subject_holders(subject: &CertificationSubject) -> Result<Vec<Principal>, String> {
+    match subject {
+        CertificationSubject::Offer(offer_id) => OFFERS
+            .with(|o| o.borrow().get(offer_id))
+            .map(|offer| vec![offer.farmer])
+            .ok_or_else(|| "Offer not found".to_string()),
+        CertificationSubject::Batch(batch_id) => {
+            let batch = BATCHES
+                .with(|b| b.borrow().get(batch_id))
+                .ok_or_else(|| "Batch not found".to_string())?;
+            let farmer = batch_id
+                .strip_prefix("batch_")
+                .and_then(|offer_id| OFFERS.with(|o| o.borrow().get(&offer_id.to_string())))
+                .map(|offer| offer.farmer);
+            Ok([Some(batch.owner), farmer].into_iter().flatten().collect())
+        }
+    }
+}
+
+// Batch whose verification record lists the subject's certificates
+fn subject_batch(subject: &CertificationSubject) -> String {
+    match subject {
+        CertificationSubject::Offer(offer_id) => format!("batch_{}", offer_id),
+        CertificationSubject::Batch(batch_id) => batch_id.clone(),
+    }
+}
+
+/// Issues a certificate signed by the calling inspector.
+pub fn issue(
+    inspector: Principal,
+    request: IssueCertificateRequest,
+) -> Result<QualityCertificate, String> {
+    if subject_holders(&request.subject)?.contains(&inspector) {
+        return Err("Inspectors cannot certify their own produce".to_string());
+    }
+    let now = get_current_time();
+    if request.expires_at <= now {
+        return Err("Expiry must be in the future".to_string());
+    }
+    if let QualityGrade::Certified(grade) = &request.grade {
+        if grade.trim().is_empty() || grade.len() > MAX_GRADE_LEN {
+            return Err(format!(
+                "A certified grade of 1 to {} bytes is required",
+                MAX_GRADE_LEN
+            ));
+        }
+    }
+    if request
+        .report_hash
+        .as_ref()
+        .is_some_and(|hash| !is_sha256_hex(hash))
+    {
+        return Err("Report hash must be a hex SHA-256 digest".to_string());
+    }
+    if request
+        .notes
+        .as_ref()
+        .is_some_and(|notes| notes.len() > MAX_NOTES_LEN)
+    {
+        return Err(format!("Notes are longer than {} bytes", MAX_NOTES_LEN));
+    }
+
+    let certificate = QualityCertificate {
+        id: generate_id("cert"),
+        subject: request.subject,
+        grade: request.grade,
+        inspector,
+        issued_at: now,
+        expires_at: request.expires_at,
+        report_hash: request.report_hash.map(|hash| hash.to_lowercase()),
+        notes: request.notes,
+        revoked_at: None,
+        revocation_reason: None,
+    };
+    CERTIFICATES.with(|c| {
+        c.borrow_mut()
+            .insert(certificate.id.clone(), certificate.clone())
+    });
+    verification::certify(&subject_batch(&certificate.subject));
+    Ok(certificate)
+}
+
+/// Revokes a certificate. Only its inspector and admins can revoke.
+pub fn revoke(
+    caller: Principal,
+    certificate_id: &str,
+    reason: String,
+) -> Result<QualityCertificate, String> {
+    let mut certificate = CERTIFICATES
+        .with(|c| c.borrow().get(&certificate_id.to_string()))
+        .ok_or_else(|| "Certificate not found".to_string())?;
+    permissions::authorize(
+        &caller,
+        Operation::RevokeCertificate,
+        &[(Ownership::CertificateInspector, certificate.inspector)],
+    )?;
+    if certificate.revoked_at.is_some() {
+        return Err("Certificate is already revoked".to_string());
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LEN {
+        return Err(format!(
+            "A revocation reason of 1 to {} bytes is required",
+            MAX_REASON_LEN
+        ));
+    }
+
+    certificate.revoked_at = Some(get_current_time());
+    certificate.revocation_reason = Some(reason);
+    CERTIFICATES.with(|c| {
+        c.borrow_mut()
+            .insert(certificate.id.clone(), certificate.clone())
+    });
+    verification::certify(&subject_batch(&certificate.subject));
+    Ok(certificate)
+}
+
+/// Every certificate issued for the subject, valid or not, oldest first.
+pub fn for_subject(subject: &CertificationSubject) -> Vec<QualityCertificate> {
+    let mut certificates: Vec<QualityCertificate> = CERTIFICATES.with(|c| {
+        c.borrow()
+            .iter()
+            .filter(|(_, certificate)| certificate.subject == *subject)
+            .map(|(_, certificate)| certificate)
+            .collect()
+    });
+    certificates.sort_by_key(|certificate| certificate.issued_at);
+    certificates
+}
+
+/// Grade of the most recently issued valid certificate of an offer or of its batch.
+pub fn verified_grade(offer_id: &str) -> Option<QualityGrade> {
+    let now = get_current_time();
+    let subjects = [
+        CertificationSubject::Offer(offer_id.to_string()),
+        CertificationSubject::Batch(format!("batch_{}", offer_id)),
+    ];
+    CERTIFICATES.with(|c| {
+        c.borrow()
+            .iter()
+            .map(|(_, certificate)| certificate)
+            .filter(|certificate| {
+                subjects.contains(&certificate.subject) && is_valid(certificate, now)
+            })
+            .max_by_key(|certificate| certificate.issued_at)
+            .map(|certificate| certificate.grade)
+    })
+}
+
+/// The offer as shown to users, with its verified grade filled in.
+pub fn with_verified_grade(mut offer: InvestmentOffer) -> InvestmentOffer {
+    offer.verified_grade = verified_grade(&offer.id);
+    offer
+}
@@ -0,0 +1,313 @@
+use candid::Principal;
+
+use crate::accounts::{self, get_profile, save_profile};
+use crate::permissions::{self, Operation};
+use crate::provenance::is_sha256_hex;
+use crate::types::*;
+use crate::{get_current_time, USERS};
+
+// -----------------------------
+// KYC verification
+// -----------------------------
+// Farmers and investors verify their identity by submitting the SHA-256 digests of documents kept
+// off-chain. A verifier, appointed by an admin like inspectors are, compares them with the documents
+// and approves or rejects the submission. A rejected user may submit again, and a verifier may later
+// reject a verified user whose documents turn out to be invalid. Offers created with
+// `verified_investors_only` accept requests from verified investors only.
+
+const MAX_DOCUMENTS: usize = 5;
+const MAX_REASON_LEN: usize = 500;
+
+pub fn is_verified(principal: &Principal) -> bool {
+    USERS.with(|users| {
+        users
+            .borrow()
+            .get(principal)
+            .is_some_and(|user| user.kyc.status == KycStatus::Verified)
+    })
+}
+
+/// Submits the caller's documents for review.
+pub fn submit(caller: Principal, document_hashes: Vec<String>) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    accounts::ensure_active(&profile)?;
+    match profile.kyc.status {
+        KycStatus::Pending => return Err("KYC review already pending".to_string()),
+        KycStatus::Verified => return Err("KYC already verified".to_string()),
+        KycStatus::Unverified | KycStatus::Rejected => {}
+    }
+    if document_hashes.is_empty() || document_hashes.len() > MAX_DOCUMENTS {
+        return Err(format!("Submit between 1 and {} documents", MAX_DOCUMENTS));
+    }
+    if document_hashes.iter().any(|hash| !is_sha256_hex(hash)) {
+        return Err("Document hashes must be hex SHA-256 digests".to_string());
+    }
+    if (1..document_hashes.len()).any(|i| document_hashes[..i].contains(&document_hashes[i])) {
+        return Err("Duplicate document hash".to_string());
+    }
+
+    profile.kyc = KycRecord {
+        status: KycStatus::Pending,
+        document_hashes,
+        submitted_at: Some(get_current_time()),
+        ..KycRecord::default()
+    };
+    Ok(save_profile(profile))
+}
+
+// Profile under review by `verifier`, who may not review their own
+fn reviewed_profile(verifier: &Principal, principal: &Principal) -> Result<UserProfile, String> {
+    permissions::authorize(verifier, Operation::ReviewKyc, &[])?;
+    if verifier == principal {
+        return Err("Verifiers cannot review their own KYC".to_string());
+    }
+    get_profile(principal)
+}
+
+/// Approves a pending submission.
+pub fn approve(verifier: Principal, principal: Principal) -> Result<UserProfile, String> {
+    let mut profile = reviewed_profile(&verifier, &principal)?;
+    if profile.kyc.status != KycStatus::Pending {
+        return Err("No KYC submission pending".to_string());
+    }
+    profile.kyc.status = KycStatus::Verified;
+    profile.kyc.reviewed_by = Some(verifier);
+    profile.kyc.reviewed_at = Some(get_current_time());
+    Ok(save_profile(profile))
+}
+
+/// Rejects a pending submission, or withdraws an earlier verification.
+pub fn reject(
+    verifier: Principal,
+    principal: Principal,
+    reason: String,
+) -> Result<UserProfile, String> {
+    let mut profile = reviewed_profile(&verifier, &principal)?;
+    if !matches!(profile.kyc.status, KycStatus::Pending | KycStatus::Verified) {
+        return Err("No KYC submission to reject".to_string());
+    }
+    if reason.trim().is_empty() {
+        return Err("A rejection reason is required".to_string());
+    }
+    if reason.len() > MAX_REASON_LEN {
+        return Err(format!("Reason is longer than {} bytes", MAX_REASON_LEN));
+    }
+    profile.kyc.status = KycStatus::Rejected;
+    profile.kyc.reviewed_by = Some(verifier);
+    profile.kyc.reviewed_at = Some(get_current_time());
+    profile.kyc.rejection_reason = Some(reason);
+    Ok(save_profile(profile))
+}
+
+/// Profiles whose submission waits for a verifier, oldest first.
+pub fn pending(verifier: Principal) -> Result<Vec<UserProfile>, String> {
+    permissions::authorize(&verifier, Operation::ReviewKyc, &[])?;
+    let mut profiles: Vec<UserProfile> = USERS.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| profile.kyc.status == KycStatus::Pending)
+            .map(|(_, profile)| profile)
+            .collect()
+    });
+    profiles.sort_by_key(|profile| profile.kyc.submitted_at);
+    Ok(profiles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, principal};
+    use crate::OFFERS;
+
+    fn digest(n: u8) -> String {
+        format!("{:02x}", n).repeat(32)
+    }
+
+    fn verifier() -> Principal {
+        let verifier = principal(100);
+        testing::register(verifier, &[UserRole::Verifier]);
+        verifier
+    }
+
+    fn investor() -> Principal {
+        let investor = principal(1);
+        testing::register(investor, &[UserRole::Investor]);
+        investor
+    }
+
+    #[test]
+    fn submissions_hold_one_to_five_documents() {
+        let user = investor();
+        assert_eq!(
+            submit(user, vec![]).unwrap_err(),
+            "Submit between 1 and 5 documents"
+        );
+        let six = (1..=6).map(digest).collect();
+        assert_eq!(
+            submit(user, six).unwrap_err(),
+            "Submit between 1 and 5 documents"
+        );
+        let profile = submit(user, (1..=5).map(digest).collect()).unwrap();
+        assert_eq!(profile.kyc.status, KycStatus::Pending);
+        assert_eq!(profile.kyc.document_hashes.len(), 5);
+        assert_eq!(profile.kyc.submitted_at, Some(testing::now()));
+    }
+
+    #[test]
+    fn submissions_hold_distinct_sha256_digests() {
+        let user = investor();
+        let hashes = [
+            "not a digest".to_string(),
+            digest(1)[..63].to_string(),
+            format!("{}zz", &digest(1)[..62]),
+        ];
+        for hash in hashes {
+            assert_eq!(
+                submit(user, vec![digest(2), hash]).unwrap_err(),
+                "Document hashes must be hex SHA-256 digests"
+            );
+        }
+        assert_eq!(
+            submit(user, vec![digest(1), digest(2), digest(1)]).unwrap_err(),
+            "Duplicate document hash"
+        );
+        assert_eq!(
+            get_profile(&user).unwrap().kyc.status,
+            KycStatus::Unverified
+        );
+    }
+
+    #[test]
+    fn one_submission_is_reviewed_at_a_time() {
+        let user = investor();
+        submit(user, vec![digest(1)]).unwrap();
+        assert_eq!(
+            submit(user, vec![digest(2)]).unwrap_err(),
+            "KYC review already pending"
+        );
+        approve(verifier(), user).unwrap();
+        assert_eq!(
+            submit(user, vec![digest(2)]).unwrap_err(),
+            "KYC already verified"
+        );
+    }
+
+    #[test]
+    fn rejected_users_may_submit_again() {
+        let user = investor();
+        submit(user, vec![digest(1)]).unwrap();
+        let profile = reject(verifier(), user, "Blurred scan".to_string()).unwrap();
+        assert_eq!(profile.kyc.status, KycStatus::Rejected);
+        assert_eq!(
+            profile.kyc.rejection_reason.as_deref(),
+            Some("Blurred scan")
+        );
+
+        let profile = submit(user, vec![digest(2)]).unwrap();
+        assert_eq!(profile.kyc.status, KycStatus::Pending);
+        assert_eq!(profile.kyc.document_hashes, vec![digest(2)]);
+        assert_eq!(profile.kyc.rejection_reason, None);
+        assert_eq!(profile.kyc.reviewed_by, None);
+    }
+
+    #[test]
+    fn only_verifiers_review_submissions() {
+        let user = investor();
+        submit(user, vec![digest(1)]).unwrap();
+
+        let admin = principal(2);
+        testing::register(admin, &[UserRole::Admin]);
+        let controller = principal(3);
+        testing::add_controller(controller);
+        for reviewer in [admin, controller, user] {
+            assert_eq!(
+                approve(reviewer, user).unwrap_err(),
+                "Verifier role required"
+            );
+            assert_eq!(
+                reject(reviewer, user, "Invalid".to_string()).unwrap_err(),
+                "Verifier role required"
+            );
+            assert!(pending(reviewer).is_err());
+        }
+
+        let verifier = verifier();
+        assert_eq!(pending(verifier).unwrap().len(), 1);
+        let profile = approve(verifier, user).unwrap();
+        assert_eq!(profile.kyc.status, KycStatus::Verified);
+        assert_eq!(profile.kyc.reviewed_by, Some(verifier));
+        assert!(is_verified(&user));
+        assert!(pending(verifier).unwrap().is_empty());
+    }
+
+    #[test]
+    fn verifiers_cannot_review_themselves() {
+        let verifier = verifier();
+        submit(verifier, vec![digest(1)]).unwrap();
+        assert_eq!(
+            approve(verifier, verifier).unwrap_err(),
+            "Verifiers cannot review their own KYC"
+        );
+    }
+
+    #[test]
+    fn rejections_need_a_reason_and_a_submission() {
+        let user = investor();
+        let verifier = verifier();
+        assert_eq!(
+            reject(verifier, user, "Invalid".to_string()).unwrap_err(),
+            "No KYC submission to reject"
+        );
+        assert_eq!(
+            approve(verifier, user).unwrap_err(),
+            "No KYC submission pending"
+        );
+        submit(user, vec![digest(1)]).unwrap();
+        assert_eq!(
+            reject(verifier, user, " ".to_string()).unwrap_err(),
+            "A rejection reason is required"
+        );
+        assert_eq!(
+            reject(verifier, user, "x".repeat(501)).unwrap_err(),
+            "Reason is longer than 500 bytes"
+        );
+
+        // A verification is withdrawn by rejecting it
+        approve(verifier, user).unwrap();
+        reject(verifier, user, "Forged passport".to_string()).unwrap();
+        assert!(!is_verified(&user));
+    }
+
+    #[test]
+    fn verified_only_offers_refuse_unverified_investors() {
+        let farmer = principal(2);
+        testing::register(farmer, &[UserRole::Farmer]);
+        let mut offer = testing::offer("offer_1", farmer);
+        offer.verified_investors_only = Some(true);
+        OFFERS.with(|offers| offers.borrow_mut().insert(offer.id.clone(), offer));
+
+        let user = investor();
+        testing::set_caller(user);
+        let request = || CreateInvestmentRequest {
+            offer_id: "offer_1".to_string(),
+            requested_quantity: 100,
+            offered_price_per_kg: Amount::from_base_units(10_000_000),
+            message: String::new(),
+            payment_method: None,
+        };
+        let response = crate::create_investment_request(request());
+        assert_eq!(
+            response.error.as_deref(),
+            Some("This offer requires a verified KYC")
+        );
+
+        // A pending submission is not enough
+        submit(user, vec![digest(1)]).unwrap();
+        assert!(!crate::create_investment_request(request()).success);
+
+        approve(verifier(), user).unwrap();
+        let response = crate::create_investment_request(request());
+        assert!(response.success, "{:?}", response.error);
+    }
+}
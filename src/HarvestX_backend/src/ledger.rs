@@ -0,0 +1,132 @@
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+// -----------------------------
+// ICRC-1 ledger client
+// -----------------------------
+// Only the subset of the ICRC-1 interface the escrow flow needs. Both the ICP ledger
+// and the bundled mock ledger (src/mock_ledger) implement these methods.
+
+pub type Subaccount = [u8; 32];
+
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+/// Queries `icrc1_balance_of` on the given ledger.
+pub async fn balance_of(ledger: Principal, account: Account) -> Result<u128, String> {
+    let (balance,): (u128,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| {
+            format!("Ledger balance query failed ({:?}): {}", code, msg)
+        })?;
+    Ok(balance)
+}
+
+/// Queries `icrc1_fee` on the given ledger.
+pub async fn fee(ledger: Principal) -> Result<u128, String> {
+    let (fee,): (u128,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| format!("Ledger fee query failed ({:?}): {}", code, msg))?;
+    Ok(fee)
+}
+
+/// Queries `icrc1_decimals` on the given ledger.
+pub async fn decimals(ledger: Principal) -> Result<u8, String> {
+    let (decimals,): (u8,) = ic_cdk::call(ledger, "icrc1_decimals", ())
+        .await
+        .map_err(|(code, msg)| format!("Ledger decimals query failed ({:?}): {}", code, msg))?;
+    Ok(decimals)
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum TransferError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+/// Sends funds out of one of this canister's subaccounts with `icrc1_transfer`.
+/// Callers that retry must pass the same arguments (memo, fee and `created_at_time`
+/// included): the ledger then reports the original block as a duplicate, which is
+/// returned as success so retries are idempotent.
+pub async fn transfer(ledger: Principal, arg: TransferArg) -> Result<u128, String> {
+    let (result,): (Result<u128, TransferError>,) =
+        ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+            .await
+            .map_err(|(code, msg)| format!("Ledger transfer call failed ({:?}): {}", code, msg))?;
+
+    match result {
+        Ok(block_index) => Ok(block_index),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(err) => Err(format!("Ledger rejected transfer: {:?}", err)),
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+/// Pulls funds with `icrc2_transfer_from`, using an ICRC-2 allowance granted to this canister.
+/// Returns the ledger block index of the transfer. As with `transfer`, retries must pass the same
+/// arguments, and the original block reported as a duplicate is returned as success.
+pub async fn transfer_from(ledger: Principal, args: TransferFromArgs) -> Result<u128, String> {
+    let amount = args.amount;
+    let (result,): (Result<u128, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| {
+                format!("Ledger transfer_from call failed ({:?}): {}", code, msg)
+            })?;
+
+    match result {
+        Ok(block_index) => Ok(block_index),
+        Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(TransferFromError::InsufficientAllowance { allowance }) => Err(format!(
+            "Insufficient allowance: approved {}, need {} plus the ledger fee",
+            allowance, amount
+        )),
+        Err(TransferFromError::InsufficientFunds { balance }) => Err(format!(
+            "Insufficient funds: investor balance is {}, need {} plus the ledger fee",
+            balance, amount
+        )),
+        Err(err) => Err(format!("Ledger rejected transfer_from: {:?}", err)),
+    }
+}
@@ -1,15 +1,41 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
+use ic_certified_map::{Hash, RbTree};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl, StableBTreeMap, storable::Storable, storable::Bound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, storable::Storable, storable::Bound,
 };
 use std::cell::RefCell;
 use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use ic_cdk_timers::TimerId;
 
 use sha2::{Sha224, Digest};
-use hex;
 
+mod accounts;
+mod auth;
+mod certification;
+mod http;
+mod icrc3;
+mod inspection;
+mod kyc;
+mod ledger;
+mod migration;
+mod nft;
+mod permissions;
+mod provenance;
+mod roles;
+mod share_ledgers;
+mod shares;
 mod types;
+mod verification;
+#[cfg(test)]
+mod testing;
+use auth::{authenticated, not_banned};
+use permissions::{Operation, Ownership};
+use ledger::{Account, TransferArg, TransferError, TransferFromArgs, TransferFromError};
 use types::*;
 
 // Memory management
@@ -22,68 +48,502 @@ const TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
 
 // NEW memory slots for tokenization & escrow
 const BATCHES_MEMORY_ID: MemoryId = MemoryId::new(4);
-const SHARES_MEMORY_ID: MemoryId = MemoryId::new(5);
-const ESCROW_MEMORY_ID: MemoryId = MemoryId::new(6);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(7);
+const REFUNDS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const FEES_MEMORY_ID: MemoryId = MemoryId::new(9);
+const TREASURY_MEMORY_ID: MemoryId = MemoryId::new(10);
+const PAYMENT_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const TREASURY_WITHDRAWN_MEMORY_ID: MemoryId = MemoryId::new(12);
+const ESCROWS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const MIGRATIONS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const SHARES_TOTAL_MEMORY_ID: MemoryId = MemoryId::new(15);
+const SHARES_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(16);
+const TX_DEDUP_MEMORY_ID: MemoryId = MemoryId::new(18);
+const SHARES_ALLOWANCES_MEMORY_ID: MemoryId = MemoryId::new(19);
+const SHARE_BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(20);
+const SHARE_BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(21);
+const PROVENANCE_MEMORY_ID: MemoryId = MemoryId::new(22);
+const CERTIFICATES_MEMORY_ID: MemoryId = MemoryId::new(23);
+const BANS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const SHARE_LEDGERS_MEMORY_ID: MemoryId = MemoryId::new(25);
+
+// Stable memory layout: every structure in `thread_local!` below opens its memory through
+// `claim_memory`, which only hands out ids registered here, and each at most once.
+const MEMORY_LAYOUT: &[(MemoryId, &str)] = &[
+    (USERS_MEMORY_ID, "users"),
+    (OFFERS_MEMORY_ID, "offers"),
+    (REQUESTS_MEMORY_ID, "requests"),
+    (TRANSACTIONS_MEMORY_ID, "transactions"),
+    (BATCHES_MEMORY_ID, "batches"),
+    (CONFIG_MEMORY_ID, "config"),
+    (REFUNDS_MEMORY_ID, "refunds"),
+    (FEES_MEMORY_ID, "fees"),
+    (TREASURY_MEMORY_ID, "treasury"),
+    (PAYMENT_TOKENS_MEMORY_ID, "payment tokens"),
+    (TREASURY_WITHDRAWN_MEMORY_ID, "treasury withdrawals"),
+    (ESCROWS_MEMORY_ID, "escrows"),
+    (MIGRATIONS_MEMORY_ID, "applied migrations"),
+    (SHARES_TOTAL_MEMORY_ID, "share totals"),
+    (SHARES_BALANCES_MEMORY_ID, "share balances"),
+    (TX_DEDUP_MEMORY_ID, "transaction deduplication"),
+    (SHARES_ALLOWANCES_MEMORY_ID, "share allowances"),
+    (SHARE_BLOCKS_INDEX_MEMORY_ID, "share block log index"),
+    (SHARE_BLOCKS_DATA_MEMORY_ID, "share block log data"),
+    (PROVENANCE_MEMORY_ID, "batch provenance events"),
+    (CERTIFICATES_MEMORY_ID, "quality certificates"),
+    (BANS_MEMORY_ID, "banned principals"),
+    (SHARE_LEDGERS_MEMORY_ID, "share ledger canisters"),
+];
+
+// Ids that held data in earlier releases. They are never reused, since upgraded canisters still
+// carry the old bytes.
+const RETIRED_MEMORY_IDS: &[(MemoryId, &str)] = &[
+    // share totals and balances, both opened on this one memory; rebuilt by the
+    // "share ledger recovery" migration
+    (MemoryId::new(5), "shared share totals and balances"),
+    // escrow subaccount hex strings, superseded by ESCROWS
+    (MemoryId::new(6), "escrow subaccounts"),
+    // counter of share transaction indices, superseded by the share block log
+    (MemoryId::new(17), "next share transaction index"),
+];
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
+    // Memory ids already opened by a stable structure (see `claim_memory`)
+    static CLAIMED_MEMORIES: RefCell<BTreeSet<MemoryId>> = const { RefCell::new(BTreeSet::new()) };
+
     static USERS: RefCell<StableBTreeMap<Principal, UserProfile, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(USERS_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(USERS_MEMORY_ID))
     );
 
     static OFFERS: RefCell<StableBTreeMap<String, InvestmentOffer, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(OFFERS_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(OFFERS_MEMORY_ID))
     );
 
     static REQUESTS: RefCell<StableBTreeMap<String, InvestmentRequest, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(REQUESTS_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(REQUESTS_MEMORY_ID))
     );
 
     static TRANSACTIONS: RefCell<StableBTreeMap<String, Transaction, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(TRANSACTIONS_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(TRANSACTIONS_MEMORY_ID))
     );
 
     // NEW: store minted batch NFTs (one per offer)
     static BATCHES: RefCell<StableBTreeMap<String, BatchNFT, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(BATCHES_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(BATCHES_MEMORY_ID))
     );
 
-    // NEW: shares token info and balances
+    // NEW: shares token info and balances, each in its own memory
     // shares_total: token_id -> total supply (u128)
     static SHARES_TOTAL: RefCell<StableBTreeMap<String, u128, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(SHARES_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(SHARES_TOTAL_MEMORY_ID))
     );
     // shares_balances: composite key "token_id|principal" -> balance (u128)
     static SHARES_BALANCES: RefCell<StableBTreeMap<String, u128, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(SHARES_MEMORY_ID)))
+        StableBTreeMap::init(claim_memory(SHARES_BALANCES_MEMORY_ID))
+    );
+    // Ledger calls with created_at_time, by hash of (caller, token, args) -> (created_at_time, block index)
+    static TX_DEDUP: RefCell<StableBTreeMap<String, (u64, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(TX_DEDUP_MEMORY_ID))
+    );
+    // ICRC-2 share allowances: "token_id|owner|spender" -> allowance
+    static SHARES_ALLOWANCES: RefCell<StableBTreeMap<String, ShareAllowance, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(SHARES_ALLOWANCES_MEMORY_ID))
+    );
+    // ICRC-3 block log of every share operation and batch NFT mint or transfer; a block's index is
+    // its transaction index
+    static SHARE_BLOCKS: RefCell<StableLog<icrc3::Icrc3Value, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            claim_memory(SHARE_BLOCKS_INDEX_MEMORY_ID),
+            claim_memory(SHARE_BLOCKS_DATA_MEMORY_ID),
+        )
+        .expect("failed to initialize share block log")
+    );
+
+    // Provenance timelines of batches, by "<batch_id>|<zero-padded index>"
+    static PROVENANCE: RefCell<StableBTreeMap<String, ProvenanceEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(PROVENANCE_MEMORY_ID))
+    );
+
+    // Quality certificates issued by inspectors, by certificate id
+    static CERTIFICATES: RefCell<StableBTreeMap<String, QualityCertificate, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(CERTIFICATES_MEMORY_ID))
+    );
+
+    // Principals banned by an admin
+    static BANS: RefCell<StableBTreeMap<Principal, Ban, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(BANS_MEMORY_ID))
+    );
+
+    // Share ledger canister of a batch's shares token: ledger canister -> token id
+    static SHARE_LEDGERS: RefCell<StableBTreeMap<Principal, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(SHARE_LEDGERS_MEMORY_ID))
+    );
+
+    // Escrow record per request_id: subaccount, expected amount and ledger movement history
+    static ESCROWS: RefCell<StableBTreeMap<String, EscrowRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(ESCROWS_MEMORY_ID))
+    );
+
+    // Canister-wide settings (ledger canister, ...)
+    static CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            claim_memory(CONFIG_MEMORY_ID),
+            CanisterConfig::default(),
+        )
+        .expect("failed to initialize config cell")
+    );
+
+    // Escrow refunds by request_id (at most one per request)
+    static REFUNDS: RefCell<StableBTreeMap<String, Refund, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(REFUNDS_MEMORY_ID))
+    );
+
+    // Platform commission collected per released transaction (keyed by transaction_id)
+    static FEES: RefCell<StableBTreeMap<String, FeeRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(FEES_MEMORY_ID))
+    );
+
+    static TREASURY: RefCell<StableCell<TreasuryState, Memory>> = RefCell::new(
+        StableCell::init(
+            claim_memory(TREASURY_MEMORY_ID),
+            TreasuryState::default(),
+        )
+        .expect("failed to initialize treasury cell")
+    );
+
+    // Treasury withdrawals per token symbol, ledger fees included
+    static TREASURY_WITHDRAWN: RefCell<StableBTreeMap<String, u128, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(TREASURY_WITHDRAWN_MEMORY_ID))
+    );
+
+    // Allowlisted ICRC-1 payment tokens by symbol (ICP is implicit, see `default_payment_token`)
+    static PAYMENT_TOKENS: RefCell<StableBTreeMap<String, PaymentToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(claim_memory(PAYMENT_TOKENS_MEMORY_ID))
     );
 
-    // NEW: escrow subaccounts by request_id -> 32-byte subaccount (hex string)
-    static ESCROW_SUBACCOUNTS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(ESCROW_MEMORY_ID)))
+    // Requests with a ledger call in flight (heap only: locks never outlive a message)
+    static REQUEST_LOCKS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+
+    // Offer quantity reserved by acceptances still in flight, by request id: (offer id, quantity)
+    static RESERVATIONS: RefCell<BTreeMap<String, (String, u64)>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    // Number of entries of `migration::MIGRATIONS` already applied to stable memory
+    static MIGRATIONS_APPLIED: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(claim_memory(MIGRATIONS_MEMORY_ID), 0)
+            .expect("failed to initialize migrations cell")
     );
+
+    // Verification hashes of batches, certified with the ICRC-3 tip (heap only: rebuilt from stable
+    // state on install and upgrade, see verification.rs)
+    static CERTIFIED_BATCHES: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // Body hashes of the certified HTTP responses, by URL path (heap only, like CERTIFIED_BATCHES;
+    // see http.rs)
+    static CERTIFIED_ASSETS: RefCell<RbTree<String, Hash>> = const { RefCell::new(RbTree::new()) };
+
+    // Periodic expiry sweep (timers are heap state and are re-armed after every upgrade)
+    static SWEEP_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// Hands out the virtual memory of a registered id to the one structure that owns it. Two structures
+// on the same memory would silently corrupt each other's B-tree, so a second claim traps.
+fn claim_memory(id: MemoryId) -> Memory {
+    if !MEMORY_LAYOUT.iter().any(|(registered, _)| *registered == id) {
+        ic_cdk::trap(&format!("{:?} is not registered in MEMORY_LAYOUT", id));
+    }
+    if !CLAIMED_MEMORIES.with(|c| c.borrow_mut().insert(id)) {
+        ic_cdk::trap(&format!("{:?} is already used by another stable structure", id));
+    }
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+// Traps if an id is registered twice, live or retired
+fn check_memory_layout() {
+    let mut seen = BTreeSet::new();
+    for (id, name) in MEMORY_LAYOUT.iter().chain(RETIRED_MEMORY_IDS) {
+        if !seen.insert(*id) {
+            ic_cdk::trap(&format!("{:?} ({}) is registered twice in the memory layout", id, name));
+        }
+    }
+}
+
+// Opens every stable structure so that a clashing memory claim fails the install or upgrade
+// instead of a later call
+fn open_stable_structures() {
+    USERS.with(|_| ());
+    OFFERS.with(|_| ());
+    REQUESTS.with(|_| ());
+    TRANSACTIONS.with(|_| ());
+    BATCHES.with(|_| ());
+    SHARES_TOTAL.with(|_| ());
+    SHARES_BALANCES.with(|_| ());
+    TX_DEDUP.with(|_| ());
+    SHARES_ALLOWANCES.with(|_| ());
+    SHARE_BLOCKS.with(|_| ());
+    PROVENANCE.with(|_| ());
+    CERTIFICATES.with(|_| ());
+    BANS.with(|_| ());
+    SHARE_LEDGERS.with(|_| ());
+    ESCROWS.with(|_| ());
+    CONFIG.with(|_| ());
+    REFUNDS.with(|_| ());
+    FEES.with(|_| ());
+    TREASURY.with(|_| ());
+    TREASURY_WITHDRAWN.with(|_| ());
+    PAYMENT_TOKENS.with(|_| ());
+    MIGRATIONS_APPLIED.with(|_| ());
 }
 
 // Utility functions
+#[cfg(not(test))]
 fn get_current_time() -> u64 {
     ic_cdk::api::time()
 }
 
+// Unit tests run natively, without the system API (see testing.rs)
+#[cfg(test)]
+fn get_current_time() -> u64 {
+    testing::now()
+}
+
 fn generate_id(prefix: &str) -> String {
     format!("{}_{}", prefix, get_current_time())
 }
 
+#[cfg(not(test))]
 fn get_caller() -> Principal {
     ic_cdk::caller()
 }
 
-fn is_authenticated() -> bool {
-    // TODO: enable real auth check with Internet Identity
-    //get_caller() != Principal::anonymous()
-    true
+#[cfg(test)]
+fn get_caller() -> Principal {
+    testing::caller()
+}
+
+#[cfg(not(test))]
+fn canister_id() -> Principal {
+    ic_cdk::id()
+}
+
+#[cfg(test)]
+fn canister_id() -> Principal {
+    testing::CANISTER_ID
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Parses a "YYYY-MM-DD" date (as sent by the frontend) into nanoseconds since the epoch, at 00:00 UTC
+fn parse_date_nanos(date: &str) -> Option<u64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.get(..2)?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // days since 1970-01-01 in the proleptic Gregorian calendar
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    u64::try_from(days).ok()?.checked_mul(NANOS_PER_DAY)
+}
+
+fn offer_farmer(offer_id: &str) -> Option<Principal> {
+    OFFERS.with(|offers| offers.borrow().get(&offer_id.to_string()).map(|offer| offer.farmer))
+}
+
+fn get_config() -> CanisterConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+const DEFAULT_TOKEN_SYMBOL: &str = "ICP";
+
+// ICP on the configured ledger canister
+fn default_payment_token() -> PaymentToken {
+    PaymentToken {
+        symbol: DEFAULT_TOKEN_SYMBOL.to_string(),
+        ledger_canister: get_config().ledger_canister,
+        decimals: 8,
+    }
+}
+
+fn find_payment_token(symbol: &str) -> Option<PaymentToken> {
+    if symbol == DEFAULT_TOKEN_SYMBOL {
+        return Some(default_payment_token());
+    }
+    PAYMENT_TOKENS.with(|t| t.borrow().get(&symbol.to_string()))
+}
+
+fn offer_payment_token(offer: &InvestmentOffer) -> PaymentToken {
+    offer
+        .payment_token
+        .clone()
+        .unwrap_or_else(default_payment_token)
+}
+
+fn request_payment_token(req: &InvestmentRequest) -> Result<PaymentToken, String> {
+    OFFERS
+        .with(|o| o.borrow().get(&req.offer_id))
+        .map(|offer| offer_payment_token(&offer))
+        .ok_or_else(|| "Offer not found".to_string())
+}
+
+// Serializes async work on a request across the awaits of a ledger call.
+// Released on drop, including when the message traps after an await.
+struct RequestLock {
+    request_id: String,
+}
+
+impl RequestLock {
+    fn acquire(request_id: &str) -> Result<Self, String> {
+        REQUEST_LOCKS.with(|locks| {
+            if locks.borrow_mut().insert(request_id.to_string()) {
+                Ok(Self {
+                    request_id: request_id.to_string(),
+                })
+            } else {
+                Err("Request is already being processed".to_string())
+            }
+        })
+    }
+}
+
+impl Drop for RequestLock {
+    fn drop(&mut self) {
+        REQUEST_LOCKS.with(|locks| {
+            locks.borrow_mut().remove(&self.request_id);
+        });
+    }
+}
+
+// Offer quantity taken by an acceptance whose transaction does not exist yet (e.g. while an ICRC-2
+// payment is pulled). The farmer's matching shares stay locked until it is dropped, when the
+// transaction that locks them is stored or the quantity was released.
+struct Reservation {
+    request_id: String,
+}
+
+impl Reservation {
+    fn reserve(req: &InvestmentRequest) -> Result<Self, String> {
+        reserve_offer_quantity(&req.offer_id, req.requested_quantity)?;
+        RESERVATIONS.with(|r| {
+            r.borrow_mut().insert(
+                req.id.clone(),
+                (req.offer_id.clone(), req.requested_quantity),
+            )
+        });
+        Ok(Self {
+            request_id: req.id.clone(),
+        })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        RESERVATIONS.with(|r| {
+            r.borrow_mut().remove(&self.request_id);
+        });
+    }
+}
+
+/// Quantity of the offer reserved by acceptances in flight.
+pub fn reserved_quantity(offer_id: &str) -> u64 {
+    RESERVATIONS.with(|r| {
+        r.borrow()
+            .values()
+            .filter(|(id, _)| id == offer_id)
+            .map(|(_, quantity)| quantity)
+            .sum()
+    })
+}
+
+// -----------------------------
+// Canister upgrades
+// -----------------------------
+
+// All state lives in stable structures, so there is no pre_upgrade hook. Heap state (request locks,
+// the sweep timer, the certified tree) is rebuilt after an upgrade. Both hooks take optional
+// `InitArgs`, whose admins are granted the Admin role; controllers are admins without being
+// granted anything.
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    check_memory_layout();
+    open_stable_structures();
+    // A fresh canister starts at the current storage schema
+    migration::mark_all_applied();
+    apply_init_args(args);
+    verification::certify_all();
+    schedule_expiry_sweep();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    check_memory_layout();
+    // Migrations read some maps as raw bytes before the typed structures are opened
+    migration::run_migrations();
+    open_stable_structures();
+    apply_init_args(args);
+    verification::certify_all();
+    schedule_expiry_sweep();
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    let Some(args) = args else {
+        return;
+    };
+    let now = get_current_time();
+    for admin in args.admins {
+        if auth::is_anonymous(&admin) {
+            ic_cdk::trap("The anonymous principal cannot be an admin");
+        }
+        let user = match USERS.with(|users| users.borrow().get(&admin)) {
+            Some(mut user) => {
+                if !user.roles.contains(&UserRole::Admin) {
+                    user.roles.push(UserRole::Admin);
+                }
+                user.pending_roles.retain(|role| *role != UserRole::Admin);
+                user.updated_at = now;
+                user
+            }
+            None => UserProfile {
+                principal: admin,
+                roles: vec![UserRole::Admin],
+                pending_roles: vec![],
+                display_name: String::new(),
+                email: String::new(),
+                created_at: now,
+                updated_at: now,
+                kyc: KycRecord::default(),
+                deactivated_at: None,
+                erased_at: None,
+            },
+        };
+        USERS.with(|users| users.borrow_mut().insert(admin, user));
+    }
+}
+
+// -----------------------------
+// Authentication (see auth.rs)
+// -----------------------------
+
+// Rejects anonymous ingress messages to methods outside `auth::ANONYMOUS_METHODS`
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    if auth::accepts_ingress(&get_caller(), &ic_cdk::api::call::method_name()) {
+        ic_cdk::api::call::accept_message();
+    }
 }
 
 // -----------------------------
@@ -94,10 +554,12 @@ fn is_authenticated() -> bool {
 pub struct DepositInfo {
     pub escrow_canister: Principal,
     pub subaccount_hex: String,
-    pub expected_amount_e8s: u128,
+    // in base units of `payment_token` (e8s for ICP)
+    pub expected_amount: Amount,
+    pub payment_token: PaymentToken,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct BatchNFT {
     pub id: String,
     pub owner: Principal,
@@ -105,7 +567,7 @@ pub struct BatchNFT {
     pub minted_at: u64,
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct BatchMetadata {
     pub product_name: String,
     pub product_type: ProductType,
@@ -116,15 +578,18 @@ pub struct BatchMetadata {
     pub additional: Option<String>,
 }
 
+impl Versioned for BatchNFT {
+    const SCHEMA_VERSION: u8 = 1;
+}
+
 // Implement Storable for BatchNFT so it can be persisted in StableBTreeMap
 impl Storable for BatchNFT {
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        let bytes = candid::encode_one(self).expect("encode BatchNFT failed");
-        Cow::Owned(bytes)
+        Cow::Owned(encode_record(self))
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        candid::decode_one(&bytes).expect("decode BatchNFT failed")
+        decode_record(&bytes).expect("decode BatchNFT failed")
     }
 
     // Set as unbounded — change to Bound::Bounded(n) if you want a max size
@@ -135,24 +600,16 @@ impl Storable for BatchNFT {
 // Existing user management functions
 // -----------------------------
 
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "authenticated")]
 fn get_current_user() -> ApiResponse<Option<UserProfile>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
-
     let caller = get_caller();
     let user = USERS.with(|users| users.borrow().get(&caller));
 
     ApiResponse::success(user)
 }
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "authenticated")]
 fn register_user(request: RegisterUserRequest) -> ApiResponse<UserProfile> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
-
     let caller = get_caller();
 
     // Check if user already exists
@@ -160,14 +617,32 @@ fn register_user(request: RegisterUserRequest) -> ApiResponse<UserProfile> {
         return ApiResponse::error("User already registered".to_string());
     }
 
+    if matches!(request.role, UserRole::Admin) {
+        return ApiResponse::error(roles::ADMIN_APPOINTMENT.to_string());
+    }
+
+    if matches!(request.role, UserRole::Inspector | UserRole::Verifier) {
+        return ApiResponse::error(
+            "Inspectors and verifiers are appointed by an admin".to_string(),
+        );
+    }
+
+    if let Err(e) = accounts::validate_contact(&request.display_name, &request.email) {
+        return ApiResponse::error(e);
+    }
+
     let now = get_current_time();
     let user = UserProfile {
         principal: caller,
-        role: request.role,
+        roles: vec![request.role],
+        pending_roles: vec![],
         display_name: request.display_name,
         email: request.email,
         created_at: now,
         updated_at: now,
+        kyc: KycRecord::default(),
+        deactivated_at: None,
+        erased_at: None,
     };
 
     USERS.with(|users| {
@@ -177,256 +652,389 @@ fn register_user(request: RegisterUserRequest) -> ApiResponse<UserProfile> {
     ApiResponse::success(user)
 }
 
-#[ic_cdk::update]
+/// Replaces every role of a user with `new_role`. See `grant_role` and `revoke_role` to change one.
+#[ic_cdk::update(guard = "authenticated")]
 fn update_user_role(principal: Principal, new_role: UserRole) -> ApiResponse<UserProfile> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
+    match roles::replace(get_caller(), principal, new_role) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
     }
+}
 
-    let caller = get_caller();
+/// Takes on a role. Farmer, Investor and Guest are added at once; Inspector and Verifier wait for
+/// an admin.
+#[ic_cdk::update(guard = "authenticated")]
+fn add_role(role: UserRole) -> ApiResponse<UserProfile> {
+    match roles::add(get_caller(), role) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
 
-    // Check if caller is admin
-    if !USERS.with(|users| {
-        users
-            .borrow()
-            .get(&caller)
-            .map(|user| matches!(user.role, UserRole::Admin))
-            .unwrap_or(false)
-    }) {
-        return ApiResponse::error("Admin access required".to_string());
+/// Gives up a role, or withdraws a pending request for one.
+#[ic_cdk::update(guard = "authenticated")]
+fn remove_role(role: UserRole) -> ApiResponse<UserProfile> {
+    match roles::remove(get_caller(), role) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
     }
+}
 
-    USERS.with(|users| {
-        let mut users_map = users.borrow_mut();
-        match users_map.get(&principal) {
-            Some(mut user) => {
-                user.role = new_role;
-                user.updated_at = get_current_time();
-                users_map.insert(principal, user.clone());
-                ApiResponse::success(user)
-            }
-            None => ApiResponse::error("User not found".to_string()),
-        }
-    })
+/// Grants a role to a user, approving their request for it if they made one.
+#[ic_cdk::update(guard = "authenticated")]
+fn grant_role(principal: Principal, role: UserRole) -> ApiResponse<UserProfile> {
+    match roles::grant(get_caller(), principal, role) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+/// Revokes a role from a user, or rejects their request for it.
+#[ic_cdk::update(guard = "authenticated")]
+fn revoke_role(principal: Principal, role: UserRole) -> ApiResponse<UserProfile> {
+    match roles::revoke(get_caller(), principal, role) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+/// Users waiting for an admin to approve a role.
+#[ic_cdk::query(guard = "authenticated")]
+fn get_pending_role_requests() -> ApiResponse<Vec<UserProfile>> {
+    match roles::pending(get_caller()) {
+        Ok(users) => ApiResponse::success(users),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+// -----------------------------
+// KYC (see kyc.rs)
+// -----------------------------
+
+/// Submits hex SHA-256 digests of the caller's identity documents for review by a verifier.
+#[ic_cdk::update(guard = "authenticated")]
+fn submit_kyc(document_hashes: Vec<String>) -> ApiResponse<UserProfile> {
+    match kyc::submit(get_caller(), document_hashes) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn approve_kyc(principal: Principal) -> ApiResponse<UserProfile> {
+    match kyc::approve(get_caller(), principal) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+/// Rejects a pending submission, or withdraws a verification.
+#[ic_cdk::update(guard = "authenticated")]
+fn reject_kyc(principal: Principal, reason: String) -> ApiResponse<UserProfile> {
+    match kyc::reject(get_caller(), principal, reason) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_pending_kyc_reviews() -> ApiResponse<Vec<UserProfile>> {
+    match kyc::pending(get_caller()) {
+        Ok(users) => ApiResponse::success(users),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+// -----------------------------
+// Accounts (see accounts.rs)
+// -----------------------------
+
+#[ic_cdk::update(guard = "authenticated")]
+fn update_profile(request: UpdateProfileRequest) -> ApiResponse<UserProfile> {
+    match accounts::update(get_caller(), request) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+/// Leaves the platform. Deals already under way can still be completed.
+#[ic_cdk::update(guard = "authenticated")]
+fn deactivate_account() -> ApiResponse<UserProfile> {
+    match accounts::deactivate(get_caller()) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn reactivate_account() -> ApiResponse<UserProfile> {
+    match accounts::reactivate(get_caller()) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+/// Deactivates the caller's account for good and scrubs its personal data. Offers, requests and
+/// transactions are kept.
+#[ic_cdk::update(guard = "authenticated")]
+fn request_erasure() -> ApiResponse<UserProfile> {
+    match accounts::erase(get_caller()) {
+        Ok(user) => ApiResponse::success(user),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn ban_user(principal: Principal, reason: String) -> ApiResponse<Ban> {
+    match accounts::ban(get_caller(), principal, reason) {
+        Ok(ban) => ApiResponse::success(ban),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn unban_user(principal: Principal) -> ApiResponse<Ban> {
+    match accounts::unban(get_caller(), principal) {
+        Ok(ban) => ApiResponse::success(ban),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_banned_users() -> ApiResponse<Vec<Ban>> {
+    match accounts::bans(get_caller()) {
+        Ok(bans) => ApiResponse::success(bans),
+        Err(e) => ApiResponse::error(e),
+    }
 }
 
 // -----------------------------
 // Offer management functions (modified to mint NFT on create)
 // -----------------------------
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "authenticated")]
 fn create_agricultural_offer(request: CreateOfferRequest) -> ApiResponse<InvestmentOffer> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
+    let caller = get_caller();
+
+    if let Err(e) = permissions::authorize(&caller, Operation::CreateOffer, &[]) {
+        return ApiResponse::error(e);
     }
 
-    let caller = get_caller();
+    if request.price_per_kg.is_zero() {
+        return ApiResponse::error("Price per kg must be greater than zero".to_string());
+    }
 
-    // Check if user is farmer
-    let user_role = USERS.with(|users| users.borrow().get(&caller).map(|user| user.role.clone()));
+    let symbol = request
+        .payment_token
+        .clone()
+        .unwrap_or_else(|| DEFAULT_TOKEN_SYMBOL.to_string());
+    let payment_token = match find_payment_token(&symbol) {
+        Some(token) => token,
+        None => return ApiResponse::error(format!("Payment token {} is not supported", symbol)),
+    };
 
-    match user_role {
-        Some(UserRole::Farmer) | Some(UserRole::Admin) => {
-            let now = get_current_time();
-            let offer_id = generate_id("offer");
-
-            let offer = InvestmentOffer {
-                id: offer_id.clone(),
-                farmer: caller,
-                product_name: request.product_name.clone(),
-                product_type: request.product_type.clone(),
-                total_quantity: request.total_quantity,
-                available_quantity: request.total_quantity, // Initially all available
-                price_per_kg: request.price_per_kg,
-                description: request.description.clone(),
-                harvest_date: request.harvest_date.clone(),
-                location: request.location.clone(),
-                quality_grade: request.quality_grade.clone(),
-                minimum_investment: request.minimum_investment,
-                status: OfferStatus::Active,
-                created_at: now,
-                updated_at: now,
-            };
+    let now = get_current_time();
+    let offer_id = generate_id("offer");
+
+    let offer = InvestmentOffer {
+        id: offer_id.clone(),
+        farmer: caller,
+        product_name: request.product_name.clone(),
+        product_type: request.product_type.clone(),
+        total_quantity: request.total_quantity,
+        available_quantity: request.total_quantity, // Initially all available
+        price_per_kg: request.price_per_kg,
+        description: request.description.clone(),
+        harvest_date: request.harvest_date.clone(),
+        location: request.location.clone(),
+        quality_grade: request.quality_grade.clone(),
+        minimum_investment: request.minimum_investment,
+        status: OfferStatus::Active,
+        created_at: now,
+        updated_at: now,
+        payment_token: Some(payment_token),
+        verified_grade: None,
+        verified_investors_only: Some(request.verified_investors_only.unwrap_or(false)),
+    };
 
-            // store offer
-            OFFERS.with(|offers| {
-                offers.borrow_mut().insert(offer_id.clone(), offer.clone());
-            });
+    // store offer
+    OFFERS.with(|offers| {
+        offers.borrow_mut().insert(offer_id.clone(), offer.clone());
+    });
 
-            // Mint a Batch NFT representing this offer
-            let batch_id = format!("batch_{}", offer_id);
-            let metadata = BatchMetadata {
-                product_name: offer.product_name.clone(),
-                product_type: offer.product_type.clone(),
-                quality_grade: offer.quality_grade.clone(),
-                location: offer.location.clone(),
-                harvest_date: offer.harvest_date.clone(),
-                total_quantity: offer.total_quantity,
-                additional: None,
-            };
-            let nft = BatchNFT {
-                id: batch_id.clone(),
-                owner: caller,
-                metadata,
-                minted_at: now,
-            };
-            BATCHES.with(|b| {
-                b.borrow_mut().insert(batch_id.clone(), nft);
-            });
+    // Mint a Batch NFT representing this offer
+    let batch_id = format!("batch_{}", offer_id);
+    let metadata = BatchMetadata {
+        product_name: offer.product_name.clone(),
+        product_type: offer.product_type.clone(),
+        quality_grade: offer.quality_grade.clone(),
+        location: offer.location.clone(),
+        harvest_date: offer.harvest_date.clone(),
+        total_quantity: offer.total_quantity,
+        additional: None,
+    };
+    let nft = BatchNFT {
+        id: batch_id.clone(),
+        owner: caller,
+        metadata,
+        minted_at: now,
+    };
+    nft::record_mint(&nft, None);
+    BATCHES.with(|b| {
+        b.borrow_mut().insert(batch_id.clone(), nft);
+    });
 
-            // Create a shares token for this batch:
-            // token_id pattern: "shares:batch_<offer_id>"
-            let token_id = format!("shares:{}", batch_id);
-            let total_shares = offer.total_quantity as u128; // 1 share == 1 kg initially
-            SHARES_TOTAL.with(|t| {
-                t.borrow_mut().insert(token_id.clone(), total_shares);
-            });
-            // assign all shares to farmer by default
-            let farmer_key = format!("{}|{}", token_id, caller.to_text());
-            SHARES_BALANCES.with(|b| {
-                b.borrow_mut().insert(farmer_key, total_shares);
-            });
+    // Create a shares token for this batch and issue all shares to the farmer
+    // (1 share == 1 kg initially)
+    shares::issue(
+        &shares::token_id(&offer_id),
+        &shares::account_of(caller),
+        offer.total_quantity as u128,
+    );
+    verification::certify(&batch_id);
 
-            ApiResponse::success(offer)
-        }
-        Some(_) => ApiResponse::error("Farmer role required".to_string()),
-        None => ApiResponse::error("User not found".to_string()),
-    }
+    ApiResponse::success(offer)
 }
 
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "not_banned")]
 fn get_available_offers() -> ApiResponse<Vec<InvestmentOffer>> {
-    let offers = OFFERS.with(|offers| {
+    ApiResponse::success(available_offers())
+}
+
+// Active offers of active farmers, as listed to investors (also served at GET /offers, see http.rs)
+fn available_offers() -> Vec<InvestmentOffer> {
+    OFFERS.with(|offers| {
         offers
             .borrow()
             .iter()
-            .filter(|(_, offer)| matches!(offer.status, OfferStatus::Active))
-            .map(|(_, offer)| offer.clone())
+            .filter(|(_, offer)| {
+                matches!(offer.status, OfferStatus::Active)
+                    && accounts::is_active_user(&offer.farmer)
+            })
+            .map(|(_, offer)| inspection::with_verified_grade(offer))
             .collect::<Vec<_>>()
-    });
-
-    ApiResponse::success(offers)
+    })
 }
 
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "authenticated")]
 fn get_farmer_offers() -> ApiResponse<Vec<InvestmentOffer>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
-
     let caller = get_caller();
     let offers = OFFERS.with(|offers| {
         offers
             .borrow()
             .iter()
             .filter(|(_, offer)| offer.farmer == caller)
-            .map(|(_, offer)| offer.clone())
+            .map(|(_, offer)| inspection::with_verified_grade(offer))
             .collect::<Vec<_>>()
     });
 
     ApiResponse::success(offers)
 }
 
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "not_banned")]
 fn get_offer_by_id(offer_id: String) -> ApiResponse<Option<InvestmentOffer>> {
     let offer = OFFERS.with(|offers| offers.borrow().get(&offer_id));
-    ApiResponse::success(offer)
+    ApiResponse::success(offer.map(inspection::with_verified_grade))
 }
 
 // -----------------------------
 // Investment request functions
 // -----------------------------
 
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "authenticated")]
 fn create_investment_request(request: CreateInvestmentRequest) -> ApiResponse<InvestmentRequest> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
-
     let caller = get_caller();
 
-    // Check if user is investor
-    let user_role = USERS.with(|users| users.borrow().get(&caller).map(|user| user.role.clone()));
-
-    match user_role {
-        Some(UserRole::Investor) | Some(UserRole::Admin) => {
-            // Verify offer exists and is active
-            let offer_valid = OFFERS.with(|offers| {
-                offers
-                    .borrow()
-                    .get(&request.offer_id)
-                    .map(|offer| {
-                        matches!(offer.status, OfferStatus::Active)
-                            && offer.available_quantity >= request.requested_quantity
-                    })
-                    .unwrap_or(false)
-            });
-
-            if !offer_valid {
-                return ApiResponse::error("Invalid offer or insufficient quantity".to_string());
-            }
-
-            let now = get_current_time();
-            let request_id = generate_id("req");
-            let expires_at = now + (7 * 24 * 60 * 60 * 1_000_000_000); // 7 days in nanoseconds
-
-            let investment_request = InvestmentRequest {
-                id: request_id.clone(),
-                offer_id: request.offer_id,
-                investor: caller,
-                requested_quantity: request.requested_quantity,
-                offered_price_per_kg: request.offered_price_per_kg,
-                total_offered: request.requested_quantity as f64 * request.offered_price_per_kg,
-                message: request.message,
-                status: RequestStatus::Pending,
-                created_at: now,
-                updated_at: now,
-                expires_at,
-            };
-
-            REQUESTS.with(|requests| {
-                requests
-                    .borrow_mut()
-                    .insert(request_id.clone(), investment_request.clone());
-            });
+    if let Err(e) = permissions::authorize(&caller, Operation::CreateInvestmentRequest, &[]) {
+        return ApiResponse::error(e);
+    }
 
-            // Prepare escrow subaccount for this request (store subaccount bytes as hex)
-            let sub_hex = calculate_subaccount_hex(&request_id);
-            ESCROW_SUBACCOUNTS.with(|esc| {
-                esc.borrow_mut().insert(request_id.clone(), sub_hex.clone());
-            });
+    // Verify offer exists and is active
+    let offer = OFFERS.with(|offers| offers.borrow().get(&request.offer_id));
+    let offer_valid = offer.as_ref().is_some_and(|offer| {
+        matches!(offer.status, OfferStatus::Active)
+            && offer.available_quantity >= request.requested_quantity
+    });
 
-            ApiResponse::success(investment_request)
-        }
-        Some(_) => ApiResponse::error("Investor role required".to_string()),
-        None => ApiResponse::error("User not found".to_string()),
+    if !offer_valid {
+        return ApiResponse::error("Invalid offer or insufficient quantity".to_string());
     }
-}
 
-#[ic_cdk::query]
-fn get_requests_for_offer(offer_id: String) -> ApiResponse<Vec<InvestmentRequest>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
+    if offer
+        .as_ref()
+        .is_some_and(|offer| !accounts::is_active_user(&offer.farmer))
+    {
+        return ApiResponse::error("The farmer of this offer is no longer active".to_string());
     }
 
-    let caller = get_caller();
-
-    // Verify caller is the farmer for this offer
-    let is_offer_owner = OFFERS.with(|offers| {
-        offers
-            .borrow()
-            .get(&offer_id)
-            .map(|offer| offer.farmer == caller)
-            .unwrap_or(false)
-    });
+    let verified_only = offer.is_some_and(|offer| offer.verified_investors_only == Some(true));
+    if verified_only && !kyc::is_verified(&caller) {
+        return ApiResponse::error("This offer requires a verified KYC".to_string());
+    }
 
-    if !is_offer_owner {
-        return ApiResponse::error("Access denied - not offer owner".to_string());
+    if request.offered_price_per_kg.is_zero() {
+        return ApiResponse::error("Offered price per kg must be greater than zero".to_string());
     }
+    let total_offered = match request
+        .offered_price_per_kg
+        .checked_mul(request.requested_quantity)
+    {
+        Some(total) => total,
+        None => return ApiResponse::error("Offered total is out of range".to_string()),
+    };
 
-    let requests = REQUESTS.with(|requests| {
-        requests
-            .borrow()
+    let now = get_current_time();
+    let request_id = generate_id("req");
+    let expires_at = now + (7 * 24 * 60 * 60 * 1_000_000_000); // 7 days in nanoseconds
+
+    let investment_request = InvestmentRequest {
+        id: request_id.clone(),
+        offer_id: request.offer_id,
+        investor: caller,
+        requested_quantity: request.requested_quantity,
+        offered_price_per_kg: request.offered_price_per_kg,
+        total_offered,
+        message: request.message,
+        status: RequestStatus::Pending,
+        created_at: now,
+        updated_at: now,
+        expires_at,
+        payment_method: Some(
+            request.payment_method.unwrap_or(PaymentMethod::SubaccountDeposit),
+        ),
+        payment_pull: None,
+    };
+
+    REQUESTS.with(|requests| {
+        requests
+            .borrow_mut()
+            .insert(request_id.clone(), investment_request.clone());
+    });
+
+    // Open the escrow record for this request (deposits go to its subaccount)
+    if let Some(offer) = OFFERS.with(|o| o.borrow().get(&investment_request.offer_id)) {
+        let record = new_escrow_record(&investment_request, &offer);
+        ESCROWS.with(|e| e.borrow_mut().insert(request_id.clone(), record));
+    }
+
+    ApiResponse::success(investment_request)
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_requests_for_offer(offer_id: String) -> ApiResponse<Vec<InvestmentRequest>> {
+    let caller = get_caller();
+
+    let farmer = match offer_farmer(&offer_id) {
+        Some(farmer) => farmer,
+        None => return ApiResponse::error("Offer not found".to_string()),
+    };
+    if let Err(e) = permissions::authorize(&caller, Operation::ViewOfferRequests, &[(Ownership::OfferFarmer, farmer)]) {
+        return ApiResponse::error(e);
+    }
+
+    let requests = REQUESTS.with(|requests| {
+        requests
+            .borrow()
             .iter()
             .filter(|(_, req)| req.offer_id == offer_id)
             .map(|(_, req)| req.clone())
@@ -436,12 +1044,8 @@ fn get_requests_for_offer(offer_id: String) -> ApiResponse<Vec<InvestmentRequest
     ApiResponse::success(requests)
 }
 
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "authenticated")]
 fn get_investor_requests() -> ApiResponse<Vec<InvestmentRequest>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
-
     let caller = get_caller();
     let requests = REQUESTS.with(|requests| {
         requests
@@ -459,14 +1063,10 @@ fn get_investor_requests() -> ApiResponse<Vec<InvestmentRequest>> {
 // Request response functions (modified flow: ACCEPT -> create transaction & wait for deposit)
 // -----------------------------
 
-#[ic_cdk::update]
-fn respond_to_investment_request(
+#[ic_cdk::update(guard = "authenticated")]
+async fn respond_to_investment_request(
     request: RespondToRequestRequest,
 ) -> ApiResponse<InvestmentRequest> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
-
     let caller = get_caller();
 
     // Get the investment request
@@ -477,17 +1077,12 @@ fn respond_to_investment_request(
         None => return ApiResponse::error("Investment request not found".to_string()),
     };
 
-    // Verify caller is the farmer for this offer
-    let is_offer_owner = OFFERS.with(|offers| {
-        offers
-            .borrow()
-            .get(&investment_request.offer_id)
-            .map(|offer| offer.farmer == caller)
-            .unwrap_or(false)
-    });
-
-    if !is_offer_owner {
-        return ApiResponse::error("Access denied - not offer owner".to_string());
+    let farmer = match offer_farmer(&investment_request.offer_id) {
+        Some(farmer) => farmer,
+        None => return ApiResponse::error("Offer not found".to_string()),
+    };
+    if let Err(e) = permissions::authorize(&caller, Operation::RespondToRequest, &[(Ownership::OfferFarmer, farmer)]) {
+        return ApiResponse::error(e);
     }
 
     // Check if request is still pending
@@ -495,14 +1090,55 @@ fn respond_to_investment_request(
         return ApiResponse::error("Request already processed".to_string());
     }
 
-    let now = get_current_time();
+    if get_current_time() > investment_request.expires_at {
+        return ApiResponse::error("Request has expired".to_string());
+    }
+
+    let _lock = match RequestLock::acquire(&investment_request.id) {
+        Ok(lock) => lock,
+        Err(e) => return ApiResponse::error(e),
+    };
 
     if request.accept {
-        // Accept the request - create transaction and update availability
+        // Reserve the quantity up front so a concurrent acceptance cannot oversell the offer, and
+        // the farmer cannot transfer its shares away while the payment is pulled
+        let reservation = match Reservation::reserve(&investment_request) {
+            Ok(reservation) => reservation,
+            Err(e) => return ApiResponse::error(e),
+        };
+
+        // ICRC-2 requests: pull the approved funds into the escrow subaccount before accepting
+        let pays_by_approval = matches!(
+            investment_request.payment_method,
+            Some(PaymentMethod::Icrc2Approval)
+        );
+        if pays_by_approval {
+            match pull_approved_payment(&mut investment_request).await {
+                Ok(block_index) => record_escrow_entry(
+                    &investment_request.id,
+                    EscrowEntryKind::Deposit,
+                    expected_amount(&investment_request).base_units(),
+                    0,
+                    block_index,
+                    EscrowState::Funded,
+                ),
+                // The request stays pending with its `payment_pull`; accepting it again retries
+                // the same transfer
+                Err(e) => {
+                    release_offer_quantity(
+                        &investment_request.offer_id,
+                        investment_request.requested_quantity,
+                    );
+                    return ApiResponse::error(e);
+                }
+            }
+        }
+
+        let now = get_current_time();
         investment_request.status = RequestStatus::Accepted;
 
         let transaction_id = generate_id("txn");
-        let transaction = Transaction {
+        let mut transaction = Transaction {
             id: transaction_id.clone(),
             offer_id: investment_request.offer_id.clone(),
             request_id: investment_request.id.clone(),
@@ -515,23 +1151,33 @@ fn respond_to_investment_request(
             created_at: now,
             updated_at: now,
             tokenized_at: None,
+            delivered_at: None,
+            released_at: None,
+            release_block_index: None,
+            release_plan: None,
         };
 
-        // Update offer availability
-        OFFERS.with(|offers| {
-            let mut offers_map = offers.borrow_mut();
-            if let Some(mut offer) = offers_map.get(&investment_request.offer_id) {
-                offer.available_quantity -= investment_request.requested_quantity;
-                offer.updated_at = now;
-
-                // Mark as completed if no quantity left
-                if offer.available_quantity == 0 {
-                    offer.status = OfferStatus::Completed;
-                }
-
-                offers_map.insert(investment_request.offer_id.clone(), offer);
+        // Funds are already in escrow for ICRC-2 requests, so shares can be minted right away.
+        // If that fails, the request is rejected and the pulled payment returned, so it cannot be
+        // accepted (and paid) again. A failed refund can be retried with `refund_request`.
+        if pays_by_approval {
+            if let Err(e) = tokenize_transaction(&mut transaction) {
+                release_offer_quantity(
+                    &investment_request.offer_id,
+                    investment_request.requested_quantity,
+                );
+                drop(reservation);
+                investment_request.status = RequestStatus::Rejected;
+                investment_request.updated_at = get_current_time();
+                REQUESTS.with(|requests| {
+                    requests
+                        .borrow_mut()
+                        .insert(investment_request.id.clone(), investment_request.clone());
+                });
+                let _ = refund_escrow(&investment_request, RefundReason::Rejected).await;
+                return ApiResponse::error(format!("{}; the payment was refunded", e));
             }
-        });
+        }
 
         // Store transaction
         TRANSACTIONS.with(|transactions| {
@@ -540,13 +1186,13 @@ fn respond_to_investment_request(
                 .insert(transaction_id.clone(), transaction);
         });
 
-        // After acceptance: frontend should call `get_deposit_info(request_id)` to get deposit subaccount info
+        // Subaccount deposits: frontend should call `get_deposit_info(request_id)`, then `settle_request`
     } else {
         // Reject the request
         investment_request.status = RequestStatus::Rejected;
     }
 
-    investment_request.updated_at = now;
+    investment_request.updated_at = get_current_time();
 
     // Update the request
     REQUESTS.with(|requests| {
@@ -555,11 +1201,93 @@ fn respond_to_investment_request(
             .insert(request.request_id, investment_request.clone());
     });
 
+    // Return anything the investor deposited ahead of a rejection.
+    // Failures are recorded on the refund and can be retried with `refund_request`.
+    if !request.accept {
+        let _ = refund_escrow(&investment_request, RefundReason::Rejected).await;
+    }
+
+    ApiResponse::success(investment_request)
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+async fn cancel_investment_request(request_id: String) -> ApiResponse<InvestmentRequest> {
+    let caller = get_caller();
+    let mut investment_request = match REQUESTS.with(|r| r.borrow().get(&request_id)) {
+        Some(req) => req,
+        None => return ApiResponse::error("Investment request not found".to_string()),
+    };
+
+    if let Err(e) = permissions::authorize(&caller, Operation::CancelRequest, &[(Ownership::RequestInvestor, investment_request.investor)]) {
+        return ApiResponse::error(e);
+    }
+
+    if !matches!(investment_request.status, RequestStatus::Pending) {
+        return ApiResponse::error("Only pending requests can be cancelled".to_string());
+    }
+
+    let _lock = match RequestLock::acquire(&request_id) {
+        Ok(lock) => lock,
+        Err(e) => return ApiResponse::error(e),
+    };
+
+    investment_request.status = RequestStatus::Cancelled;
+    investment_request.updated_at = get_current_time();
+    REQUESTS.with(|requests| {
+        requests
+            .borrow_mut()
+            .insert(request_id, investment_request.clone());
+    });
+
+    // Failures are recorded on the refund and can be retried with `refund_request`
+    let _ = refund_escrow(&investment_request, RefundReason::Cancelled).await;
+
     ApiResponse::success(investment_request)
 }
 
+fn reserve_offer_quantity(offer_id: &str, quantity: u64) -> Result<(), String> {
+    OFFERS.with(|offers| {
+        let mut offers_map = offers.borrow_mut();
+        let mut offer = offers_map
+            .get(&offer_id.to_string())
+            .ok_or_else(|| "Offer not found".to_string())?;
+        // Cancelled and expired offers take no more acceptances (nor, with ICRC-2, payments)
+        if !matches!(offer.status, OfferStatus::Active) {
+            return Err("Offer is no longer active".to_string());
+        }
+        if offer.available_quantity < quantity {
+            return Err("Insufficient quantity available".to_string());
+        }
+
+        offer.available_quantity -= quantity;
+        offer.updated_at = get_current_time();
+
+        // Mark as completed if no quantity left
+        if offer.available_quantity == 0 {
+            offer.status = OfferStatus::Completed;
+        }
+
+        offers_map.insert(offer_id.to_string(), offer);
+        Ok(())
+    })
+}
+
+fn release_offer_quantity(offer_id: &str, quantity: u64) {
+    OFFERS.with(|offers| {
+        let mut offers_map = offers.borrow_mut();
+        if let Some(mut offer) = offers_map.get(&offer_id.to_string()) {
+            offer.available_quantity += quantity;
+            offer.updated_at = get_current_time();
+            if matches!(offer.status, OfferStatus::Completed) {
+                offer.status = OfferStatus::Active;
+            }
+            offers_map.insert(offer_id.to_string(), offer);
+        }
+    });
+}
+
 // -----------------------------
-// New: Deposit / Escrow helpers
+// Escrow deposits
 // -----------------------------
 
 // Helper: create a deterministic 32-byte subaccount from request_id
@@ -579,14 +1307,72 @@ fn calculate_subaccount_hex(request_id: &str) -> String {
     hex::encode(bytes)
 }
 
-/// Returns deposit info: the escrow canister principal (this canister id) and the subaccount hex for the request.
-/// Frontend can compute an ICP account identifier: AccountIdentifier::new(escrow_canister_principal, Some(subaccount_bytes))
-#[ic_cdk::query]
-fn get_deposit_info(request_id: String) -> ApiResponse<DepositInfo> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
+fn escrow_account(request_id: &str) -> Account {
+    Account {
+        owner: canister_id(),
+        subaccount: Some(calculate_subaccount_bytes(request_id)),
+    }
+}
+
+fn decode_subaccount_hex(sub_hex: &str) -> Result<[u8; 32], String> {
+    hex::decode(sub_hex)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| "Malformed escrow subaccount".to_string())
+}
+
+fn new_escrow_record(req: &InvestmentRequest, offer: &InvestmentOffer) -> EscrowRecord {
+    EscrowRecord {
+        request_id: req.id.clone(),
+        offer_id: req.offer_id.clone(),
+        investor: req.investor,
+        farmer: offer.farmer,
+        subaccount_hex: calculate_subaccount_hex(&req.id),
+        token_symbol: offer_payment_token(offer).symbol,
+        expected_amount: expected_amount(req),
+        state: EscrowState::AwaitingDeposit,
+        entries: Vec::new(),
+        created_at: req.created_at,
+        updated_at: req.created_at,
     }
+}
+
+// Appends a ledger movement to the request's escrow record and moves it to `state`
+fn record_escrow_entry(
+    request_id: &str,
+    kind: EscrowEntryKind,
+    amount: u128,
+    ledger_fee: u128,
+    block_index: Option<u128>,
+    state: EscrowState,
+) {
+    ESCROWS.with(|e| {
+        let mut escrows = e.borrow_mut();
+        if let Some(mut record) = escrows.get(&request_id.to_string()) {
+            let now = get_current_time();
+            record.entries.push(EscrowEntry {
+                kind,
+                amount,
+                ledger_fee,
+                block_index,
+                recorded_at: now,
+            });
+            record.state = state;
+            record.updated_at = now;
+            escrows.insert(request_id.to_string(), record);
+        }
+    });
+}
+
+// Expected escrow deposit for a request, in base units of the offer's payment token
+fn expected_amount(req: &InvestmentRequest) -> Amount {
+    req.total_offered
+}
 
+/// Returns deposit info: the escrow canister principal (this canister id), the subaccount hex for the request
+/// and the token to pay in. Deposits go to the ICRC-1 account (escrow_canister, subaccount) on the token's ledger.
+#[ic_cdk::query(guard = "authenticated")]
+fn get_deposit_info(request_id: String) -> ApiResponse<DepositInfo> {
     // Check request exists and caller is the investor who created it
     let caller = get_caller();
     let req_opt = REQUESTS.with(|r| r.borrow().get(&request_id));
@@ -594,130 +1380,115 @@ fn get_deposit_info(request_id: String) -> ApiResponse<DepositInfo> {
         return ApiResponse::error("Request not found".into());
     }
     let req = req_opt.unwrap();
-    if req.investor != caller {
-        return ApiResponse::error("Unauthorized - only investor can request deposit info".into());
-    }
-
-    // ensure subaccount stored
-    let sub_hex = ESCROW_SUBACCOUNTS.with(|esc| {
-        let mut esc_map = esc.borrow_mut();
-        if let Some(s) = esc_map.get(&request_id) {
-            s.clone()
-        } else {
-            let new_hex = calculate_subaccount_hex(&request_id);
-            esc_map.insert(request_id.clone(), new_hex.clone());
-            new_hex
-        }
-    });
+    if let Err(e) = permissions::authorize(&caller, Operation::ViewDepositInfo, &[(Ownership::RequestInvestor, req.investor)]) {
+        return ApiResponse::error(e);
+    }
+
+    let sub_hex = ESCROWS
+        .with(|e| e.borrow().get(&request_id))
+        .map(|record| record.subaccount_hex)
+        .unwrap_or_else(|| calculate_subaccount_hex(&request_id));
+
+    let payment_token = match request_payment_token(&req) {
+        Ok(token) => token,
+        Err(e) => return ApiResponse::error(e),
+    };
 
     let deposit_info = DepositInfo {
-        escrow_canister: ic_cdk::id(),
+        escrow_canister: canister_id(),
         subaccount_hex: sub_hex,
-        // NOTE: amount in ICP must be computed by frontend or backend and shown in UI.
-        // We return the expected_amount here (in ICP e8 units recommended)
-        expected_amount_e8s: ((req.total_offered) * 100_000_000f64) as u128,
+        expected_amount: expected_amount(&req),
+        payment_token,
     };
 
     ApiResponse::success(deposit_info)
 }
 
-// DepositInfo type is defined here; ensure it matches candid
-
 /// settle_request: verifies deposit and mints shares to investor.
-/// IMPORTANT: ledger calls are not implemented here because ledger candid differs between local dfx and mainnet.
-/// You'll need to wire an inter-canister call to the ledger canister to check the balance of
-/// (this_canister_principal, subaccount) and ensure the expected amount has arrived.
-/// I left a clear TODO where to put that call. After verifying balance,
-/// this function mints shares to the investor and marks the transaction as tokenized.
-#[ic_cdk::update]
-fn settle_request(request_id: String) -> ApiResponse<Transaction> {
-    // This function requires admin/farmer authorization in production. Here we keep it simple.
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
+/// The balance of (this canister, escrow subaccount) is read from the configured ledger
+/// canister and must cover `DepositInfo.expected_amount` before any shares move.
+/// After verifying the balance, this function mints shares to the investor and marks
+/// the transaction as tokenized.
+#[ic_cdk::update(guard = "authenticated")]
+async fn settle_request(request_id: String) -> ApiResponse<Transaction> {
+    let caller = get_caller();
 
     // find request and related transaction
     let req_opt = REQUESTS.with(|r| r.borrow().get(&request_id));
     if req_opt.is_none() {
         return ApiResponse::error("Request not found".into());
     }
-    let _req = req_opt.unwrap();
+    let req = req_opt.unwrap();
 
-    // find transaction record by matching request_id
-    let txn_opt = TRANSACTIONS.with(|t| {
-        t.borrow()
-            .iter()
-            .find(|(_, txn)| txn.request_id == request_id)
-            .map(|(_, txn)| txn.clone())
-    });
+    let txn_opt = find_transaction_for_request(&request_id);
     if txn_opt.is_none() {
         return ApiResponse::error("Transaction not found".into());
     }
     let mut txn = txn_opt.unwrap();
+    let owners = [
+        (Ownership::RequestInvestor, req.investor),
+        (Ownership::TransactionFarmer, txn.farmer),
+    ];
+    if let Err(e) = permissions::authorize(&caller, Operation::SettleRequest, &owners) {
+        return ApiResponse::error(e);
+    }
+    if matches!(txn.status, TransactionStatus::Cancelled) {
+        return ApiResponse::error("Request expired before its deposit was settled".into());
+    }
+    if !matches!(txn.status, TransactionStatus::Confirmed) {
+        return ApiResponse::error("Transaction already settled".into());
+    }
 
     // retrieve escrow subaccount
-    let sub_hex_opt = ESCROW_SUBACCOUNTS.with(|esc| esc.borrow().get(&request_id));
-    if sub_hex_opt.is_none() {
+    let escrow_opt = ESCROWS.with(|e| e.borrow().get(&request_id));
+    if escrow_opt.is_none() {
         return ApiResponse::error("No escrow account for this request".into());
     }
-    let _sub_hex = sub_hex_opt.unwrap();
+    let subaccount = match decode_subaccount_hex(&escrow_opt.unwrap().subaccount_hex) {
+        Ok(sub) => sub,
+        Err(e) => return ApiResponse::error(e),
+    };
 
-    // TODO: call ledger canister to query balance for (this_canister, subaccount)
-    // Example (pseudo):
-    //  let ledger_principal = Principal::from_text("<LEDGER_CANISTER_PRINCIPAL>").unwrap();
-    //  let account_id = AccountIdentifier::new(ic_cdk::id(), Some(sub_bytes));
-    //  let balance = call ledger's `account_balance` or appropriate method to get balance
-    //
-    // For now, we cannot perform this call because the ledger candid may not be available.
-    // You must replace the logic below with a real ledger check.
+    let payment_token = match request_payment_token(&req) {
+        Ok(token) => token,
+        Err(e) => return ApiResponse::error(e),
+    };
 
-    // ----- PLACEHOLDER: assume payment is received (for local testing only) -----
-    let payment_received = true; // <<-- Replace with actual ledger check
-    // ---------------------------------------------------------------------------
+    let _lock = match RequestLock::acquire(&request_id) {
+        Ok(lock) => lock,
+        Err(e) => return ApiResponse::error(e),
+    };
 
-    if !payment_received {
-        return ApiResponse::error("Payment not yet received".into());
-    }
+    // query the token's ledger for the escrow balance of (this_canister, subaccount)
+    let escrow_account = Account {
+        owner: canister_id(),
+        subaccount: Some(subaccount),
+    };
+    let balance = match ledger::balance_of(payment_token.ledger_canister, escrow_account).await {
+        Ok(balance) => balance,
+        Err(e) => return ApiResponse::error(e),
+    };
 
-    // Mint (transfer) shares to investor: token_id derived from batch id of the offer
-    // find offer -> batch token id
-    let offer_opt = OFFERS.with(|o| o.borrow().get(&txn.offer_id));
-    if offer_opt.is_none() {
-        return ApiResponse::error("Offer not found".into());
+    let expected = expected_amount(&req);
+    if balance < expected.base_units() {
+        return ApiResponse::error(format!(
+            "Insufficient deposit: expected {} {} base units, escrow holds {}",
+            expected, payment_token.symbol, balance
+        ));
     }
-    let offer = offer_opt.unwrap();
-    let canonical_batch_id = format!("batch_{}", offer.id);
-    let token_id = format!("shares:{}", canonical_batch_id);
-
-    // compute share amount: 1 share per kg by default
-    let share_amount: u128 = txn.quantity as u128;
-
-    // update balances
-    let investor_key = format!("{}|{}", token_id, txn.investor.to_text());
-    SHARES_BALANCES.with(|b| {
-        let mut bmap = b.borrow_mut();
-        let current = bmap.get(&investor_key).unwrap_or(0u128);
-        let farmer_balance = bmap.get(&farmer_key).unwrap_or(0u128);
-
 
-    });
-
-    // subtract from farmer balance
-    let farmer_key = format!("{}|{}", token_id, txn.farmer.to_text());
-    SHARES_BALANCES.with(|b| {
-        let mut bmap = b.borrow_mut();
-        let farmer_balance = bmap.get(&farmer_key).copied().unwrap_or(0u128);
-        let new_farmer = if farmer_balance >= share_amount {
-            farmer_balance - share_amount
-        } else {
-            0u128
-        };
-        bmap.insert(farmer_key.clone(), new_farmer);
-    });
+    if let Err(e) = tokenize_transaction(&mut txn) {
+        return ApiResponse::error(e);
+    }
 
-    txn.status = TransactionStatus::Tokenized;
-    txn.tokenized_at = Some(get_current_time());
-    txn.updated_at = get_current_time();
+    record_escrow_entry(
+        &request_id,
+        EscrowEntryKind::Deposit,
+        balance,
+        0,
+        None,
+        EscrowState::Funded,
+    );
 
     // persist transaction
     TRANSACTIONS.with(|t| {
@@ -727,105 +1498,1476 @@ fn settle_request(request_id: String) -> ApiResponse<Transaction> {
     ApiResponse::success(txn)
 }
 
-// -----------------------------
-// Transaction functions (unchanged)
-// -----------------------------
+fn pull_memo(request_id: &str) -> Vec<u8> {
+    let mut hasher = Sha224::new();
+    hasher.update(b"pull:");
+    hasher.update(request_id.as_bytes());
+    hasher.finalize().to_vec()
+}
 
-#[ic_cdk::query]
-fn get_farmer_transactions() -> ApiResponse<Vec<Transaction>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
-    }
+// Pulls an ICRC-2 approved payment from the investor's default account into the request's escrow
+// subaccount. Callers must hold the request's RequestLock. The ledger arguments are stored on the
+// request before the call, so that an acceptance retried after a failed call sends the same transfer
+// and the ledger reports it as a duplicate if the first one went through. Past the deduplication
+// window, an escrow that already holds the payment is taken as paid. Returns the block index of the
+// pull, if known.
+async fn pull_approved_payment(req: &mut InvestmentRequest) -> Result<Option<u128>, String> {
+    let payment_token = request_payment_token(req)?;
+    let ledger = payment_token.ledger_canister;
+    let now = get_current_time();
+    let pull = match req.payment_pull.take() {
+        Some(pull) if now.saturating_sub(pull.created_at_time) < LEDGER_DEDUP_WINDOW_NANOS => pull,
+        previous => {
+            let amount = expected_amount(req).base_units();
+            if previous.is_some()
+                && ledger::balance_of(ledger, escrow_account(&req.id)).await? >= amount
+            {
+                return Ok(None);
+            }
+            PaymentPull {
+                amount,
+                created_at_time: now,
+            }
+        }
+    };
+    req.payment_pull = Some(pull.clone());
+    req.updated_at = now;
+    REQUESTS.with(|r| r.borrow_mut().insert(req.id.clone(), req.clone()));
+
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: req.investor,
+            subaccount: None,
+        },
+        to: escrow_account(&req.id),
+        amount: pull.amount,
+        fee: None,
+        memo: Some(pull_memo(&req.id)),
+        created_at_time: Some(pull.created_at_time),
+    };
+    ledger::transfer_from(ledger, args).await.map(Some)
+}
 
-    let caller = get_caller();
-    let transactions = TRANSACTIONS.with(|transactions| {
-        transactions
-            .borrow()
+// Mints the transaction's shares to the investor (moved out of the farmer's allocation)
+// and marks it tokenized. Callers persist the transaction.
+fn tokenize_transaction(txn: &mut Transaction) -> Result<(), String> {
+    // Mint (transfer) shares to investor: token_id derived from batch id of the offer
+    // find offer -> batch token id
+    let offer = OFFERS
+        .with(|o| o.borrow().get(&txn.offer_id))
+        .ok_or_else(|| "Offer not found".to_string())?;
+    let token_id = shares::token_id(&offer.id);
+
+    // compute share amount: 1 share per kg by default
+    let share_amount: u128 = txn.quantity as u128;
+
+    // move shares from the farmer's allocation to the investor
+    shares::move_shares(
+        &token_id,
+        &shares::account_of(txn.farmer),
+        &shares::account_of(txn.investor),
+        share_amount,
+        &txn.id.as_bytes().to_vec(),
+    )?;
+
+    let now = get_current_time();
+    txn.status = TransactionStatus::Tokenized;
+    txn.tokenized_at = Some(now);
+    txn.updated_at = now;
+    Ok(())
+}
+
+fn find_transaction_for_request(request_id: &str) -> Option<Transaction> {
+    TRANSACTIONS.with(|t| {
+        t.borrow()
             .iter()
-            .filter(|(_, txn)| txn.farmer == caller)
+            .find(|(_, txn)| txn.request_id == request_id)
             .map(|(_, txn)| txn.clone())
-            .collect::<Vec<_>>()
-    });
+    })
+}
 
-    ApiResponse::success(transactions)
+// -----------------------------
+// Escrow release to the farmer
+// -----------------------------
+
+fn release_memo(request_id: &str) -> Vec<u8> {
+    let mut hasher = Sha224::new();
+    hasher.update(b"release:");
+    hasher.update(request_id.as_bytes());
+    hasher.finalize().to_vec()
 }
 
-#[ic_cdk::query]
-fn get_investor_transactions() -> ApiResponse<Vec<Transaction>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
+// Treasury lives in a dedicated subaccount of this canister
+fn treasury_account() -> Account {
+    let mut subaccount = [0u8; 32];
+    subaccount[..8].copy_from_slice(b"treasury");
+    Account {
+        owner: canister_id(),
+        subaccount: Some(subaccount),
     }
+}
 
-    let caller = get_caller();
-    let transactions = TRANSACTIONS.with(|transactions| {
-        transactions
-            .borrow()
-            .iter()
-            .filter(|(_, txn)| txn.investor == caller)
-            .map(|(_, txn)| txn.clone())
-            .collect::<Vec<_>>()
-    });
+// Commission on an escrowed amount, rounded down. Waived when it would not cover the
+// ledger fee of moving it to the treasury.
+fn platform_commission(escrowed: Amount, ledger_fee: u128) -> u128 {
+    let bps = TREASURY.with(|t| t.borrow().get().platform_fee_bps.min(MAX_PLATFORM_FEE_BPS));
+    let commission = escrowed
+        .mul_bps(bps, Rounding::Down)
+        .map(Amount::base_units)
+        .unwrap_or(0);
+    if commission > ledger_fee {
+        commission
+    } else {
+        0
+    }
+}
 
-    ApiResponse::success(transactions)
+// Adds `amount` to the entry for `token_symbol`, keeping one entry per token
+fn add_token_amount(totals: &mut Vec<TokenAmount>, token_symbol: &str, amount: u128) {
+    match totals.iter_mut().find(|t| t.token_symbol == token_symbol) {
+        Some(total) => total.amount += amount,
+        None => totals.push(TokenAmount {
+            token_symbol: token_symbol.to_string(),
+            amount,
+        }),
+    }
 }
 
-// -----------------------------
-// Admin functions (unchanged)
-// -----------------------------
+// Net commission credited to the treasury, per token
+fn fee_revenue() -> Vec<TokenAmount> {
+    let records = FEES.with(|f| f.borrow().iter().map(|(_, record)| record).collect::<Vec<_>>());
+    summarize_fees(records).total_collected
+}
 
-#[ic_cdk::query]
-fn get_all_users() -> ApiResponse<Vec<UserProfile>> {
-    if !is_authenticated() {
-        return ApiResponse::error("Authentication required".to_string());
+fn treasury_balance(token_symbol: &str) -> u128 {
+    let revenue = fee_revenue()
+        .into_iter()
+        .find(|t| t.token_symbol == token_symbol)
+        .map(|t| t.amount)
+        .unwrap_or(0);
+    let withdrawn = TREASURY_WITHDRAWN
+        .with(|w| w.borrow().get(&token_symbol.to_string()))
+        .unwrap_or(0);
+    revenue.saturating_sub(withdrawn)
+}
+
+// Pays the escrowed amount of a tokenized transaction, minus the platform commission and the ledger fee, to the farmer's
+// default account and completes the transaction. Whatever the escrow holds beyond the expected amount goes back to the
+// investor, net of the ledger fee. Callers check eligibility (delivery confirmed or harvest date reached) and persist
+// the returned transaction.
+async fn release_escrow_to_farmer(mut txn: Transaction) -> Result<Transaction, String> {
+    if !matches!(txn.status, TransactionStatus::Tokenized) {
+        return Err("Only tokenized transactions can be released".to_string());
     }
 
-    let caller = get_caller();
+    let req = REQUESTS
+        .with(|r| r.borrow().get(&txn.request_id))
+        .ok_or_else(|| "Request not found".to_string())?;
 
-    // Check if caller is admin
-    let is_admin = USERS.with(|users| {
-        users
-            .borrow()
-            .get(&caller)
-            .map(|user| matches!(user.role, UserRole::Admin))
-            .unwrap_or(false)
+    let _lock = RequestLock::acquire(&txn.request_id)?;
+
+    let payment_token = request_payment_token(&req)?;
+    let ledger = payment_token.ledger_canister;
+    let escrowed = expected_amount(&req);
+
+    // A recorded fee means an earlier attempt already moved the commission, so a retry only
+    // pays the farmer
+    let recorded_fee = FEES.with(|f| f.borrow().get(&txn.id));
+
+    // Retries inside the dedup window send the same transfers as the first attempt, so the
+    // ledger rejects them as duplicates instead of paying twice
+    let now = get_current_time();
+    let plan = match txn.release_plan.clone() {
+        Some(plan) if now.saturating_sub(plan.created_at_time) < LEDGER_DEDUP_WINDOW_NANOS => plan,
+        _ => {
+            let fee = ledger::fee(ledger).await?;
+            let commission = match &recorded_fee {
+                Some(record) => record.gross_amount,
+                None => platform_commission(escrowed, fee),
+            };
+            // The escrow holds an overpayment when it has more than it still owes. One that
+            // would not cover the ledger fee of sending it back stays in the escrow
+            let balance = ledger::balance_of(ledger, escrow_account(&txn.request_id)).await?;
+            let owed = match &recorded_fee {
+                Some(_) => escrowed.base_units().saturating_sub(commission),
+                None => escrowed.base_units(),
+            };
+            let overpayment = Some(balance.saturating_sub(owed)).filter(|excess| *excess > fee);
+            ReleasePlan { commission, fee, created_at_time: now, overpayment }
+        }
+    };
+    let ReleasePlan { commission, fee, created_at_time, overpayment } = plan.clone();
+
+    // The escrow pays the commission (the fee of moving it included), the farmer's ledger fee
+    // and a non-zero payout
+    let amount = escrowed
+        .base_units()
+        .checked_sub(commission)
+        .and_then(|amount| amount.checked_sub(fee))
+        .filter(|amount| *amount > 0)
+        .ok_or_else(|| {
+            "Escrowed amount does not cover the commission and ledger fees".to_string()
+        })?;
+
+    txn.release_plan = Some(plan);
+    TRANSACTIONS.with(|t| {
+        t.borrow_mut().insert(txn.id.clone(), txn.clone());
     });
 
-    if !is_admin {
-        return ApiResponse::error("Admin access required".to_string());
+    // Platform commission goes to the treasury first
+    if recorded_fee.is_none() && commission > 0 {
+        let arg = TransferArg {
+            from_subaccount: Some(calculate_subaccount_bytes(&txn.request_id)),
+            to: treasury_account(),
+            amount: commission - fee,
+            fee: Some(fee),
+            memo: Some(release_memo(&txn.request_id)),
+            created_at_time: Some(created_at_time),
+        };
+        let block_index = ledger::transfer(ledger, arg).await?;
+        record_escrow_entry(
+            &txn.request_id,
+            EscrowEntryKind::Commission,
+            commission - fee,
+            fee,
+            Some(block_index),
+            EscrowState::Funded,
+        );
+        let record = FeeRecord {
+            transaction_id: txn.id.clone(),
+            offer_id: txn.offer_id.clone(),
+            token_symbol: payment_token.symbol.clone(),
+            gross_amount: commission,
+            ledger_fee: fee,
+            block_index,
+            collected_at: get_current_time(),
+        };
+        FEES.with(|f| f.borrow_mut().insert(txn.id.clone(), record));
     }
 
-    let users = USERS.with(|users| {
-        users
-            .borrow()
-            .iter()
-            .map(|(_, user)| user.clone())
-            .collect::<Vec<_>>()
+    // Then the overpayment goes back to the investor, unless an earlier attempt refunded it
+    let refunded = ESCROWS.with(|e| {
+        e.borrow().get(&txn.request_id).is_some_and(|record| {
+            record.entries.iter().any(|entry| matches!(entry.kind, EscrowEntryKind::Refund))
+        })
     });
+    if let Some(overpayment) = overpayment.filter(|_| !refunded) {
+        let arg = TransferArg {
+            from_subaccount: Some(calculate_subaccount_bytes(&txn.request_id)),
+            to: Account {
+                owner: txn.investor,
+                subaccount: None,
+            },
+            amount: overpayment - fee,
+            fee: Some(fee),
+            memo: Some(refund_memo(&txn.request_id)),
+            created_at_time: Some(created_at_time),
+        };
+        let block_index = ledger::transfer(ledger, arg).await?;
+        record_escrow_entry(
+            &txn.request_id,
+            EscrowEntryKind::Refund,
+            overpayment - fee,
+            fee,
+            Some(block_index),
+            EscrowState::Funded,
+        );
+    }
 
-    ApiResponse::success(users)
+    let arg = TransferArg {
+        from_subaccount: Some(calculate_subaccount_bytes(&txn.request_id)),
+        to: Account {
+            owner: txn.farmer,
+            subaccount: None,
+        },
+        amount,
+        fee: Some(fee),
+        memo: Some(release_memo(&txn.request_id)),
+        created_at_time: Some(created_at_time),
+    };
+    let block_index = ledger::transfer(ledger, arg).await?;
+    record_escrow_entry(
+        &txn.request_id,
+        EscrowEntryKind::Release,
+        amount,
+        fee,
+        Some(block_index),
+        EscrowState::Released,
+    );
+
+    let now = get_current_time();
+    txn.status = TransactionStatus::Completed;
+    txn.released_at = Some(now);
+    txn.release_block_index = Some(block_index);
+    txn.updated_at = now;
+
+    TRANSACTIONS.with(|t| {
+        t.borrow_mut().insert(txn.id.clone(), txn.clone());
+    });
+
+    Ok(txn)
 }
 
-#[ic_cdk::query]
-fn get_platform_stats() -> ApiResponse<PlatformStats> {
-    let stats = PlatformStats {
-        total_users: USERS.with(|users| users.borrow().len() as u64),
-        total_offers: OFFERS.with(|offers| offers.borrow().len() as u64),
-        total_requests: REQUESTS.with(|requests| requests.borrow().len() as u64),
-        total_transactions: TRANSACTIONS.with(|transactions| transactions.borrow().len() as u64),
-        active_offers: OFFERS.with(|offers| {
-            offers
-                .borrow()
-                .iter()
-                .filter(|(_, offer)| matches!(offer.status, OfferStatus::Active))
-                .count() as u64
-        }),
+/// Investor confirms the goods were delivered; the escrow is released to the farmer right away.
+#[ic_cdk::update(guard = "authenticated")]
+async fn confirm_delivery(transaction_id: String) -> ApiResponse<Transaction> {
+    let caller = get_caller();
+    let mut txn = match TRANSACTIONS.with(|t| t.borrow().get(&transaction_id)) {
+        Some(txn) => txn,
+        None => return ApiResponse::error("Transaction not found".into()),
     };
 
-    ApiResponse::success(stats)
+    if let Err(e) = permissions::authorize(&caller, Operation::ConfirmDelivery, &[(Ownership::TransactionInvestor, txn.investor)]) {
+        return ApiResponse::error(e);
+    }
+
+    if !matches!(txn.status, TransactionStatus::Tokenized) {
+        return ApiResponse::error("Only tokenized transactions can be confirmed".to_string());
+    }
+
+    // The confirmation is recorded even if the ledger transfer below fails,
+    // so the farmer can retry the release with `release_escrow`
+    if txn.delivered_at.is_none() {
+        txn.delivered_at = Some(get_current_time());
+        txn.updated_at = get_current_time();
+        TRANSACTIONS.with(|t| {
+            t.borrow_mut().insert(txn.id.clone(), txn.clone());
+        });
+    }
+
+    match release_escrow_to_farmer(txn).await {
+        Ok(txn) => ApiResponse::success(txn),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+/// Releases the escrow of a tokenized transaction to the farmer once delivery was confirmed
+/// or the offer's harvest date has been reached.
+#[ic_cdk::update(guard = "authenticated")]
+async fn release_escrow(transaction_id: String) -> ApiResponse<Transaction> {
+    let caller = get_caller();
+    let txn = match TRANSACTIONS.with(|t| t.borrow().get(&transaction_id)) {
+        Some(txn) => txn,
+        None => return ApiResponse::error("Transaction not found".into()),
+    };
+
+    if let Err(e) = permissions::authorize(&caller, Operation::ReleaseEscrow, &[(Ownership::TransactionFarmer, txn.farmer)]) {
+        return ApiResponse::error(e);
+    }
+
+    if txn.delivered_at.is_none() {
+        let harvest_date = OFFERS.with(|o| o.borrow().get(&txn.offer_id).map(|o| o.harvest_date));
+        let harvest_at = match harvest_date.as_deref().and_then(parse_date_nanos) {
+            Some(at) => at,
+            None => return ApiResponse::error("Offer has no valid harvest date".into()),
+        };
+        if get_current_time() < harvest_at {
+            return ApiResponse::error(
+                "Escrow is released on delivery confirmation or at the harvest date".into(),
+            );
+        }
+    }
+
+    match release_escrow_to_farmer(txn).await {
+        Ok(txn) => ApiResponse::success(txn),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+// -----------------------------
+// Escrow refunds
+// -----------------------------
+
+// Ledgers deduplicate identical transfers for 24h; retries inside this window reuse the original arguments
+const LEDGER_DEDUP_WINDOW_NANOS: u64 = 23 * 60 * 60 * 1_000_000_000;
+
+fn refund_memo(request_id: &str) -> Vec<u8> {
+    let mut hasher = Sha224::new();
+    hasher.update(b"refund:");
+    hasher.update(request_id.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Sends the escrow balance of a request, net of the ledger fee, back to the investor's default account.
+// Callers must hold the request's RequestLock. A completed refund is returned as-is, and a failed one is
+// retried with the same ledger arguments so that the ledger deduplicates it. Returns Ok(None) when the
+// escrow balance does not cover the ledger fee.
+async fn refund_escrow(
+    req: &InvestmentRequest,
+    reason: RefundReason,
+) -> Result<Option<Refund>, String> {
+    let existing = REFUNDS.with(|r| r.borrow().get(&req.id));
+    if let Some(refund) = &existing {
+        if matches!(refund.status, RefundStatus::Completed) {
+            return Ok(existing);
+        }
+    }
+
+    let payment_token = request_payment_token(req)?;
+    let ledger = payment_token.ledger_canister;
+    let now = get_current_time();
+    let mut refund = match existing {
+        Some(refund) if now.saturating_sub(refund.created_at_time) < LEDGER_DEDUP_WINDOW_NANOS => {
+            refund
+        }
+        existing => {
+            let balance = ledger::balance_of(ledger, escrow_account(&req.id)).await?;
+            let fee = ledger::fee(ledger).await?;
+            if balance <= fee {
+                // Nothing was ever deposited: the escrow ends empty
+                if existing.is_none() {
+                    ESCROWS.with(|e| {
+                        let mut escrows = e.borrow_mut();
+                        if let Some(mut record) = escrows.get(&req.id) {
+                            if record.state == EscrowState::AwaitingDeposit {
+                                record.state = EscrowState::Closed;
+                                record.updated_at = now;
+                                escrows.insert(req.id.clone(), record);
+                            }
+                        }
+                    });
+                }
+                return Ok(existing);
+            }
+            Refund {
+                id: existing
+                    .as_ref()
+                    .map(|r| r.id.clone())
+                    .unwrap_or_else(|| generate_id("refund")),
+                request_id: req.id.clone(),
+                investor: req.investor,
+                reason,
+                token_symbol: payment_token.symbol.clone(),
+                amount: balance - fee,
+                fee,
+                status: RefundStatus::Pending,
+                block_index: None,
+                created_at_time: now,
+                created_at: existing.as_ref().map(|r| r.created_at).unwrap_or(now),
+                updated_at: now,
+            }
+        }
+    };
+
+    refund.status = RefundStatus::Pending;
+    refund.updated_at = now;
+    REFUNDS.with(|r| r.borrow_mut().insert(req.id.clone(), refund.clone()));
+
+    let arg = TransferArg {
+        from_subaccount: Some(calculate_subaccount_bytes(&req.id)),
+        to: Account {
+            owner: req.investor,
+            subaccount: None,
+        },
+        amount: refund.amount,
+        fee: Some(refund.fee),
+        memo: Some(refund_memo(&req.id)),
+        created_at_time: Some(refund.created_at_time),
+    };
+    match ledger::transfer(ledger, arg).await {
+        Ok(block_index) => {
+            refund.status = RefundStatus::Completed;
+            refund.block_index = Some(block_index);
+            record_escrow_entry(
+                &req.id,
+                EscrowEntryKind::Refund,
+                refund.amount,
+                refund.fee,
+                Some(block_index),
+                EscrowState::Refunded,
+            );
+        }
+        Err(e) => refund.status = RefundStatus::Failed(e),
+    }
+    refund.updated_at = get_current_time();
+    REFUNDS.with(|r| r.borrow_mut().insert(req.id.clone(), refund.clone()));
+
+    Ok(Some(refund))
+}
+
+/// Refunds the escrow balance of a rejected, cancelled or expired request to its investor.
+/// Pending requests past `expires_at` are marked expired first. Safe to call repeatedly.
+#[ic_cdk::update(guard = "authenticated")]
+async fn refund_request(request_id: String) -> ApiResponse<Refund> {
+    let caller = get_caller();
+    let mut req = match REQUESTS.with(|r| r.borrow().get(&request_id)) {
+        Some(req) => req,
+        None => return ApiResponse::error("Request not found".into()),
+    };
+
+    if let Err(e) = permissions::authorize(&caller, Operation::RefundRequest, &[(Ownership::RequestInvestor, req.investor)]) {
+        return ApiResponse::error(e);
+    }
+
+    let _lock = match RequestLock::acquire(&request_id) {
+        Ok(lock) => lock,
+        Err(e) => return ApiResponse::error(e),
+    };
+
+    let now = get_current_time();
+    let reason = match req.status {
+        RequestStatus::Rejected => RefundReason::Rejected,
+        RequestStatus::Cancelled => RefundReason::Cancelled,
+        RequestStatus::Expired => RefundReason::Expired,
+        RequestStatus::Pending if now > req.expires_at => {
+            req.status = RequestStatus::Expired;
+            req.updated_at = now;
+            REQUESTS.with(|r| r.borrow_mut().insert(request_id.clone(), req.clone()));
+            RefundReason::Expired
+        }
+        _ => return ApiResponse::error("Request is not eligible for a refund".into()),
+    };
+
+    match refund_escrow(&req, reason).await {
+        Ok(Some(refund)) => match &refund.status {
+            RefundStatus::Failed(e) => ApiResponse::error(format!("Refund failed: {}", e)),
+            _ => ApiResponse::success(refund),
+        },
+        Ok(None) => ApiResponse::error("Nothing to refund".into()),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_refund(request_id: String) -> ApiResponse<Option<Refund>> {
+    let caller = get_caller();
+    let req = match REQUESTS.with(|r| r.borrow().get(&request_id)) {
+        Some(req) => req,
+        None => return ApiResponse::error("Request not found".into()),
+    };
+
+    let mut owners = vec![(Ownership::RequestInvestor, req.investor)];
+    if let Some(farmer) = offer_farmer(&req.offer_id) {
+        owners.push((Ownership::OfferFarmer, farmer));
+    }
+    if let Err(e) = permissions::authorize(&caller, Operation::ViewRefund, &owners) {
+        return ApiResponse::error(e);
+    }
+
+    ApiResponse::success(REFUNDS.with(|r| r.borrow().get(&request_id)))
+}
+
+// -----------------------------
+// Escrow records
+// -----------------------------
+
+/// Escrow history of a request: expected amount, verified deposits, refunds, commission and release,
+/// each with its ledger block index. Visible to the request's investor, the offer's farmer and admins.
+#[ic_cdk::query(guard = "authenticated")]
+fn get_escrow(request_id: String) -> ApiResponse<EscrowRecord> {
+    let caller = get_caller();
+    let record = match ESCROWS.with(|e| e.borrow().get(&request_id)) {
+        Some(record) => record,
+        None => return ApiResponse::error("Escrow record not found".into()),
+    };
+
+    let owners = [
+        (Ownership::RequestInvestor, record.investor),
+        (Ownership::OfferFarmer, record.farmer),
+    ];
+    if let Err(e) = permissions::authorize(&caller, Operation::ViewEscrow, &owners) {
+        return ApiResponse::error(e);
+    }
+
+    ApiResponse::success(record)
+}
+
+/// Escrow records where the caller is the investor or the farmer; admins get every record.
+#[ic_cdk::query(guard = "authenticated")]
+fn get_escrows() -> ApiResponse<Vec<EscrowRecord>> {
+    let caller = get_caller();
+    // Roles allowed to view any escrow see them all
+    let admin = permissions::authorize(&caller, Operation::ViewEscrow, &[]).is_ok();
+    let records = ESCROWS.with(|e| {
+        e.borrow()
+            .iter()
+            .filter(|(_, record)| admin || record.investor == caller || record.farmer == caller)
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(records)
+}
+
+// -----------------------------
+// Expiry sweeps
+// -----------------------------
+
+const DEFAULT_SWEEP_INTERVAL_SECS: u64 = 60 * 60;
+const MIN_SWEEP_INTERVAL_SECS: u64 = 60;
+
+fn sweep_interval_secs() -> u64 {
+    get_config()
+        .expiry_sweep_interval_secs
+        .unwrap_or(DEFAULT_SWEEP_INTERVAL_SECS)
+}
+
+// (Re)arms the periodic sweep with the configured interval, replacing any running timer
+fn schedule_expiry_sweep() {
+    let interval = Duration::from_secs(sweep_interval_secs());
+    let timer = ic_cdk_timers::set_timer_interval(interval, || {
+        ic_cdk::spawn(async {
+            let report = sweep_expired().await;
+            if report.expired_offers + report.expired_requests + report.refunds_failed > 0 {
+                ic_cdk::println!("Expiry sweep: {:?}", report);
+            }
+        })
+    });
+    if let Some(previous) = SWEEP_TIMER.with(|t| t.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+fn is_request_locked(request_id: &str) -> bool {
+    REQUEST_LOCKS.with(|locks| locks.borrow().contains(request_id))
+}
+
+// An offer expires once its harvest day is over
+fn harvest_passed(offer: &InvestmentOffer, now: u64) -> bool {
+    parse_date_nanos(&offer.harvest_date)
+        .map(|harvest_at| now >= harvest_at.saturating_add(NANOS_PER_DAY))
+        .unwrap_or(false)
+}
+
+// Marks stale offers and requests expired and returns the requests whose escrow must be refunded.
+// Requests with a ledger call in flight are left for the next sweep.
+fn expire_stale_records(now: u64, report: &mut ExpirySweepReport) -> Vec<InvestmentRequest> {
+    // Active offers whose harvest date has passed
+    let stale_offers: Vec<InvestmentOffer> = OFFERS.with(|o| {
+        o.borrow()
+            .iter()
+            .filter(|(_, offer)| matches!(offer.status, OfferStatus::Active) && harvest_passed(offer, now))
+            .map(|(_, offer)| offer)
+            .collect()
+    });
+    for mut offer in stale_offers {
+        offer.status = OfferStatus::Expired;
+        offer.updated_at = now;
+        OFFERS.with(|o| o.borrow_mut().insert(offer.id.clone(), offer));
+        report.expired_offers += 1;
+    }
+
+    // Pending requests past `expires_at` or on an expired offer, and accepted requests whose deposit
+    // was never settled by `expires_at`
+    let candidates: Vec<InvestmentRequest> = REQUESTS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(id, req)| {
+                matches!(req.status, RequestStatus::Pending | RequestStatus::Accepted) && !is_request_locked(id)
+            })
+            .map(|(_, req)| req)
+            .collect()
+    });
+
+    let mut expired = Vec::new();
+    for mut req in candidates {
+        let offer_expired = OFFERS
+            .with(|o| o.borrow().get(&req.offer_id))
+            .map(|offer| matches!(offer.status, OfferStatus::Expired))
+            .unwrap_or(false);
+        match req.status {
+            RequestStatus::Pending if now > req.expires_at || offer_expired => {}
+            RequestStatus::Accepted if now > req.expires_at => {
+                let unsettled = find_transaction_for_request(&req.id)
+                    .filter(|txn| matches!(txn.status, TransactionStatus::Confirmed));
+                let mut txn = match unsettled {
+                    Some(txn) => txn,
+                    None => continue,
+                };
+                txn.status = TransactionStatus::Cancelled;
+                txn.updated_at = now;
+                TRANSACTIONS.with(|t| t.borrow_mut().insert(txn.id.clone(), txn));
+                release_offer_quantity(&req.offer_id, req.requested_quantity);
+                report.released_quantity += req.requested_quantity;
+            }
+            _ => continue,
+        }
+
+        req.status = RequestStatus::Expired;
+        req.updated_at = now;
+        REQUESTS.with(|r| r.borrow_mut().insert(req.id.clone(), req.clone()));
+        report.expired_requests += 1;
+        expired.push(req);
+    }
+
+    // Earlier sweeps' refunds that did not go through are retried
+    let retries: Vec<InvestmentRequest> = REFUNDS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(id, refund)| {
+                !matches!(refund.status, RefundStatus::Completed) && !is_request_locked(id)
+            })
+            .filter_map(|(id, _)| REQUESTS.with(|r| r.borrow().get(&id)))
+            .filter(|req| matches!(req.status, RequestStatus::Expired))
+            .collect()
+    });
+    expired.extend(retries);
+    expired
+}
+
+// Expires stale offers and requests, returns reserved quantity to offers and refunds expired escrows
+async fn sweep_expired() -> ExpirySweepReport {
+    let now = get_current_time();
+    let mut report = ExpirySweepReport {
+        ran_at: now,
+        ..Default::default()
+    };
+
+    for req in expire_stale_records(now, &mut report) {
+        let _lock = match RequestLock::acquire(&req.id) {
+            Ok(lock) => lock,
+            Err(_) => continue,
+        };
+        match refund_escrow(&req, RefundReason::Expired).await {
+            Ok(Some(refund)) if matches!(refund.status, RefundStatus::Completed) => {
+                report.refunds_completed += 1
+            }
+            Ok(Some(_)) | Err(_) => report.refunds_failed += 1,
+            Ok(None) => {}
+        }
+    }
+
+    report
+}
+
+/// Runs an expiry sweep immediately instead of waiting for the timer.
+#[ic_cdk::update(guard = "authenticated")]
+async fn run_expiry_sweep() -> ApiResponse<ExpirySweepReport> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::RunExpirySweep, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    ApiResponse::success(sweep_expired().await)
+}
+
+/// Sets the number of seconds between expiry sweeps and restarts the timer.
+#[ic_cdk::update(guard = "authenticated")]
+fn set_expiry_sweep_interval(interval_secs: u64) -> ApiResponse<CanisterConfig> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ConfigureCanister, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    if interval_secs < MIN_SWEEP_INTERVAL_SECS {
+        return ApiResponse::error(format!(
+            "Sweep interval must be at least {} seconds",
+            MIN_SWEEP_INTERVAL_SECS
+        ));
+    }
+
+    let mut config = get_config();
+    config.expiry_sweep_interval_secs = Some(interval_secs);
+    CONFIG.with(|c| c.borrow_mut().set(config.clone()))
+        .expect("failed to persist config");
+    schedule_expiry_sweep();
+
+    ApiResponse::success(config)
+}
+
+// -----------------------------
+// Transaction functions
+// -----------------------------
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_farmer_transactions() -> ApiResponse<Vec<Transaction>> {
+    let caller = get_caller();
+    let transactions = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .filter(|(_, txn)| txn.farmer == caller)
+            .map(|(_, txn)| txn.clone())
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(transactions)
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_investor_transactions() -> ApiResponse<Vec<Transaction>> {
+    let caller = get_caller();
+    let transactions = TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .iter()
+            .filter(|(_, txn)| txn.investor == caller)
+            .map(|(_, txn)| txn.clone())
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(transactions)
+}
+
+// -----------------------------
+// Batch share ledgers (ICRC-1, ICRC-2 and the ICRC-3 block log, see shares.rs and icrc3.rs)
+// -----------------------------
+
+fn known_share_token(token_id: &str) -> &str {
+    if !shares::exists(token_id) {
+        ic_cdk::trap(&format!("Unknown share token: {}", token_id));
+    }
+    token_id
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_name(token_id: String) -> String {
+    shares::name(known_share_token(&token_id)).unwrap_or_default()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_symbol(token_id: String) -> String {
+    shares::symbol(known_share_token(&token_id)).unwrap_or_default()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_decimals(token_id: String) -> u8 {
+    known_share_token(&token_id);
+    shares::DECIMALS
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_fee(token_id: String) -> u128 {
+    known_share_token(&token_id);
+    shares::FEE
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_metadata(token_id: String) -> Vec<(String, shares::MetadataValue)> {
+    shares::metadata(known_share_token(&token_id)).unwrap_or_default()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_total_supply(token_id: String) -> u128 {
+    shares::total_supply(known_share_token(&token_id))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_minting_account(token_id: String) -> Option<Account> {
+    known_share_token(&token_id);
+    Some(shares::minting_account())
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_balance_of(token_id: String, account: Account) -> u128 {
+    shares::balance_of(known_share_token(&token_id), &account)
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn shares_icrc1_transfer(token_id: String, arg: TransferArg) -> Result<u128, TransferError> {
+    shares::transfer(get_caller(), &token_id, arg)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc2_allowance(token_id: String, args: shares::AllowanceArgs) -> ShareAllowance {
+    shares::allowance(known_share_token(&token_id), &args.account, &args.spender)
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn shares_icrc2_approve(
+    token_id: String,
+    args: shares::ApproveArgs,
+) -> Result<u128, shares::ApproveError> {
+    shares::approve(get_caller(), &token_id, args)
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn shares_icrc2_transfer_from(
+    token_id: String,
+    args: TransferFromArgs,
+) -> Result<u128, TransferFromError> {
+    shares::transfer_from(get_caller(), &token_id, args)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn shares_icrc1_supported_standards() -> Vec<shares::SupportedStandard> {
+    shares::supported_standards()
+}
+
+// Standard ICRC-1 and ICRC-2 ledgers of the share tokens, one canister per batch (see
+// share_ledgers.rs)
+
+#[ic_cdk::update(guard = "authenticated")]
+fn register_share_ledger(token_id: String, ledger: Principal) -> ApiResponse<Principal> {
+    match share_ledgers::register(get_caller(), &token_id, ledger) {
+        Ok(()) => ApiResponse::success(ledger),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn get_share_ledger(token_id: String) -> Option<Principal> {
+    share_ledgers::ledger_of(&token_id)
+}
+
+fn rejected_forward(message: String) -> TransferError {
+    TransferError::GenericError {
+        error_code: share_ledgers::REJECTED_FORWARD,
+        message,
+    }
+}
+
+/// `icrc1_transfer` of a share ledger on behalf of `owner`. Registered share ledgers only.
+#[ic_cdk::update(guard = "authenticated")]
+fn share_ledger_transfer(owner: Principal, arg: TransferArg) -> Result<u128, TransferError> {
+    let token_id =
+        share_ledgers::forwarded_token(&get_caller(), &owner).map_err(rejected_forward)?;
+    shares::transfer(owner, &token_id, arg)
+}
+
+/// `icrc2_approve` of a share ledger on behalf of `owner`. Registered share ledgers only.
+#[ic_cdk::update(guard = "authenticated")]
+fn share_ledger_approve(
+    owner: Principal,
+    args: shares::ApproveArgs,
+) -> Result<u128, shares::ApproveError> {
+    let token_id = share_ledgers::forwarded_token(&get_caller(), &owner).map_err(|message| {
+        shares::ApproveError::GenericError {
+            error_code: share_ledgers::REJECTED_FORWARD,
+            message,
+        }
+    })?;
+    shares::approve(owner, &token_id, args)
+}
+
+/// `icrc2_transfer_from` of a share ledger on behalf of `spender`. Registered share ledgers only.
+#[ic_cdk::update(guard = "authenticated")]
+fn share_ledger_transfer_from(
+    spender: Principal,
+    args: TransferFromArgs,
+) -> Result<u128, TransferFromError> {
+    let token_id = share_ledgers::forwarded_token(&get_caller(), &spender).map_err(|message| {
+        TransferFromError::GenericError {
+            error_code: share_ledgers::REJECTED_FORWARD,
+            message,
+        }
+    })?;
+    shares::transfer_from(spender, &token_id, args)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc3_get_blocks(args: Vec<icrc3::GetBlocksArgs>) -> icrc3::GetBlocksResult {
+    icrc3::get_blocks(args)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc3_get_tip_certificate() -> Option<icrc3::DataCertificate> {
+    certification::tip_certificate()
+}
+
+// The share block log is never archived
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc3_get_archives(_args: icrc3::GetArchivesArgs) -> Vec<icrc3::ArchiveInfo> {
+    vec![]
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc3_supported_block_types() -> Vec<icrc3::BlockType> {
+    icrc3::supported_block_types()
+}
+
+// Share tokens held by the caller's default account, with balances
+#[ic_cdk::query(guard = "authenticated")]
+fn get_my_shares() -> ApiResponse<Vec<(String, u128)>> {
+    let prefix = format!("|{}", get_caller().to_text());
+    let holdings = SHARES_BALANCES.with(|b| {
+        b.borrow()
+            .iter()
+            .filter_map(|(key, balance)| {
+                key.strip_suffix(&prefix)
+                    .map(|token_id| (token_id.to_string(), balance))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(holdings)
+}
+
+// -----------------------------
+// Batch NFT collection (ICRC-7, see nft.rs)
+// -----------------------------
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_collection_metadata() -> Vec<(String, icrc3::Icrc3Value)> {
+    nft::collection_metadata()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_symbol() -> String {
+    nft::SYMBOL.to_string()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_name() -> String {
+    nft::NAME.to_string()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_description() -> Option<String> {
+    Some(nft::DESCRIPTION.to_string())
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_total_supply() -> Nat {
+    nft::total_supply()
+}
+
+// Every offer mints a batch, so the collection has no cap
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_QUERY_BATCH_SIZE))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_UPDATE_BATCH_SIZE))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(nft::DEFAULT_TAKE_VALUE))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_TAKE_VALUE))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(nft::MAX_MEMO_SIZE))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(Nat::from(icrc3::TX_WINDOW_NANOS))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(Nat::from(icrc3::PERMITTED_DRIFT_NANOS))
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, icrc3::Icrc3Value)>>> {
+    nft::check_query_batch(token_ids.len());
+    token_ids.iter().map(nft::token_metadata).collect()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    nft::check_query_batch(token_ids.len());
+    token_ids.iter().map(nft::owner_of).collect()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    nft::check_query_batch(accounts.len());
+    accounts.iter().map(nft::balance_of).collect()
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    nft::tokens(prev, take)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    nft::tokens_of(&account, prev, take)
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn icrc7_transfer(
+    args: Vec<nft::Icrc7TransferArg>,
+) -> Vec<Option<Result<Nat, nft::Icrc7TransferError>>> {
+    nft::transfer(get_caller(), args)
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn icrc10_supported_standards() -> Vec<shares::SupportedStandard> {
+    nft::supported_standards()
+}
+
+// -----------------------------
+// Batch provenance (see provenance.rs)
+// -----------------------------
+
+#[ic_cdk::update(guard = "authenticated")]
+fn record_provenance_event(request: RecordProvenanceRequest) -> ApiResponse<ProvenanceEvent> {
+    match provenance::record(get_caller(), request) {
+        Ok(event) => ApiResponse::success(event),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_batch_provenance(batch_id: String) -> ApiResponse<Vec<ProvenanceEvent>> {
+    match provenance::trace(get_caller(), &batch_id) {
+        Ok(events) => ApiResponse::success(events),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+// Batches the caller owns, farms or holds shares in
+#[ic_cdk::query(guard = "authenticated")]
+fn get_traceable_batches() -> ApiResponse<Vec<String>> {
+    ApiResponse::success(provenance::traceable_batches(get_caller()))
+}
+
+// -----------------------------
+// Quality certification (see inspection.rs)
+// -----------------------------
+
+#[ic_cdk::update(guard = "authenticated")]
+fn issue_quality_certificate(request: IssueCertificateRequest) -> ApiResponse<QualityCertificate> {
+    let caller = get_caller();
+    if let Err(e) = permissions::authorize(&caller, Operation::IssueCertificate, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    match inspection::issue(caller, request) {
+        Ok(certificate) => ApiResponse::success(certificate),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn revoke_quality_certificate(certificate_id: String, reason: String) -> ApiResponse<QualityCertificate> {
+    match inspection::revoke(get_caller(), &certificate_id, reason) {
+        Ok(certificate) => ApiResponse::success(certificate),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+// Certificates of an offer or batch, including expired and revoked ones
+#[ic_cdk::query(guard = "not_banned")]
+fn get_quality_certificates(subject: CertificationSubject) -> ApiResponse<Vec<QualityCertificate>> {
+    ApiResponse::success(inspection::for_subject(&subject))
+}
+
+// -----------------------------
+// Public batch verification (see verification.rs)
+// -----------------------------
+
+// Verification record of a batch, for display. Clients that must not trust the boundary node
+// use `verify_batch` instead.
+#[ic_cdk::query(guard = "not_banned")]
+fn get_batch_verification(batch_id: String) -> ApiResponse<verification::BatchVerification> {
+    match verification::build(&batch_id) {
+        Some(record) => ApiResponse::success(record),
+        None => ApiResponse::error("Batch not found".to_string()),
+    }
+}
+
+// Certified verification record of a batch: check `hash_tree` against `certificate`, then the
+// SHA-256 of `verification` against the leaf at ["batches", batch_id] before decoding it
+#[ic_cdk::query(guard = "not_banned")]
+fn verify_batch(batch_id: String) -> ApiResponse<verification::CertifiedBatchVerification> {
+    match verification::certified(&batch_id) {
+        Ok(certified) => ApiResponse::success(certified),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+// -----------------------------
+// HTTP API (see http.rs)
+// -----------------------------
+
+// GET /offers, /offers/{id}, /batches/{id} and /stats as JSON. Batches are answered with a
+// certificate; other routes are upgraded to `http_request_update`.
+#[ic_cdk::query(guard = "not_banned")]
+fn http_request(request: http::HttpRequest) -> http::HttpResponse {
+    http::serve_query(request)
+}
+
+#[ic_cdk::update(guard = "not_banned")]
+fn http_request_update(request: http::HttpRequest) -> http::HttpResponse {
+    http::serve_update(request)
+}
+
+// -----------------------------
+// Admin functions
+// -----------------------------
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_all_users() -> ApiResponse<Vec<UserProfile>> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ViewAllUsers, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    let users = USERS.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .map(|(_, user)| user.clone())
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(users)
+}
+
+/// The permission matrix: which roles and resource owners may perform each operation.
+#[ic_cdk::query(guard = "authenticated")]
+fn get_permissions() -> ApiResponse<Vec<permissions::Permission>> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ViewPermissions, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    ApiResponse::success(permissions::matrix())
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn get_canister_config() -> ApiResponse<CanisterConfig> {
+    ApiResponse::success(get_config())
+}
+
+#[ic_cdk::update(guard = "authenticated")]
+fn set_ledger_canister(ledger_canister: Principal) -> ApiResponse<CanisterConfig> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ConfigureCanister, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    let mut config = get_config();
+    config.ledger_canister = ledger_canister;
+    CONFIG.with(|c| c.borrow_mut().set(config.clone()))
+        .expect("failed to persist config");
+
+    ApiResponse::success(config)
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_treasury_state() -> ApiResponse<TreasuryState> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ViewTreasury, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    ApiResponse::success(TREASURY.with(|t| t.borrow().get().clone()))
+}
+
+// Upper bound on the platform commission (10%), leaving the farmer the bulk of every escrow
+const MAX_PLATFORM_FEE_BPS: u16 = 1_000;
+
+#[ic_cdk::update(guard = "authenticated")]
+fn set_platform_fee(platform_fee_bps: u16) -> ApiResponse<TreasuryState> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ManageTreasury, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    if platform_fee_bps > MAX_PLATFORM_FEE_BPS {
+        return ApiResponse::error(format!(
+            "Platform fee cannot exceed {} basis points",
+            MAX_PLATFORM_FEE_BPS
+        ));
+    }
+
+    let mut treasury = TREASURY.with(|t| t.borrow().get().clone());
+    treasury.platform_fee_bps = platform_fee_bps;
+    TREASURY.with(|t| t.borrow_mut().set(treasury.clone()))
+        .expect("failed to persist treasury");
+
+    ApiResponse::success(treasury)
+}
+
+/// Platform fees collected in the half-open period [from, to), timestamps in nanoseconds.
+#[ic_cdk::query(guard = "authenticated")]
+fn get_fees_collected(from: u64, to: u64) -> ApiResponse<FeeSummary> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ViewTreasury, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    let records = FEES.with(|f| {
+        f.borrow()
+            .iter()
+            .filter(|(_, record)| record.collected_at >= from && record.collected_at < to)
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(summarize_fees(records))
+}
+
+#[ic_cdk::query(guard = "authenticated")]
+fn get_offer_fees(offer_id: String) -> ApiResponse<FeeSummary> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ViewTreasury, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    let records = FEES.with(|f| {
+        f.borrow()
+            .iter()
+            .filter(|(_, record)| record.offer_id == offer_id)
+            .map(|(_, record)| record)
+            .collect::<Vec<_>>()
+    });
+
+    ApiResponse::success(summarize_fees(records))
+}
+
+fn summarize_fees(records: Vec<FeeRecord>) -> FeeSummary {
+    let mut total_collected = Vec::new();
+    for record in &records {
+        add_token_amount(
+            &mut total_collected,
+            &record.token_symbol,
+            record.gross_amount - record.ledger_fee,
+        );
+    }
+    FeeSummary {
+        total_collected,
+        records,
+    }
+}
+
+/// Moves funds of one token out of the treasury subaccount. `amount` is what `to` receives;
+/// the ledger fee is paid on top. Returns the ledger block index.
+#[ic_cdk::update(guard = "authenticated")]
+async fn withdraw_from_treasury(token_symbol: String, to: Account, amount: u128) -> ApiResponse<u128> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ManageTreasury, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    let payment_token = match find_payment_token(&token_symbol) {
+        Some(token) => token,
+        None => return ApiResponse::error(format!("Payment token {} is not supported", token_symbol)),
+    };
+    let ledger = payment_token.ledger_canister;
+    let fee = match ledger::fee(ledger).await {
+        Ok(fee) => fee,
+        Err(e) => return ApiResponse::error(e),
+    };
+
+    // Reserve the withdrawal before the transfer so concurrent calls cannot overdraw
+    let total = match amount.checked_add(fee) {
+        Some(total) => total,
+        None => return ApiResponse::error("Amount is out of range".to_string()),
+    };
+    if treasury_balance(&token_symbol) < total {
+        return ApiResponse::error("Insufficient treasury balance".to_string());
+    }
+    adjust_treasury_withdrawn(&token_symbol, total as i128);
+
+    let arg = TransferArg {
+        from_subaccount: treasury_account().subaccount,
+        to,
+        amount,
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+    match ledger::transfer(ledger, arg).await {
+        Ok(block_index) => ApiResponse::success(block_index),
+        Err(e) => {
+            adjust_treasury_withdrawn(&token_symbol, -(total as i128));
+            ApiResponse::error(e)
+        }
+    }
+}
+
+fn adjust_treasury_withdrawn(token_symbol: &str, delta: i128) {
+    TREASURY_WITHDRAWN.with(|w| {
+        let mut withdrawn = w.borrow_mut();
+        let current = withdrawn.get(&token_symbol.to_string()).unwrap_or(0) as i128;
+        withdrawn.insert(token_symbol.to_string(), (current + delta) as u128);
+    });
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn get_payment_tokens() -> ApiResponse<Vec<PaymentToken>> {
+    let mut tokens = vec![default_payment_token()];
+    PAYMENT_TOKENS.with(|t| tokens.extend(t.borrow().iter().map(|(_, token)| token)));
+    ApiResponse::success(tokens)
+}
+
+/// Allowlists an ICRC-1 ledger as a payment token. Decimals are read from the ledger itself.
+#[ic_cdk::update(guard = "authenticated")]
+async fn add_payment_token(symbol: String, ledger_canister: Principal) -> ApiResponse<PaymentToken> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ManagePaymentTokens, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    if symbol.is_empty() || symbol == DEFAULT_TOKEN_SYMBOL {
+        return ApiResponse::error(format!(
+            "Invalid symbol - {} is configured with set_ledger_canister",
+            DEFAULT_TOKEN_SYMBOL
+        ));
+    }
+
+    let decimals = match ledger::decimals(ledger_canister).await {
+        Ok(decimals) => decimals,
+        Err(e) => return ApiResponse::error(e),
+    };
+
+    let token = PaymentToken {
+        symbol: symbol.clone(),
+        ledger_canister,
+        decimals,
+    };
+    PAYMENT_TOKENS.with(|t| t.borrow_mut().insert(symbol, token.clone()));
+
+    ApiResponse::success(token)
+}
+
+/// Removes a token from the allowlist. Existing offers keep the token they were created with.
+#[ic_cdk::update(guard = "authenticated")]
+fn remove_payment_token(symbol: String) -> ApiResponse<PaymentToken> {
+    if let Err(e) = permissions::authorize(&get_caller(), Operation::ManagePaymentTokens, &[]) {
+        return ApiResponse::error(e);
+    }
+
+    match PAYMENT_TOKENS.with(|t| t.borrow_mut().remove(&symbol)) {
+        Some(token) => ApiResponse::success(token),
+        None => ApiResponse::error("Payment token not found".to_string()),
+    }
+}
+
+#[ic_cdk::query(guard = "not_banned")]
+fn get_platform_stats() -> ApiResponse<PlatformStats> {
+    ApiResponse::success(platform_stats())
+}
+
+// Also served at GET /stats (see http.rs)
+fn platform_stats() -> PlatformStats {
+    let fee_revenue = fee_revenue();
+    let treasury_balance = fee_revenue
+        .iter()
+        .map(|revenue| TokenAmount {
+            token_symbol: revenue.token_symbol.clone(),
+            amount: treasury_balance(&revenue.token_symbol),
+        })
+        .collect();
+    PlatformStats {
+        total_users: USERS.with(|users| users.borrow().len()),
+        total_offers: OFFERS.with(|offers| offers.borrow().len()),
+        total_requests: REQUESTS.with(|requests| requests.borrow().len()),
+        total_transactions: TRANSACTIONS.with(|transactions| transactions.borrow().len()),
+        active_offers: OFFERS.with(|offers| {
+            offers
+                .borrow()
+                .iter()
+                .filter(|(_, offer)| matches!(offer.status, OfferStatus::Active))
+                .count() as u64
+        }),
+        treasury_balance,
+        fee_revenue,
+    }
 }
 
 // Health check
-#[ic_cdk::query]
+#[ic_cdk::query(guard = "not_banned")]
 fn health_check() -> String {
     "HarvestX backend is healthy".to_string()
 }
@@ -0,0 +1,833 @@
+use candid::{CandidType, Decode, Deserialize, Principal};
+use ic_stable_structures::{memory_manager::MemoryId, storable::Bound, StableBTreeMap, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::marker::PhantomData;
+
+use crate::icrc3::{self, Icrc3Value, ShareTx};
+use crate::ledger::Account;
+use crate::nft;
+use crate::shares::{account_of, balance_key, token_id};
+use crate::types::*;
+use crate::{
+    default_payment_token, get_current_time, new_escrow_record, offer_payment_token, BatchNFT, Memory, BATCHES, CONFIG, ESCROWS, FEES, MEMORY_MANAGER,
+    MIGRATIONS_APPLIED, OFFERS, OFFERS_MEMORY_ID, PAYMENT_TOKENS, REFUNDS, REQUESTS,
+    REQUESTS_MEMORY_ID, SHARES_ALLOWANCES, SHARES_BALANCES, SHARES_TOTAL, SHARE_BLOCKS, TRANSACTIONS, TRANSACTIONS_MEMORY_ID,
+    TREASURY, USERS,
+};
+
+// Progress goes to the replica log; unit tests run natively, without one
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        let message = format!($($arg)*);
+        #[cfg(not(test))]
+        ic_cdk::println!("{}", message);
+        #[cfg(test)]
+        let _ = message;
+    }};
+}
+
+// -----------------------------
+// Stable-memory migrations
+// -----------------------------
+// Applied in order from `post_upgrade`; the number of applied entries is kept in stable memory.
+// Append new migrations at the end and never reorder or remove entries. Migrations must be
+// idempotent: canisters upgraded before this runner existed replay the first entries.
+const MIGRATIONS: &[(&str, fn())] = &[
+    ("fixed-point amounts", migrate_money_fields),
+    ("escrow records", backfill_escrow_records),
+    ("versioned envelopes", wrap_records_in_envelopes),
+    ("share ledger recovery", rebuild_share_ledger),
+    ("share block log", seed_share_block_log),
+    ("batch NFT mint blocks", record_batch_mints),
+];
+
+pub fn run_migrations() {
+    let applied = MIGRATIONS_APPLIED.with(|m| *m.borrow().get()) as usize;
+    for (index, (name, migrate)) in MIGRATIONS.iter().enumerate().skip(applied) {
+        migrate();
+        MIGRATIONS_APPLIED
+            .with(|m| m.borrow_mut().set(index as u64 + 1))
+            .expect("failed to record migration");
+        report!("Applied storage migration {}: {}", index + 1, name);
+    }
+}
+
+// Fresh canisters start with every migration applied
+pub fn mark_all_applied() {
+    MIGRATIONS_APPLIED
+        .with(|m| m.borrow_mut().set(MIGRATIONS.len() as u64))
+        .expect("failed to record migrations");
+}
+
+// Migrations that reshape records open the maps over undecoded bytes, before the typed maps in
+// lib.rs are first touched, so that records in an old layout can be read and rewritten.
+
+// Undecoded value of a map whose values are `T`; shares `T`'s bound so the map loads unchanged
+struct RawRecord<T> {
+    bytes: Vec<u8>,
+    _type: PhantomData<T>,
+}
+
+impl<T: Storable> Storable for RawRecord<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            _type: PhantomData,
+        }
+    }
+
+    const BOUND: Bound = T::BOUND;
+}
+
+// Rewrites every value of the map in `memory_id` that no longer decodes as `T` but decodes as
+// `Legacy`. Returns the number of records converted.
+fn migrate_map<T, Legacy>(memory_id: MemoryId, convert: impl Fn(Legacy) -> T) -> u64
+where
+    T: Storable + Versioned,
+    Legacy: CandidType + for<'de> Deserialize<'de>,
+{
+    let mut map: StableBTreeMap<String, RawRecord<T>, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(memory_id)));
+
+    let converted: Vec<(String, T)> = map
+        .iter()
+        .filter(|(_, raw)| decode_record::<T>(&raw.bytes).is_err())
+        .map(|(key, raw)| {
+            let legacy = Decode!(&raw.bytes, Legacy)
+                .unwrap_or_else(|e| ic_cdk::trap(&format!("Undecodable record {}: {}", key, e)));
+            (key, convert(legacy))
+        })
+        .collect();
+
+    let count = converted.len() as u64;
+    for (key, value) in converted {
+        map.insert(
+            key,
+            RawRecord {
+                bytes: encode_record(&value),
+                _type: PhantomData,
+            },
+        );
+    }
+    count
+}
+
+// Decimal amounts stored as f64 before `Amount`. Invalid values (negative, NaN) become zero.
+fn legacy_amount(value: f64, decimals: u8) -> Amount {
+    Amount::from_legacy_f64(value, decimals).unwrap_or(Amount::ZERO)
+}
+
+// Totals are recomputed from the converted unit price so they stay exactly quantity x price
+fn legacy_total(price: Amount, quantity: u64, total: f64, decimals: u8) -> Amount {
+    price
+        .checked_mul(quantity)
+        .unwrap_or_else(|| legacy_amount(total, decimals))
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct InvestmentOfferV0 {
+    id: String,
+    farmer: Principal,
+    product_name: String,
+    product_type: ProductType,
+    total_quantity: u64,
+    available_quantity: u64,
+    price_per_kg: f64,
+    description: String,
+    harvest_date: String,
+    location: String,
+    quality_grade: QualityGrade,
+    minimum_investment: u64,
+    status: OfferStatus,
+    created_at: u64,
+    updated_at: u64,
+    payment_token: Option<PaymentToken>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct InvestmentRequestV0 {
+    id: String,
+    offer_id: String,
+    investor: Principal,
+    requested_quantity: u64,
+    offered_price_per_kg: f64,
+    total_offered: f64,
+    message: String,
+    status: RequestStatus,
+    created_at: u64,
+    updated_at: u64,
+    expires_at: u64,
+    payment_method: Option<PaymentMethod>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+struct TransactionV0 {
+    id: String,
+    offer_id: String,
+    request_id: String,
+    farmer: Principal,
+    investor: Principal,
+    quantity: u64,
+    price_per_kg: f64,
+    total_amount: f64,
+    status: TransactionStatus,
+    created_at: u64,
+    updated_at: u64,
+    tokenized_at: Option<u64>,
+    delivered_at: Option<u64>,
+    released_at: Option<u64>,
+    release_block_index: Option<u128>,
+}
+
+/// Converts offers, requests and transactions that still hold f64 prices and totals into
+/// `Amount`s in base units of the offer's payment token (ICP for offers that predate
+/// multi-token support). Records already in the current layout are left untouched.
+fn migrate_money_fields() {
+    let offers = migrate_map(OFFERS_MEMORY_ID, |o: InvestmentOfferV0| {
+        let decimals = o
+            .payment_token
+            .as_ref()
+            .map(|t| t.decimals)
+            .unwrap_or_else(|| default_payment_token().decimals);
+        InvestmentOffer {
+            price_per_kg: legacy_amount(o.price_per_kg, decimals),
+            id: o.id,
+            farmer: o.farmer,
+            product_name: o.product_name,
+            product_type: o.product_type,
+            total_quantity: o.total_quantity,
+            available_quantity: o.available_quantity,
+            description: o.description,
+            harvest_date: o.harvest_date,
+            location: o.location,
+            quality_grade: o.quality_grade,
+            minimum_investment: o.minimum_investment,
+            status: o.status,
+            created_at: o.created_at,
+            updated_at: o.updated_at,
+            payment_token: o.payment_token,
+            verified_grade: None,
+            verified_investors_only: None,
+        }
+    });
+
+    // Requests and transactions are priced in their offer's token
+    let offer_decimals: BTreeMap<String, u8> = {
+        let map: StableBTreeMap<String, InvestmentOffer, Memory> =
+            StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(OFFERS_MEMORY_ID)));
+        map.iter()
+            .map(|(id, offer)| (id, offer_payment_token(&offer).decimals))
+            .collect()
+    };
+    let decimals_of = |offer_id: &str| -> u8 {
+        offer_decimals
+            .get(offer_id)
+            .copied()
+            .unwrap_or_else(|| default_payment_token().decimals)
+    };
+
+    let requests = migrate_map(REQUESTS_MEMORY_ID, |r: InvestmentRequestV0| {
+        let decimals = decimals_of(&r.offer_id);
+        let price = legacy_amount(r.offered_price_per_kg, decimals);
+        InvestmentRequest {
+            total_offered: legacy_total(price, r.requested_quantity, r.total_offered, decimals),
+            offered_price_per_kg: price,
+            id: r.id,
+            offer_id: r.offer_id,
+            investor: r.investor,
+            requested_quantity: r.requested_quantity,
+            message: r.message,
+            status: r.status,
+            created_at: r.created_at,
+            updated_at: r.updated_at,
+            expires_at: r.expires_at,
+            payment_method: r.payment_method,
+            payment_pull: None,
+        }
+    });
+
+    let transactions = migrate_map(TRANSACTIONS_MEMORY_ID, |t: TransactionV0| {
+        let decimals = decimals_of(&t.offer_id);
+        let price = legacy_amount(t.price_per_kg, decimals);
+        Transaction {
+            total_amount: legacy_total(price, t.quantity, t.total_amount, decimals),
+            price_per_kg: price,
+            id: t.id,
+            offer_id: t.offer_id,
+            request_id: t.request_id,
+            farmer: t.farmer,
+            investor: t.investor,
+            quantity: t.quantity,
+            status: t.status,
+            created_at: t.created_at,
+            updated_at: t.updated_at,
+            tokenized_at: t.tokenized_at,
+            delivered_at: t.delivered_at,
+            released_at: t.released_at,
+            release_block_index: t.release_block_index,
+            release_plan: None,
+        }
+    });
+
+    if offers + requests + transactions > 0 {
+        report!(
+            "Migrated f64 amounts: {} offers, {} requests, {} transactions",
+            offers,
+            requests,
+            transactions
+        );
+    }
+}
+
+/// Creates escrow records for requests made before `ESCROWS` existed, rebuilding their history from
+/// transactions, platform fees and refunds. Subaccount deposits were only ever observed as a balance,
+/// so they are recorded as the expected amount without a block index.
+fn backfill_escrow_records() {
+    let transactions: BTreeMap<String, Transaction> = TRANSACTIONS.with(|t| {
+        t.borrow()
+            .iter()
+            .map(|(_, txn)| (txn.request_id.clone(), txn))
+            .collect()
+    });
+    let missing: Vec<InvestmentRequest> = REQUESTS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter(|(id, _)| !ESCROWS.with(|e| e.borrow().contains_key(id)))
+            .map(|(_, req)| req)
+            .collect()
+    });
+
+    let mut count = 0u64;
+    for req in missing {
+        let offer = match OFFERS.with(|o| o.borrow().get(&req.offer_id)) {
+            Some(offer) => offer,
+            None => continue,
+        };
+        let mut record = new_escrow_record(&req, &offer);
+        let expected = record.expected_amount.base_units();
+
+        let funded = transactions
+            .get(&req.id)
+            .filter(|txn| !matches!(txn.status, TransactionStatus::Confirmed));
+        if let Some(txn) = funded {
+            record.state = EscrowState::Funded;
+            record.entries.push(EscrowEntry {
+                kind: EscrowEntryKind::Deposit,
+                amount: expected,
+                ledger_fee: 0,
+                block_index: None,
+                recorded_at: txn.tokenized_at.unwrap_or(txn.updated_at),
+            });
+
+            let fee = FEES.with(|f| f.borrow().get(&txn.id));
+            if let Some(fee) = &fee {
+                record.entries.push(EscrowEntry {
+                    kind: EscrowEntryKind::Commission,
+                    amount: fee.gross_amount - fee.ledger_fee,
+                    ledger_fee: fee.ledger_fee,
+                    block_index: Some(fee.block_index),
+                    recorded_at: fee.collected_at,
+                });
+            }
+
+            if let (Some(released_at), Some(block_index)) = (txn.released_at, txn.release_block_index) {
+                // The payout amount was not stored; it is the escrow net of the commission and the
+                // ledger fee, which matched the commission transfer's fee when one was made
+                let ledger_fee = fee.as_ref().map(|f| f.ledger_fee).unwrap_or(0);
+                let commission = fee.as_ref().map(|f| f.gross_amount).unwrap_or(0);
+                record.state = EscrowState::Released;
+                record.entries.push(EscrowEntry {
+                    kind: EscrowEntryKind::Release,
+                    amount: expected.saturating_sub(commission).saturating_sub(ledger_fee),
+                    ledger_fee,
+                    block_index: Some(block_index),
+                    recorded_at: released_at,
+                });
+            }
+        }
+
+        match REFUNDS.with(|r| r.borrow().get(&req.id)) {
+            Some(refund) if matches!(refund.status, RefundStatus::Completed) => {
+                record.state = EscrowState::Refunded;
+                record.entries.push(EscrowEntry {
+                    kind: EscrowEntryKind::Refund,
+                    amount: refund.amount,
+                    ledger_fee: refund.fee,
+                    block_index: refund.block_index,
+                    recorded_at: refund.updated_at,
+                });
+            }
+            None if matches!(
+                req.status,
+                RequestStatus::Rejected | RequestStatus::Cancelled | RequestStatus::Expired
+            ) =>
+            {
+                record.state = EscrowState::Closed;
+            }
+            _ => {}
+        }
+
+        record.updated_at = get_current_time();
+        ESCROWS.with(|e| e.borrow_mut().insert(req.id.clone(), record));
+        count += 1;
+    }
+
+    if count > 0 {
+        report!("Backfilled {} escrow records", count);
+    }
+}
+
+// Re-inserts every entry so that it is written back through `Storable::to_bytes`
+fn rewrite_map<K, V>(map: &mut StableBTreeMap<K, V, Memory>)
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let entries: Vec<(K, V)> = map.iter().collect();
+    for (key, value) in entries {
+        map.insert(key, value);
+    }
+}
+
+/// Re-encodes every stored record so that it carries a versioned envelope.
+fn wrap_records_in_envelopes() {
+    USERS.with(|m| rewrite_map(&mut m.borrow_mut()));
+    OFFERS.with(|m| rewrite_map(&mut m.borrow_mut()));
+    REQUESTS.with(|m| rewrite_map(&mut m.borrow_mut()));
+    TRANSACTIONS.with(|m| rewrite_map(&mut m.borrow_mut()));
+    BATCHES.with(|m| rewrite_map(&mut m.borrow_mut()));
+    REFUNDS.with(|m| rewrite_map(&mut m.borrow_mut()));
+    FEES.with(|m| rewrite_map(&mut m.borrow_mut()));
+    PAYMENT_TOKENS.with(|m| rewrite_map(&mut m.borrow_mut()));
+    ESCROWS.with(|m| rewrite_map(&mut m.borrow_mut()));
+
+    CONFIG.with(|c| {
+        let config = c.borrow().get().clone();
+        c.borrow_mut().set(config).expect("failed to rewrite config");
+    });
+    TREASURY.with(|c| {
+        let treasury = c.borrow().get().clone();
+        c.borrow_mut().set(treasury).expect("failed to rewrite treasury state");
+    });
+}
+
+/// Rebuilds share totals and balances in their own memories. Both maps used to be opened on the
+/// same memory, so each overwrote the other's B-tree nodes and neither can be trusted. Shares only
+/// ever moved when a transaction was tokenized, so they follow from offers and transactions: every
+/// offer issues one share per kg to its farmer, and each tokenized transaction moves its quantity
+/// to the investor.
+fn rebuild_share_ledger() {
+    let mut totals: BTreeMap<String, u128> = BTreeMap::new();
+    let mut balances: BTreeMap<String, u128> = BTreeMap::new();
+
+    OFFERS.with(|o| {
+        for (_, offer) in o.borrow().iter() {
+            let token_id = token_id(&offer.id);
+            let total = offer.total_quantity as u128;
+            totals.insert(token_id.clone(), total);
+            balances.insert(balance_key(&token_id, &account_of(offer.farmer)), total);
+        }
+    });
+
+    TRANSACTIONS.with(|t| {
+        for (_, txn) in t.borrow().iter() {
+            if !matches!(txn.status, TransactionStatus::Tokenized | TransactionStatus::Completed) {
+                continue;
+            }
+            let token_id = token_id(&txn.offer_id);
+            if !totals.contains_key(&token_id) {
+                continue;
+            }
+            let shares = txn.quantity as u128;
+            let farmer = balances
+                .entry(balance_key(&token_id, &account_of(txn.farmer)))
+                .or_insert(0);
+            *farmer = farmer.saturating_sub(shares);
+            *balances
+                .entry(balance_key(&token_id, &account_of(txn.investor)))
+                .or_insert(0) += shares;
+        }
+    });
+
+    SHARES_TOTAL.with(|m| {
+        let mut map = m.borrow_mut();
+        map.clear_new();
+        for (token_id, total) in &totals {
+            map.insert(token_id.clone(), *total);
+        }
+    });
+    SHARES_BALANCES.with(|m| {
+        let mut map = m.borrow_mut();
+        map.clear_new();
+        for (key, balance) in &balances {
+            map.insert(key.clone(), *balance);
+        }
+    });
+
+    report!(
+        "Rebuilt share ledger: {} tokens, {} balances",
+        totals.len(),
+        balances.len()
+    );
+}
+
+// "principal" or "principal.subaccount_hex", as written by the share ledger
+fn parse_account(text: &str) -> Option<Account> {
+    let (owner, subaccount) = match text.split_once('.') {
+        Some((owner, sub_hex)) => (owner, Some(hex::decode(sub_hex).ok()?.try_into().ok()?)),
+        None => (text, None),
+    };
+    Some(Account {
+        owner: Principal::from_text(owner).ok()?,
+        subaccount,
+    })
+}
+
+/// Starts the share block log of canisters that moved shares before it existed. Earlier operations
+/// were not recorded, so the log opens with a snapshot: one mint per current balance and one
+/// approval per live allowance, each with memo "snapshot".
+fn seed_share_block_log() {
+    if icrc3::log_length() > 0 {
+        return;
+    }
+    let memo = b"snapshot".to_vec();
+
+    let balances: Vec<(String, u128)> = SHARES_BALANCES.with(|b| b.borrow().iter().collect());
+    for (key, balance) in &balances {
+        let mut parts = key.splitn(3, '|');
+        let (token_id, owner) = match (parts.next(), parts.next()) {
+            (Some(token_id), Some(owner)) => (token_id, owner),
+            _ => continue,
+        };
+        let account = match parts.next() {
+            Some(sub_hex) => parse_account(&format!("{}.{}", owner, sub_hex)),
+            None => parse_account(owner),
+        };
+        if let Some(account) = account {
+            icrc3::append(ShareTx {
+                to: Some(&account),
+                memo: Some(&memo),
+                ..ShareTx::new("1mint", token_id, *balance)
+            });
+        }
+    }
+
+    let allowances: Vec<(String, ShareAllowance)> =
+        SHARES_ALLOWANCES.with(|a| a.borrow().iter().collect());
+    for (key, allowance) in &allowances {
+        let parts: Vec<&str> = key.split('|').collect();
+        let (owner, spender) = match parts.as_slice() {
+            [_, owner, spender] => (parse_account(owner), parse_account(spender)),
+            _ => continue,
+        };
+        if let (Some(owner), Some(spender)) = (owner, spender) {
+            icrc3::append(ShareTx {
+                from: Some(&owner),
+                spender: Some(&spender),
+                memo: Some(&memo),
+                expires_at: allowance.expires_at,
+                ..ShareTx::new("2approve", parts[0], allowance.allowance)
+            });
+        }
+    }
+
+    report!("Seeded share block log with {} blocks", icrc3::log_length());
+}
+
+// Token id of a "7mint" block
+fn minted_token_id(block: &Icrc3Value) -> Option<candid::Nat> {
+    let Icrc3Value::Map(fields) = block else {
+        return None;
+    };
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, v)| v);
+    if field("btype") != Some(&Icrc3Value::Text("7mint".to_string())) {
+        return None;
+    }
+    match field("tx")? {
+        Icrc3Value::Map(tx) => tx.iter().find_map(|(key, value)| match value {
+            Icrc3Value::Nat(tid) if key == "tid" => Some(tid.clone()),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// Records a "7mint" block, memo "snapshot", for every batch minted before the ICRC-7 collection
+/// was logged. Batches that already have one are skipped.
+fn record_batch_mints() {
+    let minted: BTreeSet<candid::Nat> = SHARE_BLOCKS.with(|b| {
+        b.borrow()
+            .iter()
+            .filter_map(|block| minted_token_id(&block))
+            .collect()
+    });
+    let mut batches: Vec<BatchNFT> =
+        BATCHES.with(|b| b.borrow().iter().map(|(_, batch)| batch).collect());
+    batches.sort_by_key(|batch| batch.minted_at);
+
+    let mut recorded = 0;
+    for batch in &batches {
+        if nft::token_id_of(&batch.id).is_some_and(|tid| !minted.contains(&tid)) {
+            nft::record_mint(batch, Some(b"snapshot"));
+            recorded += 1;
+        }
+    }
+    report!("Recorded {} batch mint blocks", recorded);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Encode;
+
+    use crate::{BatchMetadata, ESCROWS, USERS_MEMORY_ID};
+
+    const OFFER_ID: &str = "offer_1700";
+    const REQUEST_ID: &str = "req_1701";
+    const TRANSACTION_ID: &str = "txn_1702";
+
+    fn farmer() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    fn investor() -> Principal {
+        Principal::from_slice(&[2; 29])
+    }
+
+    // User profiles before schema versions: a single role
+    #[derive(CandidType, Deserialize)]
+    struct UserProfileV0 {
+        principal: Principal,
+        role: UserRole,
+        display_name: String,
+        email: String,
+        created_at: u64,
+        updated_at: u64,
+    }
+
+    fn raw_map<K, T>(memory_id: MemoryId) -> StableBTreeMap<K, RawRecord<T>, Memory>
+    where
+        K: Storable + Ord + Clone,
+        T: Storable,
+    {
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(memory_id)))
+    }
+
+    fn raw_record<T, V: CandidType>(value: &V) -> RawRecord<T> {
+        RawRecord {
+            bytes: Encode!(value).unwrap(),
+            _type: PhantomData,
+        }
+    }
+
+    // Stable memory as left by a release that stored f64 amounts and records without envelopes:
+    // an offer priced at 0.1 ICP per kg, a tokenized sale of 100 kg and the offer's batch
+    fn write_legacy_records() {
+        let offer = InvestmentOfferV0 {
+            id: OFFER_ID.to_string(),
+            farmer: farmer(),
+            product_name: "Wheat".to_string(),
+            product_type: ProductType::Grains,
+            total_quantity: 1_000,
+            available_quantity: 900,
+            price_per_kg: 0.1,
+            description: String::new(),
+            harvest_date: "2024-06-01".to_string(),
+            location: "Nile Delta".to_string(),
+            quality_grade: QualityGrade::Premium,
+            minimum_investment: 10,
+            status: OfferStatus::Active,
+            created_at: 1,
+            updated_at: 1,
+            payment_token: None,
+        };
+        raw_map::<String, InvestmentOffer>(OFFERS_MEMORY_ID)
+            .insert(OFFER_ID.to_string(), raw_record(&offer));
+
+        let request = InvestmentRequestV0 {
+            id: REQUEST_ID.to_string(),
+            offer_id: OFFER_ID.to_string(),
+            investor: investor(),
+            requested_quantity: 100,
+            offered_price_per_kg: 0.1,
+            // 100 x 0.1 in f64
+            total_offered: 10.000000000000002,
+            message: String::new(),
+            status: RequestStatus::Accepted,
+            created_at: 2,
+            updated_at: 2,
+            expires_at: 3,
+            payment_method: None,
+        };
+        raw_map::<String, InvestmentRequest>(REQUESTS_MEMORY_ID)
+            .insert(REQUEST_ID.to_string(), raw_record(&request));
+
+        let transaction = TransactionV0 {
+            id: TRANSACTION_ID.to_string(),
+            offer_id: OFFER_ID.to_string(),
+            request_id: REQUEST_ID.to_string(),
+            farmer: farmer(),
+            investor: investor(),
+            quantity: 100,
+            price_per_kg: 0.1,
+            total_amount: 10.000000000000002,
+            status: TransactionStatus::Tokenized,
+            created_at: 4,
+            updated_at: 5,
+            tokenized_at: Some(5),
+            delivered_at: None,
+            released_at: None,
+            release_block_index: None,
+        };
+        raw_map::<String, Transaction>(TRANSACTIONS_MEMORY_ID)
+            .insert(TRANSACTION_ID.to_string(), raw_record(&transaction));
+
+        let profile = UserProfileV0 {
+            principal: investor(),
+            role: UserRole::Investor,
+            display_name: "Investor".to_string(),
+            email: "investor@harvestx.local".to_string(),
+            created_at: 1,
+            updated_at: 1,
+        };
+        raw_map::<Principal, UserProfile>(USERS_MEMORY_ID).insert(investor(), raw_record(&profile));
+
+        let batch = BatchNFT {
+            id: format!("batch_{}", OFFER_ID),
+            owner: farmer(),
+            metadata: BatchMetadata {
+                product_name: "Wheat".to_string(),
+                product_type: ProductType::Grains,
+                quality_grade: QualityGrade::Premium,
+                location: "Nile Delta".to_string(),
+                harvest_date: "2024-06-01".to_string(),
+                total_quantity: 1_000,
+                additional: None,
+            },
+            minted_at: 1,
+        };
+        raw_map::<String, BatchNFT>(crate::BATCHES_MEMORY_ID)
+            .insert(batch.id.clone(), raw_record(&batch));
+    }
+
+    fn shares_of(owner: Principal) -> u128 {
+        let key = balance_key(&token_id(OFFER_ID), &account_of(owner));
+        SHARES_BALANCES.with(|b| b.borrow().get(&key)).unwrap_or(0)
+    }
+
+    // Everything the migrations write, for comparing two runs
+    fn snapshot() -> String {
+        format!(
+            "{:?}",
+            (
+                OFFERS.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                REQUESTS.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                TRANSACTIONS.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                USERS.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                BATCHES.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                ESCROWS.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                SHARES_TOTAL.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                SHARES_BALANCES.with(|m| m.borrow().iter().collect::<Vec<_>>()),
+                icrc3::tip(),
+            )
+        )
+    }
+
+    #[test]
+    fn migrations_convert_legacy_records() {
+        write_legacy_records();
+        run_migrations();
+
+        assert_eq!(
+            MIGRATIONS_APPLIED.with(|m| *m.borrow().get()),
+            MIGRATIONS.len() as u64
+        );
+
+        // f64 amounts became base units of ICP, totals exactly quantity x price
+        let offer = OFFERS
+            .with(|m| m.borrow().get(&OFFER_ID.to_string()))
+            .unwrap();
+        assert_eq!(offer.price_per_kg, Amount::from_base_units(10_000_000));
+        assert_eq!(offer.available_quantity, 900);
+        let request = REQUESTS
+            .with(|m| m.borrow().get(&REQUEST_ID.to_string()))
+            .unwrap();
+        assert_eq!(
+            request.offered_price_per_kg,
+            Amount::from_base_units(10_000_000)
+        );
+        assert_eq!(
+            request.total_offered,
+            Amount::from_base_units(1_000_000_000)
+        );
+        let transaction = TRANSACTIONS
+            .with(|m| m.borrow().get(&TRANSACTION_ID.to_string()))
+            .unwrap();
+        assert_eq!(
+            transaction.total_amount,
+            Amount::from_base_units(1_000_000_000)
+        );
+        assert!(transaction.release_plan.is_none());
+
+        // The single-role profile gained a role list and an envelope
+        let profile = USERS.with(|m| m.borrow().get(&investor())).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Investor]);
+        assert_eq!(profile.kyc.status, KycStatus::Unverified);
+        let raw = raw_map::<Principal, UserProfile>(USERS_MEMORY_ID)
+            .get(&investor())
+            .unwrap();
+        assert_eq!(&raw.bytes[..3], b"HX\x03");
+        let raw = raw_map::<String, InvestmentOffer>(OFFERS_MEMORY_ID)
+            .get(&OFFER_ID.to_string())
+            .unwrap();
+        assert_eq!(&raw.bytes[..3], b"HX\x01");
+
+        // The tokenized sale's escrow was funded with the request's total
+        let escrow = ESCROWS
+            .with(|m| m.borrow().get(&REQUEST_ID.to_string()))
+            .unwrap();
+        assert_eq!(escrow.state, EscrowState::Funded);
+        assert_eq!(escrow.entries.len(), 1);
+        assert_eq!(escrow.entries[0].amount, 1_000_000_000);
+
+        // Shares follow the sale, and the block log opens with a snapshot and the batch mint
+        assert_eq!(
+            SHARES_TOTAL.with(|m| m.borrow().get(&token_id(OFFER_ID))),
+            Some(1_000)
+        );
+        assert_eq!(shares_of(farmer()), 900);
+        assert_eq!(shares_of(investor()), 100);
+        assert_eq!(icrc3::log_length(), 3);
+    }
+
+    #[test]
+    fn migrations_are_idempotent() {
+        write_legacy_records();
+        run_migrations();
+        let migrated = snapshot();
+
+        // Canisters upgraded before the runner existed replay every entry
+        MIGRATIONS_APPLIED.with(|m| m.borrow_mut().set(0)).unwrap();
+        run_migrations();
+        assert_eq!(snapshot(), migrated);
+
+        // Nothing is left to apply on the next upgrade
+        run_migrations();
+        assert_eq!(snapshot(), migrated);
+    }
+
+    #[test]
+    fn migrations_leave_records_in_the_current_layout_untouched() {
+        // A fresh canister (see `mark_all_applied`) replaying the list finds nothing to convert
+        run_migrations();
+        assert_eq!(OFFERS.with(|m| m.borrow().len()), 0);
+        assert_eq!(ESCROWS.with(|m| m.borrow().len()), 0);
+        assert_eq!(icrc3::log_length(), 0);
+    }
+}
@@ -0,0 +1,376 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
+
+use crate::icrc3::{self, account_value, nat, Icrc3Value, TimeError};
+use crate::inspection;
+use crate::ledger::{Account, Subaccount};
+use crate::provenance;
+use crate::shares::{self, account_of, SupportedStandard};
+use crate::types::*;
+use crate::verification;
+use crate::{get_current_time, BatchNFT, BATCHES};
+
+// -----------------------------
+// Batch NFT collection (ICRC-7)
+// -----------------------------
+// Every offer mints one BatchNFT. The `icrc7_*` endpoints expose the BATCHES collection as an
+// ICRC-7 ledger: the token id of "batch_offer_<n>" is n, and its owner is the batch owner's
+// default account (batches are never held by subaccounts). Mints and transfers are recorded in
+// the ICRC-3 block log shared with the batch share tokens, as "7mint" and "7xfer" blocks.
+// Transferring a batch moves ownership of the batch record only, not the offer or its shares.
+
+pub const SYMBOL: &str = "HXB";
+pub const NAME: &str = "HarvestX Batches";
+pub const DESCRIPTION: &str =
+    "Harvest batches tokenized on HarvestX. Each token is one offer's batch of produce.";
+pub const MAX_QUERY_BATCH_SIZE: u16 = 100;
+pub const MAX_UPDATE_BATCH_SIZE: u16 = 10;
+pub const DEFAULT_TAKE_VALUE: u16 = 100;
+pub const MAX_TAKE_VALUE: u16 = 100;
+pub const MAX_MEMO_SIZE: u32 = 32;
+
+const BAD_MEMO: u128 = 1;
+const BATCH_TOO_LARGE: u128 = 2;
+
+// Dedup scope of collection transfers, alongside the share token ids
+const DEDUP_SCOPE: &str = "batches";
+
+/// ICRC-7 token id of a batch, parsed from its id "batch_offer_<n>".
+pub fn token_id_of(batch_id: &str) -> Option<Nat> {
+    batch_id
+        .strip_prefix("batch_offer_")
+        .and_then(|n| n.parse::<u64>().ok())
+        .map(Nat::from)
+}
+
+fn batch_id_of(token_id: &Nat) -> String {
+    format!("batch_offer_{}", token_id)
+}
+
+fn get_batch(token_id: &Nat) -> Option<BatchNFT> {
+    BATCHES.with(|b| b.borrow().get(&batch_id_of(token_id)))
+}
+
+fn is_default_subaccount(subaccount: &Option<Subaccount>) -> bool {
+    subaccount.map(|s| s == [0u8; 32]).unwrap_or(true)
+}
+
+// Token ids of the batches matching `owner`, in ascending order
+fn token_ids(owner: Option<Principal>) -> Vec<Nat> {
+    let mut ids: Vec<Nat> = BATCHES.with(|b| {
+        b.borrow()
+            .iter()
+            .filter(|(_, batch)| owner.map(|o| batch.owner == o).unwrap_or(true))
+            .filter_map(|(id, _)| token_id_of(&id))
+            .collect()
+    });
+    ids.sort();
+    ids
+}
+
+// ICRC-7 pagination: ids strictly after `prev`, at most `take` of them
+fn paginate(ids: Vec<Nat>, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let take = take
+        .and_then(|t| usize::try_from(t.0).ok())
+        .unwrap_or(DEFAULT_TAKE_VALUE as usize)
+        .min(MAX_TAKE_VALUE as usize);
+    ids.into_iter()
+        .filter(|id| prev.as_ref().map(|p| id > p).unwrap_or(true))
+        .take(take)
+        .collect()
+}
+
+/// Traps when a query asks for more entries than `icrc7_max_query_batch_size`.
+pub fn check_query_batch(len: usize) {
+    if len > MAX_QUERY_BATCH_SIZE as usize {
+        ic_cdk::trap(&format!(
+            "At most {} entries per query",
+            MAX_QUERY_BATCH_SIZE
+        ));
+    }
+}
+
+pub fn total_supply() -> Nat {
+    Nat::from(BATCHES.with(|b| b.borrow().len()))
+}
+
+pub fn owner_of(token_id: &Nat) -> Option<Account> {
+    get_batch(token_id).map(|batch| account_of(batch.owner))
+}
+
+pub fn balance_of(account: &Account) -> Nat {
+    if !is_default_subaccount(&account.subaccount) {
+        return Nat::from(0u8);
+    }
+    Nat::from(token_ids(Some(account.owner)).len())
+}
+
+pub fn tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    paginate(token_ids(None), prev, take)
+}
+
+pub fn tokens_of(account: &Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    if !is_default_subaccount(&account.subaccount) {
+        return vec![];
+    }
+    paginate(token_ids(Some(account.owner)), prev, take)
+}
+
+fn product_type_text(product_type: &ProductType) -> String {
+    match product_type {
+        ProductType::Other(other) => other.clone(),
+        known => format!("{:?}", known),
+    }
+}
+
+fn quality_grade_text(quality_grade: &QualityGrade) -> String {
+    match quality_grade {
+        QualityGrade::Certified(certification) => format!("Certified: {}", certification),
+        known => format!("{:?}", known),
+    }
+}
+
+fn text(value: &str) -> Icrc3Value {
+    Icrc3Value::Text(value.to_string())
+}
+
+/// Token metadata of a batch: the ICRC-7 display keys, then BatchMetadata under "harvestx:".
+pub fn token_metadata(token_id: &Nat) -> Option<Vec<(String, Icrc3Value)>> {
+    let batch = get_batch(token_id)?;
+    let metadata = &batch.metadata;
+    let offer_id = batch.id.trim_start_matches("batch_");
+    let mut entries = vec![
+        (
+            "icrc7:name".to_string(),
+            Icrc3Value::Text(format!("{} batch {}", metadata.product_name, token_id)),
+        ),
+        (
+            "icrc7:description".to_string(),
+            Icrc3Value::Text(format!(
+                "{} kg of {} ({}) from {}, harvest {}",
+                metadata.total_quantity,
+                metadata.product_name,
+                quality_grade_text(&metadata.quality_grade),
+                metadata.location,
+                metadata.harvest_date
+            )),
+        ),
+        (
+            "harvestx:product_name".to_string(),
+            text(&metadata.product_name),
+        ),
+        (
+            "harvestx:product_type".to_string(),
+            Icrc3Value::Text(product_type_text(&metadata.product_type)),
+        ),
+        (
+            "harvestx:quality_grade".to_string(),
+            Icrc3Value::Text(quality_grade_text(&metadata.quality_grade)),
+        ),
+        ("harvestx:location".to_string(), text(&metadata.location)),
+        (
+            "harvestx:harvest_date".to_string(),
+            text(&metadata.harvest_date),
+        ),
+        (
+            "harvestx:total_quantity_kg".to_string(),
+            nat(metadata.total_quantity as u128),
+        ),
+        ("harvestx:batch_id".to_string(), text(&batch.id)),
+        ("harvestx:offer_id".to_string(), text(offer_id)),
+        (
+            "harvestx:share_token".to_string(),
+            Icrc3Value::Text(shares::token_id(offer_id)),
+        ),
+        (
+            "harvestx:minted_at".to_string(),
+            nat(batch.minted_at as u128),
+        ),
+        (
+            "harvestx:provenance_events".to_string(),
+            nat(provenance::event_count(&batch.id) as u128),
+        ),
+    ];
+    if let Some(grade) = inspection::verified_grade(offer_id) {
+        entries.push((
+            "harvestx:verified_grade".to_string(),
+            Icrc3Value::Text(quality_grade_text(&grade)),
+        ));
+    }
+    if let Some(additional) = &metadata.additional {
+        entries.push(("harvestx:additional".to_string(), text(additional)));
+    }
+    Some(entries)
+}
+
+pub fn collection_metadata() -> Vec<(String, Icrc3Value)> {
+    let nat16 = |n: u16| nat(n as u128);
+    vec![
+        ("icrc7:symbol".to_string(), text(SYMBOL)),
+        ("icrc7:name".to_string(), text(NAME)),
+        ("icrc7:description".to_string(), text(DESCRIPTION)),
+        (
+            "icrc7:total_supply".to_string(),
+            Icrc3Value::Nat(total_supply()),
+        ),
+        (
+            "icrc7:max_query_batch_size".to_string(),
+            nat16(MAX_QUERY_BATCH_SIZE),
+        ),
+        (
+            "icrc7:max_update_batch_size".to_string(),
+            nat16(MAX_UPDATE_BATCH_SIZE),
+        ),
+        (
+            "icrc7:default_take_value".to_string(),
+            nat16(DEFAULT_TAKE_VALUE),
+        ),
+        ("icrc7:max_take_value".to_string(), nat16(MAX_TAKE_VALUE)),
+        (
+            "icrc7:max_memo_size".to_string(),
+            nat(MAX_MEMO_SIZE as u128),
+        ),
+        (
+            "icrc7:atomic_batch_transfers".to_string(),
+            Icrc3Value::Text("false".to_string()),
+        ),
+        (
+            "icrc7:tx_window".to_string(),
+            nat(icrc3::TX_WINDOW_NANOS as u128),
+        ),
+        (
+            "icrc7:permitted_drift".to_string(),
+            nat(icrc3::PERMITTED_DRIFT_NANOS as u128),
+        ),
+    ]
+}
+
+/// Records the mint of a new batch in the block log. Returns the block index.
+pub fn record_mint(batch: &BatchNFT, memo: Option<&[u8]>) -> Option<u128> {
+    let token_id = token_id_of(&batch.id)?;
+    let mut tx = vec![
+        ("tid".to_string(), Icrc3Value::Nat(token_id)),
+        ("to".to_string(), account_value(&account_of(batch.owner))),
+    ];
+    if let Some(memo) = memo {
+        tx.push(("memo".to_string(), Icrc3Value::Blob(memo.to_vec())));
+    }
+    Some(icrc3::append_block("7mint", Icrc3Value::Map(tx)))
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct Icrc7TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+fn transfer_one(
+    caller: Principal,
+    arg: &Icrc7TransferArg,
+    now: u64,
+) -> Result<Nat, Icrc7TransferError> {
+    if arg
+        .memo
+        .as_ref()
+        .is_some_and(|memo| memo.len() > MAX_MEMO_SIZE as usize)
+    {
+        return Err(Icrc7TransferError::GenericError {
+            error_code: Nat::from(BAD_MEMO),
+            message: format!("Memo is longer than {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+    icrc3::check_created_at_time(arg.created_at_time, now).map_err(|err| match err {
+        TimeError::TooOld => Icrc7TransferError::TooOld,
+        TimeError::CreatedInFuture { ledger_time } => {
+            Icrc7TransferError::CreatedInFuture { ledger_time }
+        }
+    })?;
+
+    let mut batch = get_batch(&arg.token_id).ok_or(Icrc7TransferError::NonExistingTokenId)?;
+    if batch.owner != caller || !is_default_subaccount(&arg.from_subaccount) {
+        return Err(Icrc7TransferError::Unauthorized);
+    }
+    if arg.to.owner == caller
+        || arg.to.owner == Principal::anonymous()
+        || !is_default_subaccount(&arg.to.subaccount)
+    {
+        return Err(Icrc7TransferError::InvalidRecipient);
+    }
+    let dedup = icrc3::deduplicate(caller, DEDUP_SCOPE, arg.created_at_time, arg, now).map_err(
+        |duplicate_of| Icrc7TransferError::Duplicate {
+            duplicate_of: Nat::from(duplicate_of),
+        },
+    )?;
+
+    let from = account_of(caller);
+    batch.owner = arg.to.owner;
+    let batch_id = batch.id.clone();
+    BATCHES.with(|b| b.borrow_mut().insert(batch_id.clone(), batch));
+
+    let mut tx = vec![
+        ("tid".to_string(), Icrc3Value::Nat(arg.token_id.clone())),
+        ("from".to_string(), account_value(&from)),
+        ("to".to_string(), account_value(&arg.to)),
+    ];
+    if let Some(memo) = &arg.memo {
+        tx.push(("memo".to_string(), Icrc3Value::Blob(memo.clone())));
+    }
+    if let Some(created_at_time) = arg.created_at_time {
+        tx.push(("ts".to_string(), nat(created_at_time as u128)));
+    }
+    let index = icrc3::append_block("7xfer", Icrc3Value::Map(tx));
+    verification::certify(&batch_id);
+    if let Some(dedup) = dedup {
+        dedup.record(index);
+    }
+    Ok(Nat::from(index))
+}
+
+/// ICRC-7 `icrc7_transfer`. Transfers are not atomic: each entry succeeds or fails on its own.
+pub fn transfer(
+    caller: Principal,
+    args: Vec<Icrc7TransferArg>,
+) -> Vec<Option<Result<Nat, Icrc7TransferError>>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE as usize {
+        return vec![Some(Err(Icrc7TransferError::GenericBatchError {
+            error_code: Nat::from(BATCH_TOO_LARGE),
+            message: format!("At most {} transfers per call", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    let now = get_current_time();
+    args.iter()
+        .map(|arg| Some(transfer_one(caller, arg, now)))
+        .collect()
+}
+
+pub fn supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        },
+    ]
+}
@@ -0,0 +1,362 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::accounts;
+use crate::types::*;
+use crate::USERS;
+
+// -----------------------------
+// Permissions
+// -----------------------------
+// Who may do what, in one place. Each operation lists the roles allowed to perform it on any
+// resource, and the ownerships that allow it on a resource the caller owns (e.g. the farmer of the
+// offer a request was made for). Endpoints call `authorize` with the owners of the resource at hand
+// instead of checking roles themselves. Admins read the matrix with `get_permissions`. Controllers
+// of the canister are super-admins: they hold the Admin role without a profile, and only they grant
+// or revoke it. Deactivated accounts hold no role, but keep their ownerships.
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    ManageUserRoles,
+    ViewAllUsers,
+    ViewPermissions,
+    CreateOffer,
+    ViewOfferRequests,
+    RespondToRequest,
+    CreateInvestmentRequest,
+    CancelRequest,
+    ViewDepositInfo,
+    SettleRequest,
+    ConfirmDelivery,
+    ReleaseEscrow,
+    RefundRequest,
+    ViewRefund,
+    ViewEscrow,
+    RecordProvenance,
+    TraceBatch,
+    IssueCertificate,
+    RevokeCertificate,
+    RunExpirySweep,
+    ConfigureCanister,
+    ViewTreasury,
+    ManageTreasury,
+    ManagePaymentTokens,
+    ReviewKyc,
+    ManageBans,
+    ManageShareLedgers,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ownership {
+    // Farmer of the offer (or of the offer a request was made for)
+    OfferFarmer,
+    // Investor who made the request
+    RequestInvestor,
+    TransactionFarmer,
+    TransactionInvestor,
+    // Current owner of the batch NFT
+    BatchOwner,
+    // Batch owner, farmer of its offer or holder of its shares
+    BatchStakeholder,
+    // Inspector who issued the certificate
+    CertificateInspector,
+}
+
+impl Ownership {
+    fn describe(self) -> &'static str {
+        match self {
+            Ownership::OfferFarmer => "offer owner",
+            Ownership::RequestInvestor => "request owner",
+            Ownership::TransactionFarmer => "transaction farmer",
+            Ownership::TransactionInvestor => "transaction investor",
+            Ownership::BatchOwner => "batch owner",
+            Ownership::BatchStakeholder => "batch stakeholder",
+            Ownership::CertificateInspector => "issuing inspector",
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Permission {
+    pub operation: Operation,
+    pub roles: Vec<UserRole>,
+    pub owners: Vec<Ownership>,
+}
+
+use Operation::*;
+use Ownership::*;
+use UserRole::{Admin, Farmer, Inspector, Investor, Verifier};
+
+// (operation, roles allowed on any resource, ownerships allowed on their own resource)
+const MATRIX: &[(Operation, &[UserRole], &[Ownership])] = &[
+    (ManageUserRoles, &[Admin], &[]),
+    (ViewAllUsers, &[Admin], &[]),
+    (ViewPermissions, &[Admin], &[]),
+    (CreateOffer, &[Farmer, Admin], &[]),
+    (ViewOfferRequests, &[], &[OfferFarmer]),
+    // The responding farmer becomes the transaction's farmer, so admins cannot respond for them
+    (RespondToRequest, &[], &[OfferFarmer]),
+    (CreateInvestmentRequest, &[Investor, Admin], &[]),
+    (CancelRequest, &[], &[RequestInvestor]),
+    (ViewDepositInfo, &[], &[RequestInvestor]),
+    (
+        SettleRequest,
+        &[Admin],
+        &[TransactionFarmer, RequestInvestor],
+    ),
+    (ConfirmDelivery, &[], &[TransactionInvestor]),
+    (ReleaseEscrow, &[Admin], &[TransactionFarmer]),
+    (RefundRequest, &[Admin], &[RequestInvestor]),
+    (ViewRefund, &[Admin], &[RequestInvestor, OfferFarmer]),
+    (ViewEscrow, &[Admin], &[RequestInvestor, OfferFarmer]),
+    (RecordProvenance, &[Admin], &[BatchOwner]),
+    (TraceBatch, &[Admin], &[BatchStakeholder]),
+    (IssueCertificate, &[Inspector], &[]),
+    (RevokeCertificate, &[Admin], &[CertificateInspector]),
+    (RunExpirySweep, &[Admin], &[]),
+    (ConfigureCanister, &[Admin], &[]),
+    (ViewTreasury, &[Admin], &[]),
+    (ManageTreasury, &[Admin], &[]),
+    (ManagePaymentTokens, &[Admin], &[]),
+    (ReviewKyc, &[Verifier], &[]),
+    (ManageBans, &[Admin], &[]),
+    (ManageShareLedgers, &[Admin], &[]),
+];
+
+fn rule(operation: Operation) -> (&'static [UserRole], &'static [Ownership]) {
+    MATRIX
+        .iter()
+        .find(|(op, _, _)| *op == operation)
+        .map(|(_, roles, owners)| (*roles, *owners))
+        .expect("operation missing from the permission matrix")
+}
+
+#[cfg(not(test))]
+pub fn is_super_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+}
+
+// Unit tests run natively, without the system API (see testing.rs)
+#[cfg(test)]
+pub fn is_super_admin(principal: &Principal) -> bool {
+    crate::testing::is_controller(principal)
+}
+
+pub fn has_role(principal: &Principal, role: &UserRole) -> bool {
+    if *role == UserRole::Admin && is_super_admin(principal) {
+        return true;
+    }
+    USERS.with(|users| {
+        users
+            .borrow()
+            .get(principal)
+            .is_some_and(|user| accounts::ensure_active(&user).is_ok() && user.roles.contains(role))
+    })
+}
+
+fn denial(roles: &[UserRole], owners: &[Ownership]) -> String {
+    if owners.is_empty() {
+        let roles: Vec<String> = roles.iter().map(|role| format!("{:?}", role)).collect();
+        return format!("{} role required", roles.join(" or "));
+    }
+    let owners: Vec<&str> = owners.iter().map(|owner| owner.describe()).collect();
+    format!("Access denied - not {}", owners.join(" or "))
+}
+
+/// Checks that the caller may perform `operation`. `owners` names the owners of the resource it
+/// applies to, e.g. `&[(Ownership::OfferFarmer, offer.farmer)]`.
+pub fn authorize(
+    caller: &Principal,
+    operation: Operation,
+    owners: &[(Ownership, Principal)],
+) -> Result<(), String> {
+    let (roles, ownerships) = rule(operation);
+    let owns = owners
+        .iter()
+        .any(|(ownership, owner)| owner == caller && ownerships.contains(ownership));
+    if owns || roles.iter().any(|role| has_role(caller, role)) {
+        return Ok(());
+    }
+    Err(denial(roles, ownerships))
+}
+
+/// The whole matrix, in declaration order.
+pub fn matrix() -> Vec<Permission> {
+    MATRIX
+        .iter()
+        .map(|(operation, roles, owners)| Permission {
+            operation: *operation,
+            roles: roles.to_vec(),
+            owners: owners.to_vec(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, principal};
+
+    const ROLES: [UserRole; 6] = [
+        Admin,
+        Farmer,
+        Investor,
+        UserRole::Guest,
+        Inspector,
+        Verifier,
+    ];
+
+    const OWNERSHIPS: [Ownership; 7] = [
+        OfferFarmer,
+        RequestInvestor,
+        TransactionFarmer,
+        TransactionInvestor,
+        BatchOwner,
+        BatchStakeholder,
+        CertificateInspector,
+    ];
+
+    // The access policy as specified, written out independently of MATRIX
+    const EXPECTED: &[(Operation, &[UserRole], &[Ownership])] = &[
+        (ManageUserRoles, &[Admin], &[]),
+        (ViewAllUsers, &[Admin], &[]),
+        (ViewPermissions, &[Admin], &[]),
+        (CreateOffer, &[Farmer, Admin], &[]),
+        (ViewOfferRequests, &[], &[OfferFarmer]),
+        (RespondToRequest, &[], &[OfferFarmer]),
+        (CreateInvestmentRequest, &[Investor, Admin], &[]),
+        (CancelRequest, &[], &[RequestInvestor]),
+        (ViewDepositInfo, &[], &[RequestInvestor]),
+        (
+            SettleRequest,
+            &[Admin],
+            &[TransactionFarmer, RequestInvestor],
+        ),
+        (ConfirmDelivery, &[], &[TransactionInvestor]),
+        (ReleaseEscrow, &[Admin], &[TransactionFarmer]),
+        (RefundRequest, &[Admin], &[RequestInvestor]),
+        (ViewRefund, &[Admin], &[RequestInvestor, OfferFarmer]),
+        (ViewEscrow, &[Admin], &[RequestInvestor, OfferFarmer]),
+        (RecordProvenance, &[Admin], &[BatchOwner]),
+        (TraceBatch, &[Admin], &[BatchStakeholder]),
+        (IssueCertificate, &[Inspector], &[]),
+        (RevokeCertificate, &[Admin], &[CertificateInspector]),
+        (RunExpirySweep, &[Admin], &[]),
+        (ConfigureCanister, &[Admin], &[]),
+        (ViewTreasury, &[Admin], &[]),
+        (ManageTreasury, &[Admin], &[]),
+        (ManagePaymentTokens, &[Admin], &[]),
+        (ReviewKyc, &[Verifier], &[]),
+        (ManageBans, &[Admin], &[]),
+        (ManageShareLedgers, &[Admin], &[]),
+    ];
+
+    #[test]
+    fn matrix_covers_every_operation_once() {
+        let operations: Vec<Operation> = matrix().iter().map(|p| p.operation).collect();
+        assert_eq!(operations.len(), EXPECTED.len());
+        for (operation, _, _) in EXPECTED {
+            assert_eq!(
+                operations.iter().filter(|op| *op == operation).count(),
+                1,
+                "{:?}",
+                operation
+            );
+        }
+    }
+
+    #[test]
+    fn roles_are_allowed_exactly_as_specified() {
+        for (index, role) in ROLES.iter().enumerate() {
+            let user = principal(index as u8 + 1);
+            testing::register(user, std::slice::from_ref(role));
+            for (operation, roles, _) in EXPECTED {
+                assert_eq!(
+                    authorize(&user, *operation, &[]).is_ok(),
+                    roles.contains(role),
+                    "{:?} by {:?}",
+                    operation,
+                    role
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ownerships_are_allowed_exactly_as_specified() {
+        let owner = principal(1);
+        testing::register(owner, &[UserRole::Guest]);
+        for (operation, _, ownerships) in EXPECTED {
+            for ownership in OWNERSHIPS {
+                assert_eq!(
+                    authorize(&owner, *operation, &[(ownership, owner)]).is_ok(),
+                    ownerships.contains(&ownership),
+                    "{:?} by {:?}",
+                    operation,
+                    ownership
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ownership_of_someone_else_is_denied() {
+        let caller = principal(1);
+        let owner = principal(2);
+        testing::register(caller, &[Farmer]);
+        assert_eq!(
+            authorize(&caller, RespondToRequest, &[(OfferFarmer, owner)]),
+            Err("Access denied - not offer owner".to_string())
+        );
+        assert_eq!(
+            authorize(
+                &caller,
+                ViewEscrow,
+                &[(RequestInvestor, owner), (OfferFarmer, owner)]
+            ),
+            Err("Access denied - not request owner or offer owner".to_string())
+        );
+        // An ownership the operation does not accept grants nothing either
+        assert!(authorize(&caller, CancelRequest, &[(OfferFarmer, caller)]).is_err());
+    }
+
+    #[test]
+    fn role_denials_name_the_required_roles() {
+        let guest = principal(1);
+        testing::register(guest, &[UserRole::Guest]);
+        assert_eq!(
+            authorize(&guest, CreateOffer, &[]),
+            Err("Farmer or Admin role required".to_string())
+        );
+    }
+
+    #[test]
+    fn controllers_are_super_admins_without_a_profile() {
+        let controller = principal(1);
+        testing::add_controller(controller);
+        assert!(is_super_admin(&controller));
+        assert!(has_role(&controller, &Admin));
+        assert!(!has_role(&controller, &Farmer));
+        for (operation, roles, _) in EXPECTED {
+            assert_eq!(
+                authorize(&controller, *operation, &[]).is_ok(),
+                roles.contains(&Admin),
+                "{:?}",
+                operation
+            );
+        }
+        assert!(!is_super_admin(&principal(2)));
+    }
+
+    #[test]
+    fn inactive_accounts_hold_no_role_but_keep_ownerships() {
+        let farmer = principal(1);
+        let mut profile = testing::register(farmer, &[Farmer, Admin]);
+        profile.deactivated_at = Some(testing::now());
+        USERS.with(|users| users.borrow_mut().insert(farmer, profile));
+        assert!(!has_role(&farmer, &Farmer));
+        assert!(!has_role(&farmer, &Admin));
+        assert!(authorize(&farmer, CreateOffer, &[]).is_err());
+        assert!(authorize(&farmer, ReleaseEscrow, &[(TransactionFarmer, farmer)]).is_ok());
+        assert!(!has_role(&principal(2), &Farmer));
+    }
+}
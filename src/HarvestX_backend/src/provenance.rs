@@ -0,0 +1,174 @@
+use candid::{Encode, Principal};
+use sha2::{Digest, Sha256};
+
+use crate::permissions::{self, Operation, Ownership};
+use crate::types::*;
+use crate::verification;
+use crate::{get_current_time, shares, BatchNFT, BATCHES, OFFERS, PROVENANCE};
+
+// -----------------------------
+// Batch provenance
+// -----------------------------
+// Every batch has an append-only timeline of what happened to its crop, from planting to shipment.
+// Events are stored under "<batch_id>|<index>", the index zero-padded so that a range scan returns
+// them in order. The batch owner records events; the owner, the offer's farmer, share holders and
+// admins can read them.
+
+const MAX_LOCATION_LEN: usize = 200;
+const MAX_NOTES_LEN: usize = 500;
+const MAX_KIND_LEN: usize = 64;
+// Reported times may run slightly ahead of the canister clock
+const PERMITTED_DRIFT_NANOS: u64 = 5 * 60 * 1_000_000_000;
+
+fn event_key(batch_id: &str, index: u64) -> String {
+    format!("{}|{:020}", batch_id, index)
+}
+
+pub fn events(batch_id: &str) -> Vec<ProvenanceEvent> {
+    let prefix = format!("{}|", batch_id);
+    PROVENANCE.with(|p| {
+        p.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, event)| event)
+            .collect()
+    })
+}
+
+/// Hex SHA-256 of the Candid-encoded timeline of a batch, as `get_batch_provenance` returns it. The
+/// public verification record carries this instead of the events, which only stakeholders may read.
+pub fn commitment(batch_id: &str) -> String {
+    let encoded = Encode!(&events(batch_id)).expect("failed to encode provenance events");
+    hex::encode(Sha256::digest(encoded))
+}
+
+pub fn event_count(batch_id: &str) -> u64 {
+    let prefix = format!("{}|", batch_id);
+    PROVENANCE.with(|p| {
+        p.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .count() as u64
+    })
+}
+
+pub fn is_sha256_hex(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn validate(request: &RecordProvenanceRequest, now: u64) -> Result<(), String> {
+    if request.location.trim().is_empty() {
+        return Err("Location is required".to_string());
+    }
+    if request.location.len() > MAX_LOCATION_LEN {
+        return Err(format!(
+            "Location is longer than {} bytes",
+            MAX_LOCATION_LEN
+        ));
+    }
+    if request
+        .notes
+        .as_ref()
+        .is_some_and(|notes| notes.len() > MAX_NOTES_LEN)
+    {
+        return Err(format!("Notes are longer than {} bytes", MAX_NOTES_LEN));
+    }
+    if let ProvenanceEventKind::Other(kind) = &request.kind {
+        if kind.trim().is_empty() || kind.len() > MAX_KIND_LEN {
+            return Err(format!(
+                "Custom event kinds must be 1 to {} bytes",
+                MAX_KIND_LEN
+            ));
+        }
+    }
+    if request
+        .attachment_hash
+        .as_ref()
+        .is_some_and(|hash| !is_sha256_hex(hash))
+    {
+        return Err("Attachment hash must be a hex SHA-256 digest".to_string());
+    }
+    if request.occurred_at > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+        return Err("Events cannot be recorded ahead of time".to_string());
+    }
+    Ok(())
+}
+
+fn get_batch(batch_id: &str) -> Result<BatchNFT, String> {
+    BATCHES
+        .with(|b| b.borrow().get(&batch_id.to_string()))
+        .ok_or_else(|| "Batch not found".to_string())
+}
+
+// Farmer of the offer the batch was minted for
+fn batch_farmer(batch_id: &str) -> Option<Principal> {
+    let offer_id = batch_id.strip_prefix("batch_")?;
+    OFFERS.with(|o| {
+        o.borrow()
+            .get(&offer_id.to_string())
+            .map(|offer| offer.farmer)
+    })
+}
+
+// Owner of the batch, farmer of its offer or holder of its shares
+fn has_stake(principal: &Principal, batch: &BatchNFT) -> bool {
+    batch.owner == *principal
+        || batch_farmer(&batch.id) == Some(*principal)
+        || batch
+            .id
+            .strip_prefix("batch_")
+            .is_some_and(|offer_id| shares::holds_shares(&shares::token_id(offer_id), principal))
+}
+
+/// Appends an event to a batch's timeline. Only the batch owner and admins can record.
+pub fn record(
+    caller: Principal,
+    request: RecordProvenanceRequest,
+) -> Result<ProvenanceEvent, String> {
+    let batch = get_batch(&request.batch_id)?;
+    permissions::authorize(
+        &caller,
+        Operation::RecordProvenance,
+        &[(Ownership::BatchOwner, batch.owner)],
+    )?;
+    let now = get_current_time();
+    validate(&request, now)?;
+
+    let index = event_count(&batch.id);
+    let event = ProvenanceEvent {
+        batch_id: batch.id.clone(),
+        index,
+        kind: request.kind,
+        actor: caller,
+        occurred_at: request.occurred_at,
+        location: request.location,
+        attachment_hash: request.attachment_hash.map(|hash| hash.to_lowercase()),
+        notes: request.notes,
+        recorded_at: now,
+    };
+    PROVENANCE.with(|p| {
+        p.borrow_mut()
+            .insert(event_key(&batch.id, index), event.clone())
+    });
+    verification::certify(&batch.id);
+    Ok(event)
+}
+
+/// Timeline of a batch, oldest first, for callers allowed to trace it.
+pub fn trace(caller: Principal, batch_id: &str) -> Result<Vec<ProvenanceEvent>, String> {
+    let batch = get_batch(batch_id)?;
+    let stake = has_stake(&caller, &batch).then_some((Ownership::BatchStakeholder, caller));
+    permissions::authorize(&caller, Operation::TraceBatch, stake.as_slice())?;
+    Ok(events(batch_id))
+}
+
+/// Ids of the batches the caller owns, farms or holds shares in.
+pub fn traceable_batches(caller: Principal) -> Vec<String> {
+    let batches: Vec<BatchNFT> =
+        BATCHES.with(|b| b.borrow().iter().map(|(_, batch)| batch).collect());
+    batches
+        .into_iter()
+        .filter(|batch| has_stake(&caller, batch))
+        .map(|batch| batch.id)
+        .collect()
+}
@@ -0,0 +1,284 @@
+use candid::Principal;
+
+use crate::accounts::{self, get_profile, save_profile};
+use crate::permissions::{self, Operation};
+use crate::types::*;
+use crate::USERS;
+
+// -----------------------------
+// Roles
+// -----------------------------
+// A profile holds a set of roles, e.g. a farmer who also invests in a neighbour's harvest. Users
+// take on Farmer, Investor and Guest themselves; asking for Inspector or Verifier queues the role
+// until an admin grants it, and Admin is only ever granted by a controller. A profile keeps at
+// least one role.
+
+pub const ADMIN_APPOINTMENT: &str =
+    "Admins are appointed at install or upgrade, or by a controller";
+
+// Roles users take on without approval
+fn is_self_service(role: &UserRole) -> bool {
+    matches!(
+        role,
+        UserRole::Farmer | UserRole::Investor | UserRole::Guest
+    )
+}
+
+fn drop_role(profile: &mut UserProfile, role: &UserRole) -> Result<(), String> {
+    if !profile.roles.contains(role) {
+        return Err("Role not held".to_string());
+    }
+    if profile.roles.len() == 1 {
+        return Err("A profile keeps at least one role".to_string());
+    }
+    profile.roles.retain(|held| held != role);
+    Ok(())
+}
+
+// Admins manage roles; only controllers grant or revoke Admin
+fn check_manager(caller: &Principal, role: &UserRole) -> Result<(), String> {
+    permissions::authorize(caller, Operation::ManageUserRoles, &[])?;
+    if *role == UserRole::Admin && !permissions::is_super_admin(caller) {
+        return Err("Only controllers can grant or revoke the Admin role".to_string());
+    }
+    Ok(())
+}
+
+/// Adds a role to the caller's profile, or asks for it if it needs an admin's approval.
+pub fn add(caller: Principal, role: UserRole) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    accounts::ensure_active(&profile)?;
+    if profile.roles.contains(&role) {
+        return Err("Role already held".to_string());
+    }
+    if role == UserRole::Admin {
+        return Err(ADMIN_APPOINTMENT.to_string());
+    }
+    if is_self_service(&role) {
+        profile.roles.push(role);
+    } else if profile.pending_roles.contains(&role) {
+        return Err("Role already requested".to_string());
+    } else {
+        profile.pending_roles.push(role);
+    }
+    Ok(save_profile(profile))
+}
+
+/// Drops one of the caller's roles, or withdraws a request for one.
+pub fn remove(caller: Principal, role: UserRole) -> Result<UserProfile, String> {
+    let mut profile = get_profile(&caller)?;
+    if profile.pending_roles.contains(&role) {
+        profile.pending_roles.retain(|pending| *pending != role);
+    } else {
+        drop_role(&mut profile, &role)?;
+    }
+    Ok(save_profile(profile))
+}
+
+/// Grants a role, approving the user's request for it if there is one.
+pub fn grant(
+    caller: Principal,
+    principal: Principal,
+    role: UserRole,
+) -> Result<UserProfile, String> {
+    check_manager(&caller, &role)?;
+    let mut profile = get_profile(&principal)?;
+    if profile.roles.contains(&role) {
+        return Err("Role already held".to_string());
+    }
+    profile.pending_roles.retain(|pending| *pending != role);
+    profile.roles.push(role);
+    Ok(save_profile(profile))
+}
+
+/// Revokes a role, or rejects the user's request for it.
+pub fn revoke(
+    caller: Principal,
+    principal: Principal,
+    role: UserRole,
+) -> Result<UserProfile, String> {
+    check_manager(&caller, &role)?;
+    let mut profile = get_profile(&principal)?;
+    if profile.pending_roles.contains(&role) {
+        profile.pending_roles.retain(|pending| *pending != role);
+    } else {
+        drop_role(&mut profile, &role)?;
+    }
+    Ok(save_profile(profile))
+}
+
+/// Replaces every role of a user with `role`.
+pub fn replace(
+    caller: Principal,
+    principal: Principal,
+    role: UserRole,
+) -> Result<UserProfile, String> {
+    check_manager(&caller, &role)?;
+    let mut profile = get_profile(&principal)?;
+    if profile.roles.contains(&UserRole::Admin) {
+        check_manager(&caller, &UserRole::Admin)?;
+    }
+    profile.pending_roles.retain(|pending| *pending != role);
+    profile.roles = vec![role];
+    Ok(save_profile(profile))
+}
+
+/// Profiles with roles waiting for approval.
+pub fn pending(caller: Principal) -> Result<Vec<UserProfile>, String> {
+    permissions::authorize(&caller, Operation::ManageUserRoles, &[])?;
+    Ok(USERS.with(|users| {
+        users
+            .borrow()
+            .iter()
+            .filter(|(_, profile)| !profile.pending_roles.is_empty())
+            .map(|(_, profile)| profile)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, principal};
+
+    fn admin() -> Principal {
+        let admin = principal(100);
+        testing::register(admin, &[UserRole::Admin]);
+        admin
+    }
+
+    fn controller() -> Principal {
+        let controller = principal(101);
+        testing::add_controller(controller);
+        controller
+    }
+
+    #[test]
+    fn self_service_roles_are_added_at_once() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Farmer]);
+        let profile = add(user, UserRole::Investor).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer, UserRole::Investor]);
+        assert!(profile.pending_roles.is_empty());
+        assert_eq!(
+            add(user, UserRole::Investor).unwrap_err(),
+            "Role already held".to_string()
+        );
+    }
+
+    #[test]
+    fn inspector_and_verifier_wait_for_an_admin() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Farmer]);
+        add(user, UserRole::Inspector).unwrap();
+        let profile = add(user, UserRole::Verifier).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer]);
+        assert_eq!(
+            profile.pending_roles,
+            vec![UserRole::Inspector, UserRole::Verifier]
+        );
+        assert!(!permissions::has_role(&user, &UserRole::Inspector));
+        assert_eq!(
+            add(user, UserRole::Inspector).unwrap_err(),
+            "Role already requested".to_string()
+        );
+        assert_eq!(pending(admin()).unwrap().len(), 1);
+
+        let profile = grant(admin(), user, UserRole::Inspector).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer, UserRole::Inspector]);
+        assert_eq!(profile.pending_roles, vec![UserRole::Verifier]);
+
+        let profile = revoke(admin(), user, UserRole::Verifier).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer, UserRole::Inspector]);
+        assert!(profile.pending_roles.is_empty());
+        assert!(pending(admin()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pending_requests_can_be_withdrawn() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Investor]);
+        add(user, UserRole::Verifier).unwrap();
+        let profile = remove(user, UserRole::Verifier).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Investor]);
+        assert!(profile.pending_roles.is_empty());
+    }
+
+    #[test]
+    fn only_controllers_grant_or_revoke_admin() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Farmer]);
+        assert_eq!(
+            add(user, UserRole::Admin).unwrap_err(),
+            ADMIN_APPOINTMENT.to_string()
+        );
+        assert_eq!(
+            grant(admin(), user, UserRole::Admin).unwrap_err(),
+            "Only controllers can grant or revoke the Admin role".to_string()
+        );
+        assert_eq!(
+            grant(user, user, UserRole::Inspector).unwrap_err(),
+            "Admin role required".to_string()
+        );
+
+        let profile = grant(controller(), user, UserRole::Admin).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer, UserRole::Admin]);
+        assert!(revoke(admin(), user, UserRole::Admin).is_err());
+        // Replacing an admin's roles takes their Admin role away
+        assert!(replace(admin(), user, UserRole::Farmer).is_err());
+        let profile = revoke(controller(), user, UserRole::Admin).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer]);
+    }
+
+    #[test]
+    fn a_profile_keeps_its_last_role() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Investor]);
+        assert_eq!(
+            remove(user, UserRole::Investor).unwrap_err(),
+            "A profile keeps at least one role".to_string()
+        );
+        assert_eq!(
+            revoke(admin(), user, UserRole::Investor).unwrap_err(),
+            "A profile keeps at least one role".to_string()
+        );
+        assert_eq!(
+            remove(user, UserRole::Farmer).unwrap_err(),
+            "Role not held".to_string()
+        );
+    }
+
+    #[test]
+    fn replace_keeps_pending_roles_consistent() {
+        let user = principal(1);
+        testing::register(user, &[UserRole::Farmer, UserRole::Investor]);
+        add(user, UserRole::Inspector).unwrap();
+        add(user, UserRole::Verifier).unwrap();
+
+        // Granting a requested role through `replace` approves the request
+        let profile = replace(admin(), user, UserRole::Inspector).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Inspector]);
+        assert_eq!(profile.pending_roles, vec![UserRole::Verifier]);
+
+        // Other requests stay pending, and no role is both held and pending
+        let profile = replace(admin(), user, UserRole::Guest).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Guest]);
+        assert_eq!(profile.pending_roles, vec![UserRole::Verifier]);
+        assert!(profile
+            .pending_roles
+            .iter()
+            .all(|role| !profile.roles.contains(role)));
+    }
+
+    #[test]
+    fn deactivated_accounts_cannot_take_on_roles() {
+        let user = principal(1);
+        let mut profile = testing::register(user, &[UserRole::Investor]);
+        profile.deactivated_at = Some(testing::now());
+        USERS.with(|users| users.borrow_mut().insert(user, profile));
+        assert_eq!(
+            add(user, UserRole::Farmer).unwrap_err(),
+            "Account deactivated".to_string()
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use candid::Principal;
+
+use crate::accounts;
+use crate::auth;
+use crate::permissions::{self, Operation};
+use crate::{shares, SHARE_LEDGERS};
+
+// -----------------------------
+// Per-batch share ledgers
+// -----------------------------
+// Wallets, indexers and DEX tooling expect one ledger canister per token, answering the standard
+// `icrc1_*` and `icrc2_*` methods. The `shares_*` endpoints of this canister take the token id as an
+// extra argument, so each batch gets a share ledger canister of its own (src/share_ledger),
+// deployed by an operator and registered here by an admin. A share ledger forwards transfers and
+// approvals, with the principal who made them, to the `share_ledger_*` endpoints, which only the
+// ledger registered for the token may call. Balances, locks and the ICRC-3 block log therefore stay
+// in this canister; the share ledger answers reads from a copy it syncs by replaying the token's
+// blocks from `icrc3_get_blocks`.
+
+// Error code of forwarded calls that are turned away; follows the codes of shares.rs
+pub const REJECTED_FORWARD: u128 = 4;
+
+/// Registers `ledger` as the share ledger of `token_id`, replacing the previous one.
+pub fn register(admin: Principal, token_id: &str, ledger: Principal) -> Result<(), String> {
+    permissions::authorize(&admin, Operation::ManageShareLedgers, &[])?;
+    if !shares::exists(token_id) {
+        return Err(format!("Unknown share token: {}", token_id));
+    }
+    if auth::is_anonymous(&ledger) || ledger == crate::canister_id() {
+        return Err("A share ledger must be a canister of its own".to_string());
+    }
+    if ledger_of(token_id) == Some(ledger) {
+        return Err("Ledger already registered for this token".to_string());
+    }
+    if SHARE_LEDGERS.with(|l| l.borrow().contains_key(&ledger)) {
+        return Err("Ledger already registered for another token".to_string());
+    }
+    SHARE_LEDGERS.with(|l| {
+        let mut ledgers = l.borrow_mut();
+        if let Some(previous) = ledgers
+            .iter()
+            .find(|(_, token)| token == token_id)
+            .map(|(previous, _)| previous)
+        {
+            ledgers.remove(&previous);
+        }
+        ledgers.insert(ledger, token_id.to_string());
+    });
+    Ok(())
+}
+
+/// Share ledger canister of a token, if one is registered.
+pub fn ledger_of(token_id: &str) -> Option<Principal> {
+    SHARE_LEDGERS.with(|l| {
+        l.borrow()
+            .iter()
+            .find(|(_, token)| token == token_id)
+            .map(|(ledger, _)| ledger)
+    })
+}
+
+/// Token of the calling share ledger, for a call it forwards on behalf of `owner`.
+pub fn forwarded_token(ledger: &Principal, owner: &Principal) -> Result<String, String> {
+    let token_id = SHARE_LEDGERS
+        .with(|l| l.borrow().get(ledger))
+        .ok_or_else(|| "Caller is not a registered share ledger".to_string())?;
+    if auth::is_anonymous(owner) {
+        return Err("Authentication required".to_string());
+    }
+    if accounts::is_banned(owner) {
+        return Err("Account banned".to_string());
+    }
+    Ok(token_id)
+}
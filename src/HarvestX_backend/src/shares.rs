@@ -0,0 +1,869 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use serde::Serialize;
+
+use crate::icrc3::{self, Dedup, ShareTx, TimeError};
+use crate::ledger::{
+    Account, Subaccount, TransferArg, TransferError, TransferFromArgs, TransferFromError,
+};
+use crate::types::*;
+use crate::verification;
+use crate::{
+    get_current_time, reserved_quantity, OFFERS, SHARES_ALLOWANCES, SHARES_BALANCES, SHARES_TOTAL,
+    TRANSACTIONS,
+};
+
+// -----------------------------
+// Batch share ledgers (ICRC-1)
+// -----------------------------
+// Every offer's batch has its own shares token, "shares:batch_<offer_id>", issued at one share per
+// kg. This canister keeps the balances of all of them: the `shares_icrc1_*` endpoints are the
+// ICRC-1 methods with the token id as an extra first argument, and each token's share ledger
+// canister offers the standard interface on top of them (see share_ledgers.rs). The minting
+// account is the canister's default account; transfers to it burn shares. Every operation is
+// recorded in the ICRC-3 block log (icrc3.rs), whose block indices are the transaction indices of
+// all share tokens.
+
+pub const DECIMALS: u8 = 0;
+pub const FEE: u128 = 0;
+pub const MIN_BURN_AMOUNT: u128 = 1;
+const MAX_MEMO_LEN: usize = 32;
+
+const UNKNOWN_TOKEN: u128 = 1;
+const BAD_MEMO: u128 = 2;
+const SELF_APPROVAL: u128 = 3;
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+// Shares token of an offer's batch: "shares:batch_<offer_id>"
+pub fn token_id(offer_id: &str) -> String {
+    format!("shares:batch_{}", offer_id)
+}
+
+fn offer_id_of(token_id: &str) -> Option<&str> {
+    token_id.strip_prefix("shares:batch_")
+}
+
+pub fn account_of(owner: Principal) -> Account {
+    Account {
+        owner,
+        subaccount: None,
+    }
+}
+
+pub fn minting_account() -> Account {
+    account_of(crate::canister_id())
+}
+
+fn is_default_subaccount(account: &Account) -> bool {
+    account.subaccount.map(|s| s == [0u8; 32]).unwrap_or(true)
+}
+
+fn same_account(a: &Account, b: &Account) -> bool {
+    a.owner == b.owner
+        && (a.subaccount == b.subaccount || is_default_subaccount(a) && is_default_subaccount(b))
+}
+
+// Key of an account's balance in SHARES_BALANCES: "token_id|principal" for the default subaccount,
+// "token_id|principal|subaccount_hex" otherwise
+pub fn balance_key(token_id: &str, account: &Account) -> String {
+    match account.subaccount {
+        Some(subaccount) if !is_default_subaccount(account) => format!(
+            "{}|{}|{}",
+            token_id,
+            account.owner.to_text(),
+            hex::encode(subaccount)
+        ),
+        _ => format!("{}|{}", token_id, account.owner.to_text()),
+    }
+}
+
+pub fn exists(token_id: &str) -> bool {
+    SHARES_TOTAL.with(|t| t.borrow().contains_key(&token_id.to_string()))
+}
+
+pub fn total_supply(token_id: &str) -> u128 {
+    SHARES_TOTAL.with(|t| t.borrow().get(&token_id.to_string()).unwrap_or(0))
+}
+
+pub fn balance_of(token_id: &str, account: &Account) -> u128 {
+    SHARES_BALANCES.with(|b| b.borrow().get(&balance_key(token_id, account)).unwrap_or(0))
+}
+
+/// Whether any account of `owner` (default or subaccount) holds shares of the token.
+pub fn holds_shares(token_id: &str, owner: &Principal) -> bool {
+    let key = format!("{}|{}", token_id, owner.to_text());
+    let subaccounts = format!("{}|", key);
+    SHARES_BALANCES.with(|b| {
+        b.borrow()
+            .range(key.clone()..)
+            .take_while(|(k, _)| *k == key || k.starts_with(&subaccounts))
+            .any(|(_, balance)| balance > 0)
+    })
+}
+
+fn set_balance(token_id: &str, account: &Account, balance: u128) {
+    let key = balance_key(token_id, account);
+    SHARES_BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        if balance == 0 {
+            balances.remove(&key);
+        } else {
+            balances.insert(key, balance);
+        }
+    });
+}
+
+// Shares of its own batch the farmer cannot transfer: the unsold quantity of an active offer, the
+// quantity reserved by acceptances in flight and the quantity of accepted requests awaiting their
+// deposit. All still belong to investors' purchases and are moved by settlement.
+fn locked_balance(token_id: &str, account: &Account) -> u128 {
+    let offer = match offer_id_of(token_id)
+        .and_then(|id| OFFERS.with(|o| o.borrow().get(&id.to_string())))
+    {
+        Some(offer) => offer,
+        None => return 0,
+    };
+    if !same_account(account, &account_of(offer.farmer)) {
+        return 0;
+    }
+
+    let unsold = if matches!(offer.status, OfferStatus::Active) {
+        offer.available_quantity as u128
+    } else {
+        0
+    };
+    let awaiting_deposit: u128 = TRANSACTIONS.with(|t| {
+        t.borrow()
+            .iter()
+            .filter(|(_, txn)| {
+                txn.offer_id == offer.id && matches!(txn.status, TransactionStatus::Confirmed)
+            })
+            .map(|(_, txn)| txn.quantity as u128)
+            .sum()
+    });
+    unsold + reserved_quantity(&offer.id) as u128 + awaiting_deposit
+}
+
+// Re-certifies the verification record of the token's batch, whose share distribution changed
+fn certify_batch(token_id: &str) {
+    if let Some(offer_id) = offer_id_of(token_id) {
+        verification::certify(&format!("batch_{}", offer_id));
+    }
+}
+
+/// Issues a new batch's shares to its farmer. Returns the transaction index.
+pub fn issue(token_id: &str, to: &Account, amount: u128) -> u128 {
+    SHARES_TOTAL.with(|t| {
+        t.borrow_mut().insert(token_id.to_string(), amount);
+    });
+    set_balance(token_id, to, amount);
+    icrc3::append(ShareTx {
+        to: Some(to),
+        ..ShareTx::new("1mint", token_id, amount)
+    })
+}
+
+/// Moves shares on behalf of the platform (settlement), ignoring the farmer's lock.
+/// `memo` links the block to the settled transaction.
+pub fn move_shares(
+    token_id: &str,
+    from: &Account,
+    to: &Account,
+    amount: u128,
+    memo: &Vec<u8>,
+) -> Result<u128, String> {
+    let from_balance = balance_of(token_id, from);
+    if from_balance < amount {
+        return Err(format!(
+            "Insufficient shares: {} holds {}, need {}",
+            from.owner, from_balance, amount
+        ));
+    }
+    set_balance(token_id, from, from_balance - amount);
+    set_balance(token_id, to, balance_of(token_id, to) + amount);
+    let index = icrc3::append(ShareTx {
+        from: Some(from),
+        to: Some(to),
+        memo: Some(memo),
+        ..ShareTx::new("1xfer", token_id, amount)
+    });
+    certify_batch(token_id);
+    Ok(index)
+}
+
+fn generic_error(error_code: u128, message: &str) -> TransferError {
+    TransferError::GenericError {
+        error_code,
+        message: message.to_string(),
+    }
+}
+
+// Checks shared by every share ledger update: known token, zero fee, memo length and the
+// created_at_time window. Returns the deduplication entry to record once the call succeeds.
+fn check_call<T: CandidType>(
+    caller: Principal,
+    token_id: &str,
+    fee: Option<u128>,
+    memo: Option<&Vec<u8>>,
+    created_at_time: Option<u64>,
+    arg: &T,
+) -> Result<Option<Dedup>, TransferError> {
+    if !exists(token_id) {
+        return Err(generic_error(UNKNOWN_TOKEN, "Unknown share token"));
+    }
+    if fee.is_some_and(|fee| fee != FEE) {
+        return Err(TransferError::BadFee { expected_fee: FEE });
+    }
+    if memo.is_some_and(|memo| memo.len() > MAX_MEMO_LEN) {
+        return Err(generic_error(BAD_MEMO, "Memo is longer than 32 bytes"));
+    }
+
+    let now = get_current_time();
+    icrc3::check_created_at_time(created_at_time, now).map_err(|err| match err {
+        TimeError::TooOld => TransferError::TooOld,
+        TimeError::CreatedInFuture { ledger_time } => {
+            TransferError::CreatedInFuture { ledger_time }
+        }
+    })?;
+    icrc3::deduplicate(caller, token_id, created_at_time, arg, now)
+        .map_err(|duplicate_of| TransferError::Duplicate { duplicate_of })
+}
+
+// Moves `amount` out of `from`'s spendable balance; sending to the minting account burns it.
+// Returns whether the shares were burned.
+fn debit_and_credit(
+    token_id: &str,
+    from: &Account,
+    to: &Account,
+    amount: u128,
+) -> Result<bool, TransferError> {
+    let burn = same_account(to, &minting_account());
+    if burn && amount < MIN_BURN_AMOUNT {
+        return Err(TransferError::BadBurn {
+            min_burn_amount: MIN_BURN_AMOUNT,
+        });
+    }
+
+    let balance = balance_of(token_id, from);
+    let spendable = balance.saturating_sub(locked_balance(token_id, from));
+    if spendable < amount {
+        return Err(TransferError::InsufficientFunds { balance: spendable });
+    }
+
+    set_balance(token_id, from, balance - amount);
+    if burn {
+        SHARES_TOTAL.with(|t| {
+            let mut totals = t.borrow_mut();
+            let supply = totals.get(&token_id.to_string()).unwrap_or(0);
+            totals.insert(token_id.to_string(), supply - amount);
+        });
+    } else {
+        set_balance(token_id, to, balance_of(token_id, to) + amount);
+    }
+    Ok(burn)
+}
+
+/// ICRC-1 `icrc1_transfer` on a batch's shares token. Transfers to the minting account burn.
+pub fn transfer(
+    caller: Principal,
+    token_id: &str,
+    arg: TransferArg,
+) -> Result<u128, TransferError> {
+    let dedup = check_call(
+        caller,
+        token_id,
+        arg.fee,
+        arg.memo.as_ref(),
+        arg.created_at_time,
+        &arg,
+    )?;
+
+    let from = Account {
+        owner: caller,
+        subaccount: arg.from_subaccount,
+    };
+    let burn = debit_and_credit(token_id, &from, &arg.to, arg.amount)?;
+
+    let index = icrc3::append(ShareTx {
+        from: Some(&from),
+        to: (!burn).then_some(&arg.to),
+        fee: arg.fee,
+        memo: arg.memo.as_ref(),
+        created_at_time: arg.created_at_time,
+        ..ShareTx::new(if burn { "1burn" } else { "1xfer" }, token_id, arg.amount)
+    });
+    certify_batch(token_id);
+    if let Some(dedup) = dedup {
+        dedup.record(index);
+    }
+    Ok(index)
+}
+
+// -----------------------------
+// Share allowances (ICRC-2)
+// -----------------------------
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: u128,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum ApproveError {
+    BadFee { expected_fee: u128 },
+    InsufficientFunds { balance: u128 },
+    AllowanceChanged { current_allowance: u128 },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+impl From<TransferError> for ApproveError {
+    fn from(err: TransferError) -> Self {
+        match err {
+            TransferError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            TransferError::InsufficientFunds { balance } => {
+                ApproveError::InsufficientFunds { balance }
+            }
+            TransferError::TooOld => ApproveError::TooOld,
+            TransferError::CreatedInFuture { ledger_time } => {
+                ApproveError::CreatedInFuture { ledger_time }
+            }
+            TransferError::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            TransferError::TemporarilyUnavailable => ApproveError::TemporarilyUnavailable,
+            TransferError::GenericError {
+                error_code,
+                message,
+            } => ApproveError::GenericError {
+                error_code,
+                message,
+            },
+            TransferError::BadBurn { .. } => ApproveError::GenericError {
+                error_code: 0,
+                message: "Unexpected burn".to_string(),
+            },
+        }
+    }
+}
+
+impl From<TransferError> for TransferFromError {
+    fn from(err: TransferError) -> Self {
+        match err {
+            TransferError::BadFee { expected_fee } => TransferFromError::BadFee { expected_fee },
+            TransferError::BadBurn { min_burn_amount } => {
+                TransferFromError::BadBurn { min_burn_amount }
+            }
+            TransferError::InsufficientFunds { balance } => {
+                TransferFromError::InsufficientFunds { balance }
+            }
+            TransferError::TooOld => TransferFromError::TooOld,
+            TransferError::CreatedInFuture { ledger_time } => {
+                TransferFromError::CreatedInFuture { ledger_time }
+            }
+            TransferError::Duplicate { duplicate_of } => {
+                TransferFromError::Duplicate { duplicate_of }
+            }
+            TransferError::TemporarilyUnavailable => TransferFromError::TemporarilyUnavailable,
+            TransferError::GenericError {
+                error_code,
+                message,
+            } => TransferFromError::GenericError {
+                error_code,
+                message,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+fn account_text(account: &Account) -> String {
+    match account.subaccount {
+        Some(subaccount) if !is_default_subaccount(account) => {
+            format!("{}.{}", account.owner.to_text(), hex::encode(subaccount))
+        }
+        _ => account.owner.to_text(),
+    }
+}
+
+// Key in SHARES_ALLOWANCES: "token_id|owner|spender", accounts as "principal[.subaccount_hex]"
+fn allowance_key(token_id: &str, owner: &Account, spender: &Account) -> String {
+    format!(
+        "{}|{}|{}",
+        token_id,
+        account_text(owner),
+        account_text(spender)
+    )
+}
+
+/// Current allowance of `spender` over `owner`'s shares; expired allowances read as zero.
+pub fn allowance(token_id: &str, owner: &Account, spender: &Account) -> ShareAllowance {
+    let now = get_current_time();
+    SHARES_ALLOWANCES.with(|a| {
+        a.borrow()
+            .get(&allowance_key(token_id, owner, spender))
+            .filter(|allowance| allowance.expires_at.map(|t| t > now).unwrap_or(true))
+            .unwrap_or_default()
+    })
+}
+
+fn set_allowance(token_id: &str, owner: &Account, spender: &Account, allowance: ShareAllowance) {
+    let key = allowance_key(token_id, owner, spender);
+    SHARES_ALLOWANCES.with(|a| {
+        let mut allowances = a.borrow_mut();
+        if allowance.allowance == 0 {
+            allowances.remove(&key);
+        } else {
+            allowances.insert(key, allowance);
+        }
+    });
+}
+
+/// ICRC-2 `icrc2_approve`: sets (not adds to) the spender's allowance over the caller's shares.
+pub fn approve(caller: Principal, token_id: &str, args: ApproveArgs) -> Result<u128, ApproveError> {
+    let dedup = check_call(
+        caller,
+        token_id,
+        args.fee,
+        args.memo.as_ref(),
+        args.created_at_time,
+        &args,
+    )?;
+
+    let owner = Account {
+        owner: caller,
+        subaccount: args.from_subaccount,
+    };
+    if args.spender.owner == caller {
+        return Err(ApproveError::GenericError {
+            error_code: SELF_APPROVAL,
+            message: "Cannot approve an account of the caller as spender".to_string(),
+        });
+    }
+    let now = get_current_time();
+    if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(ApproveError::Expired { ledger_time: now });
+    }
+    if let Some(expected) = args.expected_allowance {
+        let current = allowance(token_id, &owner, &args.spender).allowance;
+        if current != expected {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: current,
+            });
+        }
+    }
+
+    set_allowance(
+        token_id,
+        &owner,
+        &args.spender,
+        ShareAllowance {
+            allowance: args.amount,
+            expires_at: args.expires_at,
+        },
+    );
+
+    let index = icrc3::append(ShareTx {
+        from: Some(&owner),
+        spender: Some(&args.spender),
+        fee: args.fee,
+        memo: args.memo.as_ref(),
+        created_at_time: args.created_at_time,
+        expected_allowance: args.expected_allowance,
+        expires_at: args.expires_at,
+        ..ShareTx::new("2approve", token_id, args.amount)
+    });
+    if let Some(dedup) = dedup {
+        dedup.record(index);
+    }
+    Ok(index)
+}
+
+/// ICRC-2 `icrc2_transfer_from`: the caller spends an allowance over `args.from`'s shares.
+pub fn transfer_from(
+    caller: Principal,
+    token_id: &str,
+    args: TransferFromArgs,
+) -> Result<u128, TransferFromError> {
+    let dedup = check_call(
+        caller,
+        token_id,
+        args.fee,
+        args.memo.as_ref(),
+        args.created_at_time,
+        &args,
+    )?;
+
+    let spender = Account {
+        owner: caller,
+        subaccount: args.spender_subaccount,
+    };
+    let current = allowance(token_id, &args.from, &spender);
+    if current.allowance < args.amount + FEE {
+        return Err(TransferFromError::InsufficientAllowance {
+            allowance: current.allowance,
+        });
+    }
+
+    let burn = debit_and_credit(token_id, &args.from, &args.to, args.amount)?;
+    set_allowance(
+        token_id,
+        &args.from,
+        &spender,
+        ShareAllowance {
+            allowance: current.allowance - args.amount - FEE,
+            expires_at: current.expires_at,
+        },
+    );
+
+    let index = icrc3::append(ShareTx {
+        from: Some(&args.from),
+        to: (!burn).then_some(&args.to),
+        spender: Some(&spender),
+        fee: args.fee,
+        memo: args.memo.as_ref(),
+        created_at_time: args.created_at_time,
+        ..ShareTx::new(if burn { "1burn" } else { "2xfer" }, token_id, args.amount)
+    });
+    certify_batch(token_id);
+    if let Some(dedup) = dedup {
+        dedup.record(index);
+    }
+    Ok(index)
+}
+
+pub fn name(token_id: &str) -> Option<String> {
+    let offer_id = offer_id_of(token_id)?;
+    let offer = OFFERS.with(|o| o.borrow().get(&offer_id.to_string()))?;
+    Some(format!(
+        "HarvestX {} shares ({})",
+        offer.product_name, offer_id
+    ))
+}
+
+pub fn symbol(token_id: &str) -> Option<String> {
+    let offer_id = offer_id_of(token_id)?;
+    Some(format!("HXS-{}", offer_id.trim_start_matches("offer_")))
+}
+
+pub fn metadata(token_id: &str) -> Option<Vec<(String, MetadataValue)>> {
+    let offer_id = offer_id_of(token_id)?;
+    Some(vec![
+        (
+            "icrc1:name".to_string(),
+            MetadataValue::Text(name(token_id)?),
+        ),
+        (
+            "icrc1:symbol".to_string(),
+            MetadataValue::Text(symbol(token_id)?),
+        ),
+        (
+            "icrc1:decimals".to_string(),
+            MetadataValue::Nat(Nat::from(DECIMALS)),
+        ),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(FEE))),
+        (
+            "harvestx:offer_id".to_string(),
+            MetadataValue::Text(offer_id.to_string()),
+        ),
+        (
+            "harvestx:batch_id".to_string(),
+            MetadataValue::Text(format!("batch_{}", offer_id)),
+        ),
+    ])
+}
+
+/// Standards of the `shares_*` and `icrc3_*` endpoints. Only the block log follows its standard as
+/// is: wallets reach the ICRC-1 and ICRC-2 methods of a token through its share ledger canister
+/// (see share_ledgers.rs).
+pub fn supported_standards() -> Vec<SupportedStandard> {
+    vec![SupportedStandard {
+        name: "ICRC-3".to_string(),
+        url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, principal};
+    use crate::RESERVATIONS;
+
+    const OFFER_ID: &str = "offer_1";
+
+    fn farmer() -> Account {
+        account_of(principal(1))
+    }
+
+    fn holder() -> Account {
+        account_of(principal(2))
+    }
+
+    fn spender() -> Account {
+        account_of(principal(3))
+    }
+
+    fn recipient() -> Account {
+        account_of(principal(4))
+    }
+
+    // A sold-out offer whose farmer kept 700 shares and sold 300 to the holder
+    fn issue_shares() -> String {
+        let mut offer = testing::offer(OFFER_ID, farmer().owner);
+        offer.available_quantity = 0;
+        offer.status = OfferStatus::Completed;
+        OFFERS.with(|o| o.borrow_mut().insert(offer.id.clone(), offer));
+        let token_id = token_id(OFFER_ID);
+        issue(&token_id, &farmer(), 1_000);
+        move_shares(&token_id, &farmer(), &holder(), 300, &vec![]).unwrap();
+        token_id
+    }
+
+    fn approve_args(amount: u128) -> ApproveArgs {
+        ApproveArgs {
+            from_subaccount: None,
+            spender: spender(),
+            amount,
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn transfer_from_args(from: Account, amount: u128) -> TransferFromArgs {
+        TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to: recipient(),
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn spend(token_id: &str, from: Account, amount: u128) -> Result<u128, TransferFromError> {
+        transfer_from(spender().owner, token_id, transfer_from_args(from, amount))
+    }
+
+    fn allowance_of(token_id: &str, owner: &Account) -> u128 {
+        allowance(token_id, owner, &spender()).allowance
+    }
+
+    #[test]
+    fn approvals_check_the_expected_allowance() {
+        let token_id = issue_shares();
+        let owner = holder().owner;
+        approve(owner, &token_id, approve_args(100)).unwrap();
+
+        let args = ApproveArgs {
+            expected_allowance: Some(50),
+            ..approve_args(30)
+        };
+        assert!(matches!(
+            approve(owner, &token_id, args),
+            Err(ApproveError::AllowanceChanged {
+                current_allowance: 100
+            })
+        ));
+        assert_eq!(allowance_of(&token_id, &holder()), 100);
+
+        let args = ApproveArgs {
+            expected_allowance: Some(100),
+            ..approve_args(30)
+        };
+        approve(owner, &token_id, args).unwrap();
+        // Approvals set the allowance rather than add to it
+        assert_eq!(allowance_of(&token_id, &holder()), 30);
+    }
+
+    #[test]
+    fn approvals_reject_self_approval_and_fees() {
+        let token_id = issue_shares();
+        let owner = holder().owner;
+        let args = ApproveArgs {
+            spender: Account {
+                owner,
+                subaccount: Some([1; 32]),
+            },
+            ..approve_args(10)
+        };
+        assert!(matches!(
+            approve(owner, &token_id, args),
+            Err(ApproveError::GenericError {
+                error_code: SELF_APPROVAL,
+                ..
+            })
+        ));
+        let args = ApproveArgs {
+            fee: Some(1),
+            ..approve_args(10)
+        };
+        assert!(matches!(
+            approve(owner, &token_id, args),
+            Err(ApproveError::BadFee { expected_fee: FEE })
+        ));
+    }
+
+    #[test]
+    fn allowances_expire() {
+        let token_id = issue_shares();
+        let owner = holder().owner;
+        let now = testing::now();
+        let args = ApproveArgs {
+            expires_at: Some(now),
+            ..approve_args(100)
+        };
+        assert!(matches!(
+            approve(owner, &token_id, args),
+            Err(ApproveError::Expired { ledger_time }) if ledger_time == now
+        ));
+
+        let args = ApproveArgs {
+            expires_at: Some(now + 10),
+            ..approve_args(100)
+        };
+        approve(owner, &token_id, args).unwrap();
+        assert_eq!(
+            allowance(&token_id, &holder(), &spender()).expires_at,
+            Some(now + 10)
+        );
+        spend(&token_id, holder(), 10).unwrap();
+
+        testing::advance_time(10);
+        assert_eq!(allowance_of(&token_id, &holder()), 0);
+        assert!(matches!(
+            spend(&token_id, holder(), 10),
+            Err(TransferFromError::InsufficientAllowance { allowance: 0 })
+        ));
+        assert_eq!(balance_of(&token_id, &holder()), 290);
+    }
+
+    #[test]
+    fn transfers_from_spend_the_amount_and_fee() {
+        let token_id = issue_shares();
+        approve(holder().owner, &token_id, approve_args(100)).unwrap();
+
+        spend(&token_id, holder(), 40).unwrap();
+        assert_eq!(allowance_of(&token_id, &holder()), 100 - 40 - FEE);
+        assert_eq!(balance_of(&token_id, &holder()), 260);
+        assert_eq!(balance_of(&token_id, &recipient()), 40);
+
+        assert!(matches!(
+            spend(&token_id, holder(), 61),
+            Err(TransferFromError::InsufficientAllowance { allowance: 60 })
+        ));
+        let args = TransferFromArgs {
+            fee: Some(1),
+            ..transfer_from_args(holder(), 10)
+        };
+        assert!(matches!(
+            transfer_from(spender().owner, &token_id, args),
+            Err(TransferFromError::BadFee { expected_fee: FEE })
+        ));
+
+        // Spending the whole allowance removes it
+        spend(&token_id, holder(), 60).unwrap();
+        assert_eq!(allowance_of(&token_id, &holder()), 0);
+        assert!(SHARES_ALLOWANCES.with(|a| a.borrow().is_empty()));
+    }
+
+    #[test]
+    fn transfers_from_cannot_exceed_the_balance() {
+        let token_id = issue_shares();
+        approve(holder().owner, &token_id, approve_args(500)).unwrap();
+        assert!(matches!(
+            spend(&token_id, holder(), 301),
+            Err(TransferFromError::InsufficientFunds { balance: 300 })
+        ));
+        assert_eq!(allowance_of(&token_id, &holder()), 500);
+    }
+
+    #[test]
+    fn transfers_from_leave_the_farmers_locked_shares() {
+        let token_id = issue_shares();
+        approve(farmer().owner, &token_id, approve_args(1_000)).unwrap();
+
+        // Unsold quantity of an active offer
+        let mut offer = OFFERS
+            .with(|o| o.borrow().get(&OFFER_ID.to_string()))
+            .unwrap();
+        offer.status = OfferStatus::Active;
+        offer.available_quantity = 400;
+        OFFERS.with(|o| o.borrow_mut().insert(offer.id.clone(), offer));
+        assert!(matches!(
+            spend(&token_id, farmer(), 301),
+            Err(TransferFromError::InsufficientFunds { balance: 300 })
+        ));
+
+        // Quantity reserved by an acceptance in flight
+        RESERVATIONS.with(|r| {
+            r.borrow_mut()
+                .insert("req_1".to_string(), (OFFER_ID.to_string(), 100))
+        });
+        assert!(matches!(
+            spend(&token_id, farmer(), 201),
+            Err(TransferFromError::InsufficientFunds { balance: 200 })
+        ));
+
+        // Accepted requests awaiting their deposit
+        let transaction = Transaction {
+            id: "txn_1".to_string(),
+            offer_id: OFFER_ID.to_string(),
+            request_id: "req_2".to_string(),
+            farmer: farmer().owner,
+            investor: holder().owner,
+            quantity: 150,
+            price_per_kg: Amount::from_base_units(10_000_000),
+            total_amount: Amount::from_base_units(1_500_000_000),
+            status: TransactionStatus::Confirmed,
+            created_at: testing::now(),
+            updated_at: testing::now(),
+            tokenized_at: None,
+            delivered_at: None,
+            released_at: None,
+            release_block_index: None,
+            release_plan: None,
+        };
+        TRANSACTIONS.with(|t| t.borrow_mut().insert(transaction.id.clone(), transaction));
+        assert!(matches!(
+            spend(&token_id, farmer(), 51),
+            Err(TransferFromError::InsufficientFunds { balance: 50 })
+        ));
+        assert_eq!(balance_of(&token_id, &farmer()), 700);
+
+        spend(&token_id, farmer(), 50).unwrap();
+        assert_eq!(balance_of(&token_id, &farmer()), 650);
+        assert_eq!(allowance_of(&token_id, &farmer()), 950);
+        // The lock only applies to the farmer's own batch shares
+        assert_eq!(locked_balance(&token_id, &holder()), 0);
+    }
+}
@@ -0,0 +1,107 @@
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+
+use crate::types::*;
+use crate::{OFFERS, USERS};
+
+// -----------------------------
+// Unit test support
+// -----------------------------
+// Unit tests run natively, one thread per test, so every test starts with empty stable memory.
+// Without a replica there is no system API: the caller, the clock, the canister id and its
+// controllers are simulated here, and the seams in lib.rs and permissions.rs read them.
+
+/// Principal of this canister in unit tests.
+pub const CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+/// Time at which every test starts, in nanoseconds since the epoch.
+pub const START_TIME: u64 = 1_700_000_000_000_000_000;
+
+thread_local! {
+    static CALLER: Cell<Principal> = const { Cell::new(Principal::anonymous()) };
+    static NOW: Cell<u64> = const { Cell::new(START_TIME) };
+    static CONTROLLERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    static CERTIFIED_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A self-authenticating-length principal, distinct for each `n`.
+pub fn principal(n: u8) -> Principal {
+    Principal::from_slice(&[n; 29])
+}
+
+pub fn caller() -> Principal {
+    CALLER.with(Cell::get)
+}
+
+pub fn set_caller(principal: Principal) {
+    CALLER.with(|c| c.set(principal));
+}
+
+pub fn now() -> u64 {
+    NOW.with(Cell::get)
+}
+
+pub fn advance_time(nanos: u64) {
+    NOW.with(|n| n.set(n.get() + nanos));
+}
+
+pub fn is_controller(principal: &Principal) -> bool {
+    CONTROLLERS.with(|c| c.borrow().contains(principal))
+}
+
+pub fn add_controller(principal: Principal) {
+    CONTROLLERS.with(|c| c.borrow_mut().push(principal));
+}
+
+pub fn set_certified_data(data: &[u8]) {
+    CERTIFIED_DATA.with(|d| *d.borrow_mut() = data.to_vec());
+}
+
+/// Stands in for the certificate of a query call: the certified data itself, unsigned.
+pub fn data_certificate() -> Option<Vec<u8>> {
+    Some(CERTIFIED_DATA.with(|d| d.borrow().clone()))
+}
+
+/// Stores a profile holding `roles`, as if the user had registered and been granted them.
+pub fn register(principal: Principal, roles: &[UserRole]) -> UserProfile {
+    let profile = UserProfile {
+        principal,
+        roles: roles.to_vec(),
+        pending_roles: vec![],
+        display_name: format!("User {}", principal),
+        email: "user@harvestx.local".to_string(),
+        created_at: now(),
+        updated_at: now(),
+        kyc: KycRecord::default(),
+        deactivated_at: None,
+        erased_at: None,
+    };
+    USERS.with(|users| users.borrow_mut().insert(principal, profile.clone()));
+    profile
+}
+
+/// Stores an active offer of 1,000 kg of wheat at 0.1 ICP per kg.
+pub fn offer(id: &str, farmer: Principal) -> InvestmentOffer {
+    let offer = InvestmentOffer {
+        id: id.to_string(),
+        farmer,
+        product_name: "Wheat".to_string(),
+        product_type: ProductType::Grains,
+        total_quantity: 1_000,
+        available_quantity: 1_000,
+        price_per_kg: Amount::from_base_units(10_000_000),
+        description: "Durum wheat, \"Nile Delta\" <harvest>".to_string(),
+        harvest_date: "2030-06-01".to_string(),
+        location: "Nile Delta".to_string(),
+        quality_grade: QualityGrade::Premium,
+        minimum_investment: 10,
+        status: OfferStatus::Active,
+        created_at: now(),
+        updated_at: now(),
+        payment_token: None,
+        verified_grade: None,
+        verified_investors_only: Some(false),
+    };
+    OFFERS.with(|offers| offers.borrow_mut().insert(offer.id.clone(), offer.clone()));
+    offer
+}
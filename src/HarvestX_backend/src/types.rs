@@ -2,41 +2,283 @@ use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use ic_stable_structures::storable::{Bound, Storable}; // <-- Remove BoundedStorable
 use serde::Serialize;
 
+// Stored records are wrapped in a versioned envelope: `ENVELOPE_MAGIC`, the schema version the
+// record was written with, then its Candid encoding. Bytes without the magic predate envelopes
+// and are read as schema version 0. (Candid payloads start with "DIDL", never with the magic.)
+const ENVELOPE_MAGIC: &[u8; 2] = b"HX";
+
+pub trait Versioned: CandidType + for<'de> Deserialize<'de> {
+    /// Bump when older bytes can no longer be decoded into the type by Candid alone, e.g. a new
+    /// required field or a changed field type (new `opt` fields and removed fields need no bump),
+    /// and convert the older layout in `decode_version`.
+    const SCHEMA_VERSION: u8;
+
+    /// Decodes a payload written at `version` (at most `SCHEMA_VERSION`).
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        let _ = version;
+        Decode!(payload, Self).map_err(|e| e.to_string())
+    }
+}
+
+pub fn encode_record<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = ENVELOPE_MAGIC.to_vec();
+    bytes.push(T::SCHEMA_VERSION);
+    bytes.extend(Encode!(value).expect("failed to encode record"));
+    bytes
+}
+
+pub fn decode_record<T: Versioned>(bytes: &[u8]) -> Result<T, String> {
+    match bytes {
+        [b'H', b'X', version, ..] if *version > T::SCHEMA_VERSION => Err(format!(
+            "record has schema version {} but this build reads up to {}",
+            version,
+            T::SCHEMA_VERSION
+        )),
+        [b'H', b'X', version, payload @ ..] => T::decode_version(*version, payload),
+        legacy => T::decode_version(0, legacy),
+    }
+}
+
 macro_rules! impl_storable {
     ($t:ty, $max_size:expr) => {
+        impl Versioned for $t {
+            const SCHEMA_VERSION: u8 = 1;
+        }
+
+        impl_storable!($t, $max_size, versioned);
+    };
+    // For types that implement `Versioned` themselves
+    ($t:ty, $max_size:expr, versioned) => {
         impl Storable for $t {
             const BOUND: Bound = Bound::Bounded {
                 max_size: $max_size,
                 is_fixed_size: false,
             };
 
-            fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-                std::borrow::Cow::Owned(candid::Encode!(self).unwrap())
+            fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+                std::borrow::Cow::Owned(encode_record(self))
             }
 
             fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-                candid::Decode!(bytes.as_ref(), $t).unwrap()
+                decode_record(bytes.as_ref()).unwrap_or_else(|e| {
+                    panic!("failed to decode {}: {}", stringify!($t), e)
+                })
             }
         }
     };
 }
+
+// Money
+/// Exact amount in the base units of a payment token (e8s for ICP, 10^-6 for ckUSDC).
+/// Encoded as a Candid `nat`. Arithmetic is checked and returns `None` on overflow or
+/// underflow; the only division (basis-point commissions) takes an explicit `Rounding`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, CandidType, Serialize, Deserialize)]
+pub struct Amount(u128);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    // Toward zero; the party paying a commission never pays more than the exact share
+    Down,
+    // To the nearest base unit, ties away from zero
+    HalfUp,
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_base_units(units: u128) -> Self {
+        Amount(units)
+    }
+
+    pub const fn base_units(self) -> u128 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    // Price times a quantity, e.g. price per kg x kg
+    pub fn checked_mul(self, quantity: u64) -> Option<Amount> {
+        self.0.checked_mul(quantity as u128).map(Amount)
+    }
+
+    /// `self * numerator / denominator`, rounded as requested. Split into quotient and
+    /// remainder so that only `remainder * numerator` has to fit in a u128.
+    pub fn mul_div(self, numerator: u128, denominator: u128, rounding: Rounding) -> Option<Amount> {
+        if denominator == 0 {
+            return None;
+        }
+        let quotient = self.0 / denominator;
+        let remainder = self.0 % denominator;
+        let scaled = remainder.checked_mul(numerator)?;
+        let mut result = quotient
+            .checked_mul(numerator)?
+            .checked_add(scaled / denominator)?;
+        let dropped = scaled % denominator;
+        if rounding == Rounding::HalfUp && dropped >= denominator - dropped {
+            result = result.checked_add(1)?;
+        }
+        Some(Amount(result))
+    }
+
+    // Share of `bps` basis points (100 = 1%)
+    pub fn mul_bps(self, bps: u16, rounding: Rounding) -> Option<Amount> {
+        self.mul_div(bps as u128, 10_000, rounding)
+    }
+
+    /// Converts a decimal amount from a pre-fixed-point record into base units of a token
+    /// with `decimals` decimals, rounding half up. Returns `None` for negative, NaN or
+    /// out-of-range values.
+    pub fn from_legacy_f64(value: f64, decimals: u8) -> Option<Amount> {
+        let scaled = (value * 10f64.powi(decimals as i32)).round();
+        if !scaled.is_finite() || scaled < 0.0 || scaled >= u128::MAX as f64 {
+            return None;
+        }
+        Some(Amount(scaled as u128))
+    }
+}
+
+impl std::fmt::Display for Amount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
 // User Management
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
 pub enum UserRole {
     Admin,
     Farmer,
     Investor,
     Guest,
+    // Issues and revokes quality certificates; appointed by an admin
+    Inspector,
+    // Reviews KYC submissions; appointed by an admin
+    Verifier,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum KycStatus {
+    Unverified,
+    // Submitted, waiting for a verifier
+    Pending,
+    Verified,
+    Rejected,
+}
+
+// Identity verification of a user. The documents stay off-chain; only their digests are submitted.
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct KycRecord {
+    pub status: KycStatus,
+    // Hex SHA-256 digests of the submitted documents
+    pub document_hashes: Vec<String>,
+    pub submitted_at: Option<u64>,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub rejection_reason: Option<String>,
+}
+
+impl Default for KycRecord {
+    fn default() -> Self {
+        KycRecord {
+            status: KycStatus::Unverified,
+            document_hashes: vec![],
+            submitted_at: None,
+            reviewed_by: None,
+            reviewed_at: None,
+            rejection_reason: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct UserProfile {
     pub principal: Principal,
-    pub role: UserRole,
+    // Roles held, in the order they were gained; never empty
+    pub roles: Vec<UserRole>,
+    // Roles asked for that wait for an admin's approval
+    pub pending_roles: Vec<UserRole>,
     pub display_name: String,
     pub email: String,
     pub created_at: u64,
     pub updated_at: u64,
+    pub kyc: KycRecord,
+    // Set when the user leaves the platform; the account keeps its records but loses its roles
+    pub deactivated_at: Option<u64>,
+    // Set when the user's personal data was scrubbed; erased accounts stay deactivated
+    pub erased_at: Option<u64>,
+}
+
+// Schema 1 and earlier: a single role
+#[derive(CandidType, Deserialize)]
+struct UserProfileV1 {
+    principal: Principal,
+    role: UserRole,
+    display_name: String,
+    email: String,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Schema 2: several roles, no KYC
+#[derive(CandidType, Deserialize)]
+struct UserProfileV2 {
+    principal: Principal,
+    roles: Vec<UserRole>,
+    pending_roles: Vec<UserRole>,
+    display_name: String,
+    email: String,
+    created_at: u64,
+    updated_at: u64,
+}
+
+impl From<UserProfileV1> for UserProfileV2 {
+    fn from(profile: UserProfileV1) -> Self {
+        UserProfileV2 {
+            principal: profile.principal,
+            roles: vec![profile.role],
+            pending_roles: vec![],
+            display_name: profile.display_name,
+            email: profile.email,
+            created_at: profile.created_at,
+            updated_at: profile.updated_at,
+        }
+    }
+}
+
+impl Versioned for UserProfile {
+    const SCHEMA_VERSION: u8 = 3;
+
+    fn decode_version(version: u8, payload: &[u8]) -> Result<Self, String> {
+        let profile = match version {
+            3.. => return Decode!(payload, Self).map_err(|e| e.to_string()),
+            2 => Decode!(payload, UserProfileV2).map_err(|e| e.to_string())?,
+            _ => Decode!(payload, UserProfileV1)
+                .map_err(|e| e.to_string())?
+                .into(),
+        };
+        Ok(UserProfile {
+            principal: profile.principal,
+            roles: profile.roles,
+            pending_roles: profile.pending_roles,
+            display_name: profile.display_name,
+            email: profile.email,
+            created_at: profile.created_at,
+            updated_at: profile.updated_at,
+            kyc: KycRecord::default(),
+            deactivated_at: None,
+            erased_at: None,
+        })
+    }
 }
 
 // Investment Offers
@@ -48,7 +290,8 @@ pub struct InvestmentOffer {
     pub product_type: ProductType,
     pub total_quantity: u64,
     pub available_quantity: u64,
-    pub price_per_kg: f64,
+    // In base units of `payment_token`
+    pub price_per_kg: Amount,
     pub description: String,
     pub harvest_date: String,
     pub location: String,
@@ -57,6 +300,20 @@ pub struct InvestmentOffer {
     pub status: OfferStatus,
     pub created_at: u64,
     pub updated_at: u64,
+    // Token the offer is priced and paid in; None for offers created before multi-token support (ICP)
+    pub payment_token: Option<PaymentToken>,
+    // Grade of the latest valid quality certificate of the offer or its batch. Filled in when the
+    // offer is read, never stored: `quality_grade` is only the farmer's own claim.
+    pub verified_grade: Option<QualityGrade>,
+    // Only investors whose KYC is verified may request a share; None for offers created before KYC
+    pub verified_investors_only: Option<bool>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct PaymentToken {
+    pub symbol: String,
+    pub ledger_canister: Principal,
+    pub decimals: u8,
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
@@ -95,13 +352,35 @@ pub struct InvestmentRequest {
     pub offer_id: String,
     pub investor: Principal,
     pub requested_quantity: u64,
-    pub offered_price_per_kg: f64,
-    pub total_offered: f64,
+    // In base units of the offer's payment token; total = quantity x price
+    pub offered_price_per_kg: Amount,
+    pub total_offered: Amount,
     pub message: String,
     pub status: RequestStatus,
     pub created_at: u64,
     pub updated_at: u64,
     pub expires_at: u64,
+    // None for requests created before payment methods existed (subaccount deposit)
+    pub payment_method: Option<PaymentMethod>,
+    // Ledger arguments of the ICRC-2 payment pull, stored before the first attempt and reused when
+    // the acceptance is retried
+    pub payment_pull: Option<PaymentPull>,
+}
+
+// Amount and deduplication timestamp of an ICRC-2 payment pull, fixed on the first attempt so that
+// a retried acceptance sends an identical transfer
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct PaymentPull {
+    pub amount: u128,
+    pub created_at_time: u64,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum PaymentMethod {
+    // Investor transfers to the escrow subaccount, then `settle_request` verifies the balance
+    SubaccountDeposit,
+    // Investor approves the canister; funds are pulled with `icrc2_transfer_from` on acceptance
+    Icrc2Approval,
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
@@ -122,12 +401,33 @@ pub struct Transaction {
     pub farmer: Principal,
     pub investor: Principal,
     pub quantity: u64,
-    pub price_per_kg: f64,
-    pub total_amount: f64,
+    // In base units of the offer's payment token
+    pub price_per_kg: Amount,
+    pub total_amount: Amount,
     pub status: TransactionStatus,
     pub created_at: u64,
     pub updated_at: u64,
     pub tokenized_at: Option<u64>,
+    // Set when the investor confirms delivery of the goods
+    pub delivered_at: Option<u64>,
+    // Escrow release to the farmer (status moves to Completed)
+    pub released_at: Option<u64>,
+    pub release_block_index: Option<u128>,
+    // Ledger arguments of the release, reused when a failed attempt is retried
+    pub release_plan: Option<ReleasePlan>,
+}
+
+// Commission, ledger fee and deduplication timestamp of an escrow release, fixed on the first
+// attempt so that retries send identical transfers
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ReleasePlan {
+    // Gross commission; the treasury receives it net of `fee`
+    pub commission: u128,
+    pub fee: u128,
+    pub created_at_time: u64,
+    // Escrow balance beyond the expected amount, refunded to the investor net of `fee`. None when
+    // there is nothing to refund, and for plans made before overpayments were refunded
+    pub overpayment: Option<u128>,
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
@@ -135,9 +435,198 @@ pub enum TransactionStatus {
     Confirmed,
     Tokenized,
     Completed,
+    // Request expired before its deposit was settled
+    Cancelled,
+}
+
+// Platform Treasury
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, Default)]
+pub struct TreasuryState {
+    // Commission taken from each escrow release, in basis points (100 = 1%)
+    pub platform_fee_bps: u16,
+}
+
+// Amount in the base units of a payment token
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct TokenAmount {
+    pub token_symbol: String,
+    pub amount: u128,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct FeeRecord {
+    pub transaction_id: String,
+    pub offer_id: String,
+    pub token_symbol: String,
+    // Commission deducted from the escrow; the treasury receives it net of `ledger_fee`
+    pub gross_amount: u128,
+    pub ledger_fee: u128,
+    pub block_index: u128,
+    pub collected_at: u64,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct FeeSummary {
+    pub total_collected: Vec<TokenAmount>,
+    pub records: Vec<FeeRecord>,
+}
+
+// Escrow Refunds
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct Refund {
+    pub id: String,
+    pub request_id: String,
+    pub investor: Principal,
+    pub reason: RefundReason,
+    pub token_symbol: String,
+    // Net amount sent back; the ledger fee is paid out of the escrow balance
+    pub amount: u128,
+    pub fee: u128,
+    pub status: RefundStatus,
+    pub block_index: Option<u128>,
+    // Ledger deduplication timestamp, reused when a failed attempt is retried
+    pub created_at_time: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum RefundReason {
+    Rejected,
+    Cancelled,
+    Expired,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum RefundStatus {
+    Pending,
+    Completed,
+    Failed(String),
+}
+
+// Escrow Ledger
+// One record per investment request, tracing every ledger movement in and out of its escrow subaccount
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct EscrowRecord {
+    pub request_id: String,
+    pub offer_id: String,
+    pub investor: Principal,
+    pub farmer: Principal,
+    pub subaccount_hex: String,
+    pub token_symbol: String,
+    pub expected_amount: Amount,
+    pub state: EscrowState,
+    pub entries: Vec<EscrowEntry>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum EscrowState {
+    AwaitingDeposit,
+    // Deposit verified (or pulled with ICRC-2) and held until release or refund
+    Funded,
+    // Paid out to the farmer, net of the platform commission
+    Released,
+    // Returned to the investor
+    Refunded,
+    // Request ended without any funds to return
+    Closed,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct EscrowEntry {
+    pub kind: EscrowEntryKind,
+    // Base units credited to (deposits) or sent from (refunds, commission, release) the escrow,
+    // excluding the ledger fee
+    pub amount: u128,
+    pub ledger_fee: u128,
+    // None for subaccount deposits, which are observed as a balance rather than a single transfer
+    pub block_index: Option<u128>,
+    pub recorded_at: u64,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub enum EscrowEntryKind {
+    Deposit,
+    Refund,
+    Commission,
+    Release,
+}
+
+// Provenance
+// One step in the life of a batch, appended to its timeline and never edited
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct ProvenanceEvent {
+    pub batch_id: String,
+    // Position in the batch's timeline, from 0
+    pub index: u64,
+    pub kind: ProvenanceEventKind,
+    pub actor: Principal,
+    // When the step took place, as reported by the actor (nanoseconds)
+    pub occurred_at: u64,
+    pub location: String,
+    // Hex SHA-256 of a supporting document or photo kept off-chain
+    pub attachment_hash: Option<String>,
+    pub notes: Option<String>,
+    pub recorded_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum ProvenanceEventKind {
+    Planting,
+    InputApplication,
+    Harvest,
+    Drying,
+    Storage,
+    QualityTest,
+    Shipment,
+    Other(String),
+}
+
+// Quality certification
+// Issued by an inspector; valid until it expires or is revoked
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct QualityCertificate {
+    pub id: String,
+    pub subject: CertificationSubject,
+    pub grade: QualityGrade,
+    pub inspector: Principal,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    // Hex SHA-256 of the inspection report kept off-chain
+    pub report_hash: Option<String>,
+    pub notes: Option<String>,
+    pub revoked_at: Option<u64>,
+    pub revocation_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Serialize, Deserialize)]
+pub enum CertificationSubject {
+    Offer(String),
+    Batch(String),
 }
 
 // Request Types
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct IssueCertificateRequest {
+    pub subject: CertificationSubject,
+    pub grade: QualityGrade,
+    pub expires_at: u64,
+    pub report_hash: Option<String>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct RecordProvenanceRequest {
+    pub batch_id: String,
+    pub kind: ProvenanceEventKind,
+    pub occurred_at: u64,
+    pub location: String,
+    pub attachment_hash: Option<String>,
+    pub notes: Option<String>,
+}
+
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct RegisterUserRequest {
     pub role: UserRole,
@@ -145,25 +634,47 @@ pub struct RegisterUserRequest {
     pub email: String,
 }
 
+// Fields left as None are unchanged
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+}
+
+// A principal banned by an admin, blocked from every endpoint until unbanned
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct Ban {
+    pub principal: Principal,
+    pub reason: String,
+    pub banned_by: Principal,
+    pub banned_at: u64,
+}
+
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct CreateOfferRequest {
     pub product_name: String,
     pub product_type: ProductType,
     pub total_quantity: u64,
-    pub price_per_kg: f64,
+    // In base units of the payment token (e8s for ICP)
+    pub price_per_kg: Amount,
     pub description: String,
     pub harvest_date: String,
     pub location: String,
     pub quality_grade: QualityGrade,
     pub minimum_investment: u64,
+    // Symbol of an allowlisted payment token; defaults to ICP
+    pub payment_token: Option<String>,
+    // Only accept requests from investors whose KYC is verified; defaults to false
+    pub verified_investors_only: Option<bool>,
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct CreateInvestmentRequest {
     pub offer_id: String,
     pub requested_quantity: u64,
-    pub offered_price_per_kg: f64,
+    pub offered_price_per_kg: Amount,
     pub message: String,
+    pub payment_method: Option<PaymentMethod>,
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
@@ -206,13 +717,265 @@ pub struct PlatformStats {
     pub total_requests: u64,
     pub total_transactions: u64,
     pub active_offers: u64,
+    pub treasury_balance: Vec<TokenAmount>,
+    pub fee_revenue: Vec<TokenAmount>,
 }
-impl_storable!(UserProfile, 1024);
+
+// Canister Configuration
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct CanisterConfig {
+    pub ledger_canister: Principal,
+    // Seconds between expiry sweeps; None uses the default (hourly)
+    pub expiry_sweep_interval_secs: Option<u64>,
+}
+
+// Install and upgrade arguments
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct InitArgs {
+    // Granted the Admin role; principals without a profile get an empty one
+    pub admins: Vec<Principal>,
+}
+
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        Self {
+            // ICP ledger on mainnet; point this at the mock ledger on a local replica
+            ledger_canister: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            expiry_sweep_interval_secs: None,
+        }
+    }
+}
+
+// Outcome of one expiry sweep
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, Default)]
+pub struct ExpirySweepReport {
+    pub expired_offers: u64,
+    pub expired_requests: u64,
+    // Quantity reserved by accepted but unfunded requests, returned to their offers
+    pub released_quantity: u64,
+    pub refunds_completed: u64,
+    pub refunds_failed: u64,
+    pub ran_at: u64,
+}
+
+// ICRC-2 allowance over a holder's batch shares
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, Default)]
+pub struct ShareAllowance {
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+// Raised from 1024 for the KYC record; stable maps accept a larger bound on upgrade
+impl_storable!(UserProfile, 2048, versioned);
 impl_storable!(InvestmentOffer, 2048);
 impl_storable!(InvestmentRequest, 1024);
 impl_storable!(Transaction, 1024);
+impl_storable!(Refund, 1024);
+impl_storable!(FeeRecord, 512);
+impl_storable!(EscrowRecord, 2048);
+impl_storable!(ShareAllowance, 128);
+impl_storable!(ProvenanceEvent, 2048);
+impl_storable!(QualityCertificate, 2048);
+impl_storable!(TreasuryState, 128);
+impl_storable!(PaymentToken, 256);
 impl_storable!(RegisterUserRequest, 512);
+impl_storable!(UpdateProfileRequest, 512);
+impl_storable!(Ban, 1024);
 impl_storable!(CreateOfferRequest, 1024);
 impl_storable!(CreateInvestmentRequest, 512);
 impl_storable!(RespondToRequestRequest, 256);
 impl_storable!(PlatformStats, 256);
+impl_storable!(CanisterConfig, 256);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(units: u128) -> Amount {
+        Amount::from_base_units(units)
+    }
+
+    #[test]
+    fn mul_div_rounds_down_or_half_up() {
+        // 10 * 1 / 3 = 3.33...
+        assert_eq!(amount(10).mul_div(1, 3, Rounding::Down), Some(amount(3)));
+        assert_eq!(amount(10).mul_div(1, 3, Rounding::HalfUp), Some(amount(3)));
+        // 10 * 2 / 3 = 6.66...
+        assert_eq!(amount(10).mul_div(2, 3, Rounding::Down), Some(amount(6)));
+        assert_eq!(amount(10).mul_div(2, 3, Rounding::HalfUp), Some(amount(7)));
+        // Ties go up with HalfUp: 5 * 1 / 2 = 2.5
+        assert_eq!(amount(5).mul_div(1, 2, Rounding::Down), Some(amount(2)));
+        assert_eq!(amount(5).mul_div(1, 2, Rounding::HalfUp), Some(amount(3)));
+        // Exact results are the same either way
+        assert_eq!(amount(9).mul_div(2, 3, Rounding::Down), Some(amount(6)));
+        assert_eq!(amount(9).mul_div(2, 3, Rounding::HalfUp), Some(amount(6)));
+    }
+
+    #[test]
+    fn mul_div_handles_amounts_whose_product_overflows() {
+        // u128::MAX * 3 does not fit, but u128::MAX * 3 / 4 does
+        let max = amount(u128::MAX);
+        assert_eq!(
+            max.mul_div(3, 4, Rounding::Down),
+            Some(amount(u128::MAX / 4 * 3 + 2))
+        );
+        assert_eq!(max.mul_div(1, 1, Rounding::HalfUp), Some(max));
+    }
+
+    #[test]
+    fn mul_div_returns_none_on_overflow_or_zero_denominator() {
+        assert_eq!(amount(u128::MAX).mul_div(2, 1, Rounding::Down), None);
+        assert_eq!(amount(u128::MAX / 2 + 1).mul_div(2, 1, Rounding::Down), None);
+        // Rounding up past u128::MAX
+        assert_eq!(
+            amount(u128::MAX).mul_div(u128::MAX, u128::MAX - 1, Rounding::HalfUp),
+            None
+        );
+        assert_eq!(amount(10).mul_div(1, 0, Rounding::Down), None);
+    }
+
+    #[test]
+    fn mul_bps_takes_a_share_in_basis_points() {
+        // 2.5% of 1 ICP
+        assert_eq!(
+            amount(100_000_000).mul_bps(250, Rounding::Down),
+            Some(amount(2_500_000))
+        );
+        // 1% of 149 base units is 1.49, and 1% of 150 is 1.5
+        assert_eq!(amount(149).mul_bps(100, Rounding::Down), Some(amount(1)));
+        assert_eq!(amount(149).mul_bps(100, Rounding::HalfUp), Some(amount(1)));
+        assert_eq!(amount(150).mul_bps(100, Rounding::Down), Some(amount(1)));
+        assert_eq!(amount(150).mul_bps(100, Rounding::HalfUp), Some(amount(2)));
+        assert_eq!(amount(12_345).mul_bps(0, Rounding::HalfUp), Some(Amount::ZERO));
+        assert_eq!(
+            amount(u128::MAX).mul_bps(10_000, Rounding::Down),
+            Some(amount(u128::MAX))
+        );
+        assert_eq!(amount(u128::MAX).mul_bps(10_001, Rounding::Down), None);
+    }
+
+    #[test]
+    fn checked_arithmetic_returns_none_on_overflow_and_underflow() {
+        assert_eq!(amount(u128::MAX).checked_add(amount(1)), None);
+        assert_eq!(amount(1).checked_sub(amount(2)), None);
+        assert_eq!(amount(u128::MAX).checked_mul(2), None);
+        assert_eq!(amount(250).checked_mul(4), Some(amount(1_000)));
+    }
+
+    #[test]
+    fn from_legacy_f64_converts_to_base_units() {
+        // 0.1 is 0.1000000000000000055... in binary; it still maps to exactly 10^7 e8s
+        assert_eq!(Amount::from_legacy_f64(0.1, 8), Some(amount(10_000_000)));
+        assert_eq!(Amount::from_legacy_f64(0.3, 6), Some(amount(300_000)));
+        assert_eq!(Amount::from_legacy_f64(1.5, 0), Some(amount(2)));
+        assert_eq!(Amount::from_legacy_f64(0.000_000_004, 8), Some(Amount::ZERO));
+        assert_eq!(Amount::from_legacy_f64(0.000_000_005, 8), Some(amount(1)));
+        assert_eq!(Amount::from_legacy_f64(0.0, 8), Some(Amount::ZERO));
+        // Large values keep the 53-bit precision of an f64: 2^53 + 1 reads as 2^53
+        assert_eq!(
+            Amount::from_legacy_f64(2f64.powi(53) + 1.0, 0),
+            Some(amount(1 << 53))
+        );
+        assert_eq!(
+            Amount::from_legacy_f64(2f64.powi(100), 0),
+            Some(amount(1 << 100))
+        );
+    }
+
+    #[test]
+    fn from_legacy_f64_rejects_invalid_values() {
+        assert_eq!(Amount::from_legacy_f64(f64::NAN, 8), None);
+        assert_eq!(Amount::from_legacy_f64(f64::INFINITY, 8), None);
+        assert_eq!(Amount::from_legacy_f64(f64::NEG_INFINITY, 8), None);
+        assert_eq!(Amount::from_legacy_f64(-1.0, 8), None);
+        assert_eq!(Amount::from_legacy_f64(-0.01, 8), None);
+        // Beyond u128::MAX once scaled
+        assert_eq!(Amount::from_legacy_f64(1e31, 8), None);
+        assert_eq!(Amount::from_legacy_f64(f64::MAX, 0), None);
+    }
+
+    fn principal() -> Principal {
+        Principal::from_slice(&[7; 29])
+    }
+
+    fn enveloped(version: u8, payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.push(version);
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn profile_v1() -> UserProfileV1 {
+        UserProfileV1 {
+            principal: principal(),
+            role: UserRole::Farmer,
+            display_name: "Farmer".to_string(),
+            email: "farmer@harvestx.local".to_string(),
+            created_at: 1,
+            updated_at: 2,
+        }
+    }
+
+    fn assert_migrated_v1(profile: &UserProfile) {
+        assert_eq!(profile.principal, principal());
+        assert_eq!(profile.roles, vec![UserRole::Farmer]);
+        assert!(profile.pending_roles.is_empty());
+        assert_eq!(profile.display_name, "Farmer");
+        assert_eq!(profile.email, "farmer@harvestx.local");
+        assert_eq!((profile.created_at, profile.updated_at), (1, 2));
+        assert_eq!(profile.kyc.status, KycStatus::Unverified);
+        assert_eq!((profile.deactivated_at, profile.erased_at), (None, None));
+    }
+
+    #[test]
+    fn decodes_user_profiles_written_before_envelopes() {
+        let legacy = Encode!(&profile_v1()).unwrap();
+        assert_migrated_v1(&decode_record::<UserProfile>(&legacy).unwrap());
+    }
+
+    #[test]
+    fn decodes_user_profiles_of_every_schema_version() {
+        let v1 = enveloped(1, Encode!(&profile_v1()).unwrap());
+        assert_migrated_v1(&decode_record::<UserProfile>(&v1).unwrap());
+
+        let v2 = UserProfileV2 {
+            roles: vec![UserRole::Farmer, UserRole::Investor],
+            pending_roles: vec![UserRole::Inspector],
+            ..profile_v1().into()
+        };
+        let profile = decode_record::<UserProfile>(&enveloped(2, Encode!(&v2).unwrap())).unwrap();
+        assert_eq!(profile.roles, vec![UserRole::Farmer, UserRole::Investor]);
+        assert_eq!(profile.pending_roles, vec![UserRole::Inspector]);
+        assert_eq!(profile.kyc.status, KycStatus::Unverified);
+
+        let mut current = profile;
+        current.kyc.status = KycStatus::Verified;
+        current.deactivated_at = Some(3);
+        let bytes = encode_record(&current);
+        assert_eq!(&bytes[..3], b"HX\x03");
+        let decoded = decode_record::<UserProfile>(&bytes).unwrap();
+        assert_eq!(decoded.kyc.status, KycStatus::Verified);
+        assert_eq!(decoded.deactivated_at, Some(3));
+    }
+
+    #[test]
+    fn rejects_records_of_a_newer_schema_version() {
+        let future = enveloped(4, Encode!(&profile_v1()).unwrap());
+        assert!(decode_record::<UserProfile>(&future).is_err());
+    }
+
+    #[test]
+    fn decodes_unversioned_types_with_or_without_envelope() {
+        let allowance = ShareAllowance {
+            allowance: 10,
+            expires_at: Some(5),
+        };
+        let legacy = Encode!(&allowance).unwrap();
+        let decoded = decode_record::<ShareAllowance>(&legacy).unwrap();
+        assert_eq!((decoded.allowance, decoded.expires_at), (10, Some(5)));
+
+        let bytes = encode_record(&allowance);
+        assert_eq!(&bytes[..3], b"HX\x01");
+        let decoded = decode_record::<ShareAllowance>(&bytes).unwrap();
+        assert_eq!((decoded.allowance, decoded.expires_at), (10, Some(5)));
+    }
+}
@@ -0,0 +1,179 @@
+use candid::{CandidType, Deserialize, Encode, Principal};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Duration;
+
+use crate::types::*;
+use crate::{certification, http, inspection, provenance, shares, BatchMetadata, BATCHES, OFFERS};
+use crate::{SHARES_BALANCES, SHARES_TOTAL};
+
+// -----------------------------
+// Public batch verification
+// -----------------------------
+// Anyone holding a batch id (e.g. from a QR code on a sack) can check where it comes from. The
+// verification record of every batch is Candid-encoded and its SHA-256 certified under
+// ["batches", batch_id] (see certification.rs), so a client can check the response against the
+// subnet's signature instead of trusting the boundary node. The record holds no time-dependent
+// values: clients judge certificate validity from `expires_at` and `revoked_at` themselves.
+// `certify` must run after every change to a batch, its shares, certificates or provenance; it
+// also re-certifies the batch's HTTP response (see http.rs).
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BatchVerification {
+    pub batch_id: String,
+    pub offer_id: String,
+    pub metadata: BatchMetadata,
+    pub owner: Principal,
+    pub farmer: Option<Principal>,
+    pub minted_at: u64,
+    pub shares: ShareDistribution,
+    // Every certificate of the batch and its offer, oldest first, including lapsed ones
+    pub certificates: Vec<QualityCertificate>,
+    // The timeline itself is for stakeholders (see provenance.rs): the record commits to it, so a
+    // stakeholder can match what `get_batch_provenance` returns against the certified record
+    pub provenance_events: u64,
+    pub provenance_hash: String,
+}
+
+// Who holds a batch's shares, without naming investors
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShareDistribution {
+    pub token_id: String,
+    pub total_supply: u128,
+    pub farmer_balance: u128,
+    pub holders: u64,
+    pub largest_holding: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedBatchVerification {
+    // Candid encoding of the BatchVerification; its SHA-256 is the certified leaf
+    pub verification: Vec<u8>,
+    pub certificate: Vec<u8>,
+    // CBOR hash tree witnessing ["batches", batch_id]
+    pub hash_tree: Vec<u8>,
+}
+
+fn share_distribution(offer_id: &str, farmer: Option<Principal>) -> ShareDistribution {
+    let token_id = shares::token_id(offer_id);
+    let prefix = format!("{}|", token_id);
+    // Balances of the token, summed per principal across subaccounts
+    let mut holdings: BTreeMap<String, u128> = BTreeMap::new();
+    SHARES_BALANCES.with(|b| {
+        for (key, balance) in b
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            let owner = key[prefix.len()..].split('|').next().unwrap_or_default();
+            *holdings.entry(owner.to_string()).or_default() += balance;
+        }
+    });
+
+    let farmer_text = farmer.map(|f| f.to_text());
+    ShareDistribution {
+        total_supply: SHARES_TOTAL.with(|t| t.borrow().get(&token_id).unwrap_or(0)),
+        farmer_balance: farmer_text
+            .and_then(|farmer| holdings.get(&farmer).copied())
+            .unwrap_or(0),
+        holders: holdings.len() as u64,
+        largest_holding: holdings.values().copied().max().unwrap_or(0),
+        token_id,
+    }
+}
+
+pub fn build(batch_id: &str) -> Option<BatchVerification> {
+    let batch = BATCHES.with(|b| b.borrow().get(&batch_id.to_string()))?;
+    let offer_id = batch.id.trim_start_matches("batch_").to_string();
+    let farmer = OFFERS.with(|o| o.borrow().get(&offer_id).map(|offer| offer.farmer));
+
+    let mut certificates = inspection::for_subject(&CertificationSubject::Offer(offer_id.clone()));
+    certificates.extend(inspection::for_subject(&CertificationSubject::Batch(
+        batch.id.clone(),
+    )));
+    certificates.sort_by_key(|certificate| certificate.issued_at);
+
+    Some(BatchVerification {
+        shares: share_distribution(&offer_id, farmer),
+        provenance_events: provenance::event_count(&batch.id),
+        provenance_hash: provenance::commitment(&batch.id),
+        batch_id: batch.id,
+        offer_id,
+        metadata: batch.metadata,
+        owner: batch.owner,
+        farmer,
+        minted_at: batch.minted_at,
+        certificates,
+    })
+}
+
+fn encode(verification: &BatchVerification) -> Vec<u8> {
+    Encode!(verification).expect("failed to encode batch verification")
+}
+
+fn update_hash(batch_id: &str) {
+    let hash = build(batch_id).map(|verification| Sha256::digest(encode(&verification)).into());
+    certification::set_batch_hash(batch_id, hash);
+    http::update_batch_hash(batch_id);
+}
+
+/// Re-certifies the verification record of a batch after it changed.
+pub fn certify(batch_id: &str) {
+    update_hash(batch_id);
+    certification::refresh();
+}
+
+// Batches re-certified per message while the certified tree is rebuilt
+const CERTIFY_CHUNK: usize = 100;
+
+/// Re-certifies every batch. The certified tree is heap state, so this runs after install and
+/// upgrade; it works through the batches a chunk per timer message, keeping the hooks cheap and
+/// each message within its instruction limit. `verify_batch` asks clients to retry until their
+/// batch is reached.
+pub fn certify_all() {
+    // Certifies the ICRC-3 tip right away
+    certification::refresh();
+    certify_from(None);
+}
+
+// Certifies the batches after `after` (all when None), chunk by chunk
+fn certify_from(after: Option<String>) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        let batch_ids: Vec<String> = BATCHES.with(|b| {
+            let b = b.borrow();
+            let start = match after {
+                Some(after) => Bound::Excluded(after),
+                None => Bound::Unbounded,
+            };
+            b.range((start, Bound::Unbounded))
+                .take(CERTIFY_CHUNK)
+                .map(|(id, _)| id)
+                .collect()
+        });
+        for batch_id in &batch_ids {
+            update_hash(batch_id);
+        }
+        certification::refresh();
+        if batch_ids.len() == CERTIFY_CHUNK {
+            certify_from(batch_ids.last().cloned());
+        }
+    });
+}
+
+/// The verification record of a batch with its certificate. Only available in query calls.
+pub fn certified(batch_id: &str) -> Result<CertifiedBatchVerification, String> {
+    let verification = build(batch_id).ok_or_else(|| "Batch not found".to_string())?;
+    let bytes = encode(&verification);
+    let hash: [u8; 32] = Sha256::digest(&bytes).into();
+    if certification::batch_hash(batch_id) != Some(hash) {
+        return Err("Batch verification is not certified yet, retry shortly".to_string());
+    }
+    let certificate = certification::batch_certificate(batch_id)
+        .ok_or_else(|| "Certificates are only available in query calls".to_string())?;
+    Ok(CertifiedBatchVerification {
+        verification: bytes,
+        certificate: certificate.certificate,
+        hash_tree: certificate.hash_tree,
+    })
+}
@@ -12,7 +12,7 @@ import { Input } from "@/components/ui/input";
 import { Label } from "@/components/ui/label";
 import { Textarea } from "@/components/ui/textarea";
 import { useCreateInvestmentRequest } from "@/hooks/useICP";
-import { InvestmentOffer } from "@/services/icpService";
+import { icpService, InvestmentOffer } from "@/services/icpService";
 import { toast } from "sonner";
 
 interface InvestmentRequestDialogProps {
@@ -27,7 +27,9 @@ export function InvestmentRequestDialog({
     offer
 }: InvestmentRequestDialogProps) {
     const [message, setMessage] = useState('');
-    const [offeredPrice, setOfferedPrice] = useState(offer.price_per_kg.toString());
+    const decimals = icpService.getTokenDecimals(offer);
+    const currentPrice = icpService.formatAmount(offer.price_per_kg, decimals);
+    const [offeredPrice, setOfferedPrice] = useState(currentPrice);
     const [requestedQuantity, setRequestedQuantity] = useState('');
 
     const { createRequest, loading } = useCreateInvestmentRequest();
@@ -48,14 +50,14 @@ export function InvestmentRequestDialog({
             await createRequest({
                 offer_id: offer.id,
                 message,
-                offered_price_per_kg: parseFloat(offeredPrice),
+                offered_price_per_kg: icpService.toBaseUnits(offeredPrice, decimals),
                 requested_quantity: quantity,
             });
 
             toast.success('Investment request submitted successfully!');
             onOpenChange(false);
             setMessage('');
-            setOfferedPrice(offer.price_per_kg.toString());
+            setOfferedPrice(currentPrice);
             setRequestedQuantity('');
         } catch (error) {
             toast.error('Failed to submit investment request');
@@ -103,7 +105,7 @@ export function InvestmentRequestDialog({
                             required
                         />
                         <p className="text-sm text-muted-foreground">
-                            Current price: ${offer.price_per_kg}/kg
+                            Current price: ${currentPrice}/kg
                         </p>
                     </div>
 
@@ -10,8 +10,8 @@ const Navigation = () => {
 
   const isActive = (path: string) => location.pathname === path;
 
-  const isFarmer = user && 'Farmer' in user.role;
-  const isInvestor = user && 'Investor' in user.role;
+  const isFarmer = user && user.roles.some((role) => 'Farmer' in role);
+  const isInvestor = user && user.roles.some((role) => 'Investor' in role);
   return (
     <nav className="border-b border-border bg-card/50 backdrop-blur-sm sticky top-0 z-50">
       <div className="container mx-auto px-4 py-3">
@@ -60,7 +60,7 @@ const CreateListing = () => {
         quality_grade: icpService.convertQualityGrade(formData.qualityGrade),
         description: formData.description,
         total_quantity: BigInt(formData.totalQuantity),
-        price_per_kg: parseFloat(formData.pricePerKg),
+        price_per_kg: icpService.toBaseUnits(formData.pricePerKg, icpService.getTokenDecimals()),
         minimum_investment: BigInt(formData.minimumInvestment),
         location: formData.location,
         harvest_date: formData.harvestDate,
@@ -16,6 +16,7 @@ export default function FarmerDashboard() {
     const [selectedOffer, setSelectedOffer] = useState<string | null>(null)
     const { requests, loading: requestsLoading, refetch: refetchRequests } = useRequestsForOffer(selectedOffer || "")
     const { respond, loading: respondLoading } = useRespondToRequest()
+    const selectedDecimals = icpService.getTokenDecimals(offers.find((offer) => offer.id === selectedOffer))
 
     const getStatusColor = (status: string) => {
         switch (status) {
@@ -186,7 +187,7 @@ export default function FarmerDashboard() {
                                                     </div>
                                                     <div>
                                                         <p className="font-medium">Price per kg</p>
-                                                        <p>${offer.price_per_kg}</p>
+                                                        <p>${icpService.formatAmount(offer.price_per_kg, icpService.getTokenDecimals(offer))}</p>
                                                     </div>
                                                     <div>
                                                         <p className="font-medium">Quality Grade</p>
@@ -290,11 +291,11 @@ export default function FarmerDashboard() {
                                                     </div>
                                                     <div>
                                                         <p className="font-medium text-sm">Offered Price</p>
-                                                        <p>${request.offered_price_per_kg}/kg</p>
+                                                        <p>${icpService.formatAmount(request.offered_price_per_kg, selectedDecimals)}/kg</p>
                                                     </div>
                                                     <div>
                                                         <p className="font-medium text-sm">Total Value</p>
-                                                        <p className="text-lg font-bold text-primary">${request.total_offered.toFixed(2)}</p>
+                                                        <p className="text-lg font-bold text-primary">${icpService.formatAmount(request.total_offered, selectedDecimals)}</p>
                                                     </div>
                                                 </div>
 
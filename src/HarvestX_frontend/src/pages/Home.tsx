@@ -7,8 +7,8 @@ import { useCurrentUser } from "@/hooks/useICP";
 
 const Home = () => {
   const { user } = useCurrentUser();
-  const isFarmer = user && 'Farmer' in user.role;
-  const isInvestor = user && 'Investor' in user.role;
+  const isFarmer = user && user.roles.some((role) => 'Farmer' in role);
+  const isInvestor = user && user.roles.some((role) => 'Investor' in role);
 
   const features = [
     {
@@ -101,7 +101,7 @@ const InvestorDashboard = () => {
           return false
         }
       })
-      .reduce((sum, req) => sum + req.total_offered, 0) || 0
+      .reduce((sum, req) => sum + req.total_offered, 0n) || 0n
 
   const pendingRequests =
     myRequests?.filter((req) => {
@@ -341,7 +341,7 @@ const InvestorDashboard = () => {
                           </div>
                           <div className="flex items-center gap-2">
                             <DollarSign className="h-4 w-4 text-muted-foreground" />
-                            <span className="font-semibold text-primary">${offer.price_per_kg.toFixed(2)}/kg</span>
+                            <span className="font-semibold text-primary">${icpService.formatAmount(offer.price_per_kg, icpService.getTokenDecimals(offer))}/kg</span>
                           </div>
                           <div className="flex items-center gap-2">
                             <Target className="h-4 w-4 text-muted-foreground" />
@@ -407,6 +407,7 @@ const InvestorDashboard = () => {
               <div className="space-y-4">
                 {myRequests?.map((request) => {
                   const statusString = icpService.getRequestStatusString?.(request.status) || "Unknown"
+                  const decimals = icpService.getTokenDecimals(offers.find((offer) => offer.id === request.offer_id))
 
                   return (
                     <Card key={request.id} className="shadow-md">
@@ -424,10 +425,10 @@ const InvestorDashboard = () => {
                           </div>
                           <div className="text-right">
                             <div className="text-lg font-bold text-primary">
-                              ${request.total_offered.toLocaleString()}
+                              ${icpService.formatAmount(request.total_offered, decimals)}
                             </div>
                             <div className="text-sm text-muted-foreground">
-                              {Number(request.requested_quantity)}kg @ ${request.offered_price_per_kg}/kg
+                              {Number(request.requested_quantity)}kg @ ${icpService.formatAmount(request.offered_price_per_kg, decimals)}/kg
                             </div>
                           </div>
                         </div>
@@ -186,7 +186,7 @@ const Marketplace = () => {
                       </div>
                       <div className="flex items-center gap-2">
                         <TrendingUp className="h-4 w-4 text-muted-foreground" />
-                        <span className="font-semibold text-primary">${offer.price_per_kg.toFixed(2)}/kg</span>
+                        <span className="font-semibold text-primary">${icpService.formatAmount(offer.price_per_kg, icpService.getTokenDecimals(offer))}/kg</span>
                       </div>
                     </div>
 
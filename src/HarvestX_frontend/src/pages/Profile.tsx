@@ -56,10 +56,19 @@ const Profile = () => {
                                 <div className="text-lg">{user.email}</div>
                             </div>
                             <div>
-                                <div className="text-sm text-muted-foreground">Role</div>
-                                <Badge>
-                                    {"Farmer" in user.role ? "Farmer" : "Investor" in user.role ? "Investor" : "Admin" in user.role ? "Admin" : "Guest"}
-                                </Badge>
+                                <div className="text-sm text-muted-foreground">Roles</div>
+                                <div className="flex flex-wrap gap-2">
+                                    {user.roles.map((role) => (
+                                        <Badge key={Object.keys(role)[0]}>{Object.keys(role)[0]}</Badge>
+                                    ))}
+                                    {user.pending_roles.map((role) => (
+                                        <Badge key={Object.keys(role)[0]} variant="outline">{Object.keys(role)[0]} (pending)</Badge>
+                                    ))}
+                                </div>
+                            </div>
+                            <div>
+                                <div className="text-sm text-muted-foreground">KYC</div>
+                                <Badge variant="outline">{Object.keys(user.kyc.status)[0]}</Badge>
                             </div>
                             <div>
                                 <div className="text-sm text-muted-foreground">Principal</div>
@@ -29,7 +29,24 @@ export type UserRole =
   | { Farmer: null }
   | { Guest: null }
   | { Admin: null }
-  | { Investor: null };
+  | { Investor: null }
+  | { Inspector: null }
+  | { Verifier: null };
+
+export type KycStatus =
+  | { Unverified: null }
+  | { Pending: null }
+  | { Verified: null }
+  | { Rejected: null };
+
+export interface KycRecord {
+  status: KycStatus;
+  document_hashes: string[];
+  submitted_at: [] | [bigint];
+  reviewed_by: [] | [Principal];
+  reviewed_at: [] | [bigint];
+  rejection_reason: [] | [string];
+}
 
 export type RequestStatus =
   | { Rejected: null }
@@ -41,7 +58,15 @@ export type RequestStatus =
 export type TransactionStatus =
   | { Tokenized: null }
   | { Confirmed: null }
-  | { Completed: null };
+  | { Completed: null }
+  | { Cancelled: null };
+
+// Amounts (prices, totals) are exact integers in the base units of the offer's payment token
+export interface PaymentToken {
+  symbol: string;
+  ledger_canister: Principal;
+  decimals: number;
+}
 
 export interface InvestmentOffer {
   id: string;
@@ -55,19 +80,26 @@ export interface InvestmentOffer {
   product_name: string;
   product_type: ProductType;
   available_quantity: bigint;
-  price_per_kg: number;
+  price_per_kg: bigint;
   location: string;
   farmer: Principal;
   harvest_date: string;
+  payment_token: [] | [PaymentToken];
+  verified_grade: [] | [QualityGrade];
+  verified_investors_only: [] | [boolean];
 }
 
 export interface UserProfile {
   updated_at: bigint;
   principal: Principal;
-  role: UserRole;
+  roles: UserRole[];
+  pending_roles: UserRole[];
   created_at: bigint;
   email: string;
   display_name: string;
+  kyc: KycRecord;
+  deactivated_at: [] | [bigint];
+  erased_at: [] | [bigint];
 }
 
 export interface PlatformStats {
@@ -85,7 +117,7 @@ export interface CreateOfferRequest {
   quality_grade: QualityGrade;
   product_name: string;
   product_type: ProductType;
-  price_per_kg: number;
+  price_per_kg: bigint;
   location: string;
   harvest_date: string;
 }
@@ -93,7 +125,7 @@ export interface CreateOfferRequest {
 export interface CreateInvestmentRequest {
   offer_id: string;
   message: string;
-  offered_price_per_kg: number;
+  offered_price_per_kg: bigint;
   requested_quantity: bigint;
 }
 
@@ -101,11 +133,11 @@ export interface InvestmentRequest {
   id: string;
   status: RequestStatus;
   updated_at: bigint;
-  total_offered: number;
+  total_offered: bigint;
   created_at: bigint;
   offer_id: string;
   message: string;
-  offered_price_per_kg: number;
+  offered_price_per_kg: bigint;
   requested_quantity: bigint;
   expires_at: bigint;
   investor: Principal;
@@ -117,11 +149,11 @@ export interface Transaction {
   status: TransactionStatus;
   updated_at: bigint;
   tokenized_at?: bigint;
-  total_amount: number;
+  total_amount: bigint;
   created_at: bigint;
   offer_id: string;
   quantity: bigint;
-  price_per_kg: number;
+  price_per_kg: bigint;
   farmer: Principal;
   investor: Principal;
 }
@@ -168,14 +200,34 @@ const idlFactory = ({ IDL }: any) => {
     'Guest': IDL.Null,
     'Admin': IDL.Null,
     'Investor': IDL.Null,
+    'Inspector': IDL.Null,
+    'Verifier': IDL.Null,
+  });
+  const KycStatus = IDL.Variant({
+    'Unverified': IDL.Null,
+    'Pending': IDL.Null,
+    'Verified': IDL.Null,
+    'Rejected': IDL.Null,
+  });
+  const KycRecord = IDL.Record({
+    'status': KycStatus,
+    'document_hashes': IDL.Vec(IDL.Text),
+    'submitted_at': IDL.Opt(IDL.Nat64),
+    'reviewed_by': IDL.Opt(IDL.Principal),
+    'reviewed_at': IDL.Opt(IDL.Nat64),
+    'rejection_reason': IDL.Opt(IDL.Text),
   });
   const UserProfile = IDL.Record({
     'updated_at': IDL.Nat64,
     'principal': IDL.Principal,
-    'role': UserRole,
+    'roles': IDL.Vec(UserRole),
+    'pending_roles': IDL.Vec(UserRole),
     'created_at': IDL.Nat64,
     'email': IDL.Text,
     'display_name': IDL.Text,
+    'kyc': KycRecord,
+    'deactivated_at': IDL.Opt(IDL.Nat64),
+    'erased_at': IDL.Opt(IDL.Nat64),
   });
   const RegisterUserRequest = IDL.Record({
     'role': UserRole,
@@ -189,7 +241,7 @@ const idlFactory = ({ IDL }: any) => {
     'quality_grade': QualityGrade,
     'product_name': IDL.Text,
     'product_type': ProductType,
-    'price_per_kg': IDL.Float64,
+    'price_per_kg': IDL.Nat,
     'location': IDL.Text,
     'harvest_date': IDL.Text,
   });
@@ -199,6 +251,11 @@ const idlFactory = ({ IDL }: any) => {
     'Completed': IDL.Null,
     'Expired': IDL.Null,
   });
+  const PaymentToken = IDL.Record({
+    'symbol': IDL.Text,
+    'ledger_canister': IDL.Principal,
+    'decimals': IDL.Nat8,
+  });
   const InvestmentOffer = IDL.Record({
     'id': IDL.Text,
     'status': OfferStatus,
@@ -211,15 +268,18 @@ const idlFactory = ({ IDL }: any) => {
     'product_name': IDL.Text,
     'product_type': ProductType,
     'available_quantity': IDL.Nat64,
-    'price_per_kg': IDL.Float64,
+    'price_per_kg': IDL.Nat,
     'location': IDL.Text,
     'farmer': IDL.Principal,
     'harvest_date': IDL.Text,
+    'payment_token': IDL.Opt(PaymentToken),
+    'verified_grade': IDL.Opt(QualityGrade),
+    'verified_investors_only': IDL.Opt(IDL.Bool),
   });
   const CreateInvestmentRequest = IDL.Record({
     'offer_id': IDL.Text,
     'message': IDL.Text,
-    'offered_price_per_kg': IDL.Float64,
+    'offered_price_per_kg': IDL.Nat,
     'requested_quantity': IDL.Nat64,
   });
   const RequestStatus = IDL.Variant({
@@ -233,11 +293,11 @@ const idlFactory = ({ IDL }: any) => {
     'id': IDL.Text,
     'status': RequestStatus,
     'updated_at': IDL.Nat64,
-    'total_offered': IDL.Float64,
+    'total_offered': IDL.Nat,
     'created_at': IDL.Nat64,
     'offer_id': IDL.Text,
     'message': IDL.Text,
-    'offered_price_per_kg': IDL.Float64,
+    'offered_price_per_kg': IDL.Nat,
     'requested_quantity': IDL.Nat64,
     'expires_at': IDL.Nat64,
     'investor': IDL.Principal,
@@ -246,6 +306,7 @@ const idlFactory = ({ IDL }: any) => {
     'Tokenized': IDL.Null,
     'Confirmed': IDL.Null,
     'Completed': IDL.Null,
+    'Cancelled': IDL.Null,
   });
   const Transaction = IDL.Record({
     'id': IDL.Text,
@@ -253,11 +314,11 @@ const idlFactory = ({ IDL }: any) => {
     'status': TransactionStatus,
     'updated_at': IDL.Nat64,
     'tokenized_at': IDL.Opt(IDL.Nat64),
-    'total_amount': IDL.Float64,
+    'total_amount': IDL.Nat,
     'created_at': IDL.Nat64,
     'offer_id': IDL.Text,
     'quantity': IDL.Nat64,
-    'price_per_kg': IDL.Float64,
+    'price_per_kg': IDL.Nat,
     'farmer': IDL.Principal,
     'investor': IDL.Principal,
   });
@@ -485,6 +546,23 @@ class ICPService {
     }
   }
 
+  // Amount helpers: the backend stores prices in base units of the offer's token (e8s for ICP)
+  getTokenDecimals(offer?: InvestmentOffer): number {
+    return offer?.payment_token[0]?.decimals ?? 8;
+  }
+
+  toBaseUnits(value: string, decimals: number): bigint {
+    const [whole, fraction = ''] = value.trim().split('.');
+    const digits = (fraction + '0'.repeat(decimals)).slice(0, decimals);
+    return BigInt(whole || '0') * 10n ** BigInt(decimals) + BigInt(digits || '0');
+  }
+
+  formatAmount(amount: bigint, decimals: number): string {
+    const base = 10n ** BigInt(decimals);
+    const fraction = (amount % base).toString().padStart(decimals, '0').replace(/0+$/, '');
+    return fraction ? `${amount / base}.${fraction}` : `${amount / base}`;
+  }
+
   // Helper functions to extract values from variants
   getProductTypeString(productType: ProductType): string {
     if ('Nuts' in productType) return 'Nuts';
@@ -0,0 +1,347 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Minimal in-memory ICRC-1 ledger used to exercise the HarvestX escrow flow
+// on a local replica. State lives on the heap and is lost on upgrade.
+
+type Subaccount = [u8; 32];
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u128,
+}
+
+impl Default for InitArgs {
+    fn default() -> Self {
+        Self {
+            symbol: "ICP".to_string(),
+            decimals: 8,
+            fee: 10_000,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    // None and the all-zero subaccount denote the same account
+    fn normalized(self) -> (Principal, Subaccount) {
+        (self.owner, self.subaccount.unwrap_or([0u8; 32]))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: u128,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: u128 },
+    InsufficientFunds { balance: u128 },
+    AllowanceChanged { current_allowance: u128 },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Allowance {
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+type AllowanceKey = ((Principal, Subaccount), (Principal, Subaccount));
+
+thread_local! {
+    static METADATA: RefCell<InitArgs> = RefCell::new(InitArgs::default());
+    static BALANCES: RefCell<BTreeMap<(Principal, Subaccount), u128>> = const { RefCell::new(BTreeMap::new()) };
+    static ALLOWANCES: RefCell<BTreeMap<AllowanceKey, Allowance>> = const { RefCell::new(BTreeMap::new()) };
+    // Transfers carrying created_at_time (both kinds), keyed by (caller, args), for deduplication
+    static SEEN_TRANSFERS: RefCell<BTreeMap<Vec<u8>, u128>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_BLOCK: RefCell<u128> = const { RefCell::new(0) };
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        METADATA.with(|m| *m.borrow_mut() = args);
+    }
+}
+
+fn fee() -> u128 {
+    METADATA.with(|m| m.borrow().fee)
+}
+
+fn balance(account: &(Principal, Subaccount)) -> u128 {
+    BALANCES.with(|b| b.borrow().get(account).copied().unwrap_or(0))
+}
+
+fn credit(account: (Principal, Subaccount), amount: u128) {
+    BALANCES.with(|b| *b.borrow_mut().entry(account).or_insert(0) += amount);
+}
+
+fn debit(account: (Principal, Subaccount), amount: u128) -> Result<(), TransferError> {
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let current = balances.get(&account).copied().unwrap_or(0);
+        if current < amount {
+            return Err(TransferError::InsufficientFunds { balance: current });
+        }
+        balances.insert(account, current - amount);
+        Ok(())
+    })
+}
+
+fn next_block() -> u128 {
+    NEXT_BLOCK.with(|n| {
+        let mut n = n.borrow_mut();
+        let block = *n;
+        *n += 1;
+        block
+    })
+}
+
+#[ic_cdk::query]
+fn icrc1_symbol() -> String {
+    METADATA.with(|m| m.borrow().symbol.clone())
+}
+
+#[ic_cdk::query]
+fn icrc1_decimals() -> u8 {
+    METADATA.with(|m| m.borrow().decimals)
+}
+
+#[ic_cdk::query]
+fn icrc1_fee() -> u128 {
+    fee()
+}
+
+#[ic_cdk::query]
+fn icrc1_total_supply() -> u128 {
+    BALANCES.with(|b| b.borrow().values().sum())
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> u128 {
+    balance(&account.normalized())
+}
+
+#[ic_cdk::update]
+fn icrc1_transfer(arg: TransferArg) -> Result<u128, TransferError> {
+    let expected_fee = fee();
+    if arg.fee.is_some_and(|fee| fee != expected_fee) {
+        return Err(TransferError::BadFee { expected_fee });
+    }
+
+    let dedup_key = arg
+        .created_at_time
+        .map(|_| candid::encode_args((ic_cdk::caller(), arg.clone())).expect("encode transfer"));
+    if let Some(key) = &dedup_key {
+        if let Some(duplicate_of) = SEEN_TRANSFERS.with(|s| s.borrow().get(key).copied()) {
+            return Err(TransferError::Duplicate { duplicate_of });
+        }
+    }
+
+    let from = Account {
+        owner: ic_cdk::caller(),
+        subaccount: arg.from_subaccount,
+    };
+    debit(from.normalized(), arg.amount + expected_fee)?;
+    credit(arg.to.normalized(), arg.amount);
+
+    let block = next_block();
+    if let Some(key) = dedup_key {
+        SEEN_TRANSFERS.with(|s| s.borrow_mut().insert(key, block));
+    }
+    Ok(block)
+}
+
+// Expired allowances read as zero
+fn allowance(key: &AllowanceKey) -> Allowance {
+    let now = ic_cdk::api::time();
+    ALLOWANCES.with(|a| {
+        a.borrow()
+            .get(key)
+            .filter(|allowance| allowance.expires_at.map(|t| t > now).unwrap_or(true))
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    allowance(&(args.account.normalized(), args.spender.normalized()))
+}
+
+#[ic_cdk::update]
+fn icrc2_approve(args: ApproveArgs) -> Result<u128, ApproveError> {
+    let expected_fee = fee();
+    if args.fee.is_some_and(|fee| fee != expected_fee) {
+        return Err(ApproveError::BadFee { expected_fee });
+    }
+    let now = ic_cdk::api::time();
+    if let Some(expires_at) = args.expires_at {
+        if expires_at <= now {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+    }
+
+    let owner = Account {
+        owner: ic_cdk::caller(),
+        subaccount: args.from_subaccount,
+    }
+    .normalized();
+    let key = (owner, args.spender.normalized());
+    if let Some(expected) = args.expected_allowance {
+        let current = allowance(&key).allowance;
+        if current != expected {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: current,
+            });
+        }
+    }
+
+    debit(owner, expected_fee).map_err(|_| ApproveError::InsufficientFunds {
+        balance: balance(&owner),
+    })?;
+    ALLOWANCES.with(|a| {
+        a.borrow_mut().insert(
+            key,
+            Allowance {
+                allowance: args.amount,
+                expires_at: args.expires_at,
+            },
+        )
+    });
+
+    Ok(next_block())
+}
+
+#[ic_cdk::update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<u128, TransferFromError> {
+    let expected_fee = fee();
+    if args.fee.is_some_and(|fee| fee != expected_fee) {
+        return Err(TransferFromError::BadFee { expected_fee });
+    }
+
+    let dedup_key = args.created_at_time.map(|_| {
+        candid::encode_args((ic_cdk::caller(), args.clone())).expect("encode transfer_from")
+    });
+    if let Some(key) = &dedup_key {
+        if let Some(duplicate_of) = SEEN_TRANSFERS.with(|s| s.borrow().get(key).copied()) {
+            return Err(TransferFromError::Duplicate { duplicate_of });
+        }
+    }
+
+    let spender = Account {
+        owner: ic_cdk::caller(),
+        subaccount: args.spender_subaccount,
+    }
+    .normalized();
+    let from = args.from.normalized();
+    let key = (from, spender);
+    let current = allowance(&key);
+    if current.allowance < args.amount + expected_fee {
+        return Err(TransferFromError::InsufficientAllowance {
+            allowance: current.allowance,
+        });
+    }
+
+    debit(from, args.amount + expected_fee)
+        .map_err(|_| TransferFromError::InsufficientFunds { balance: balance(&from) })?;
+    credit(args.to.normalized(), args.amount);
+    ALLOWANCES.with(|a| {
+        a.borrow_mut().insert(
+            key,
+            Allowance {
+                allowance: current.allowance - args.amount - expected_fee,
+                expires_at: current.expires_at,
+            },
+        )
+    });
+
+    let block = next_block();
+    if let Some(key) = dedup_key {
+        SEEN_TRANSFERS.with(|s| s.borrow_mut().insert(key, block));
+    }
+    Ok(block)
+}
+
+/// Test helper: credits `amount` to `to` out of thin air, standing in for a real deposit.
+#[ic_cdk::update]
+fn mint(to: Account, amount: u128) -> u128 {
+    credit(to.normalized(), amount);
+    next_block()
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,738 @@
+use candid::utils::ArgumentEncoder;
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// ICRC-1 and ICRC-2 ledger of one batch's shares token. The HarvestX backend keeps the balances,
+// locks and ICRC-3 block log of every share token; this canister gives one of them the standard
+// interface that wallets, indexers and DEX tooling expect. Transfers and approvals are forwarded
+// with their caller to the backend's `share_ledger_*` methods, which accept them once this canister
+// is registered as the token's ledger (`register_share_ledger`). Reads are plain queries, so other
+// canisters can make them from update calls: they are answered from a copy of the token's balances,
+// allowances and metadata, kept in sync by replaying the token's blocks from the backend's log
+// every SYNC_INTERVAL and after each forwarded call. The copy lives on the heap and is rebuilt from
+// the log after an upgrade; reads trap until the first sync has caught up.
+
+type Subaccount = [u8; 32];
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    pub backend: Principal,
+    // Share token of the batch, "shares:batch_<offer_id>"
+    pub token_id: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: u128,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: u128 },
+    InsufficientFunds { balance: u128 },
+    AllowanceChanged { current_allowance: u128 },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Allowance {
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+// ICRC-3 generic value, the format of the backend's blocks
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: Value,
+}
+
+// The backend does not archive its log, so its `archived_blocks` are not read
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+}
+
+impl Value {
+    fn field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Map(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn nat(&self) -> Option<u128> {
+        match self {
+            Value::Nat(nat) => u128::try_from(nat.0.clone()).ok(),
+            _ => None,
+        }
+    }
+
+    // [owner] or [owner, subaccount]
+    fn account(&self) -> Option<AccountKey> {
+        let Value::Array(parts) = self else {
+            return None;
+        };
+        let owner = match parts.first()? {
+            Value::Blob(bytes) => Principal::try_from_slice(bytes).ok()?,
+            _ => return None,
+        };
+        let subaccount = match parts.get(1) {
+            Some(Value::Blob(bytes)) => Subaccount::try_from(bytes.as_slice()).ok()?,
+            Some(_) => return None,
+            None => [0u8; 32],
+        };
+        Some((owner, subaccount))
+    }
+}
+
+// Error code of transfers and approvals the backend could not be reached for
+const BACKEND_UNREACHABLE: u128 = 5;
+
+// Pause between two syncs with the backend's block log
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+// Blocks asked for per `icrc3_get_blocks` call; the backend answers at most 100
+const BLOCKS_PER_CALL: u64 = 100;
+
+// An account with its subaccount made explicit, so that None and the all-zero subaccount match
+type AccountKey = (Principal, Subaccount);
+
+fn account_key(account: &Account) -> AccountKey {
+    (account.owner, account.subaccount.unwrap_or([0u8; 32]))
+}
+
+// The token's state as of the first `next_block` blocks of the backend's log
+#[derive(Default)]
+struct Replica {
+    next_block: u64,
+    // None until the first sync completed
+    metadata: Option<Vec<(String, MetadataValue)>>,
+    total_supply: u128,
+    balances: BTreeMap<AccountKey, u128>,
+    allowances: BTreeMap<(AccountKey, AccountKey), Allowance>,
+}
+
+impl Replica {
+    fn credit(&mut self, account: AccountKey, amount: u128) {
+        *self.balances.entry(account).or_insert(0) += amount;
+    }
+
+    fn debit(&mut self, account: AccountKey, amount: u128) {
+        let balance = self.balances.get(&account).copied().unwrap_or(0);
+        match balance.saturating_sub(amount) {
+            0 => self.balances.remove(&account),
+            rest => self.balances.insert(account, rest),
+        };
+    }
+
+    fn set_allowance(&mut self, owner: AccountKey, spender: AccountKey, allowance: Allowance) {
+        if allowance.allowance == 0 {
+            self.allowances.remove(&(owner, spender));
+        } else {
+            self.allowances.insert((owner, spender), allowance);
+        }
+    }
+
+    // Applies a block the way the backend applied its operation. Blocks of other share tokens and
+    // of the batch collection are skipped.
+    fn apply(&mut self, token_id: &str, block: &Value) {
+        let Some(tx) = block.field("tx") else {
+            return;
+        };
+        if tx.field("token").and_then(Value::text) != Some(token_id) {
+            return;
+        }
+        let amount = tx.field("amt").and_then(Value::nat).unwrap_or(0);
+        let fee = tx.field("fee").and_then(Value::nat).unwrap_or(0);
+        let from = tx.field("from").and_then(Value::account);
+        let to = tx.field("to").and_then(Value::account);
+        let spender = tx.field("spender").and_then(Value::account);
+
+        match (block.field("btype").and_then(Value::text), from, to) {
+            (Some("1mint"), _, Some(to)) => {
+                self.credit(to, amount);
+                self.total_supply += amount;
+            }
+            (Some("1burn" | "1xfer" | "2xfer"), Some(from), to) => {
+                self.debit(from, amount + fee);
+                if let Some(spender) = spender {
+                    let current = self.allowances.get(&(from, spender)).cloned();
+                    if let Some(current) = current {
+                        let allowance = Allowance {
+                            allowance: current.allowance.saturating_sub(amount + fee),
+                            ..current
+                        };
+                        self.set_allowance(from, spender, allowance);
+                    }
+                }
+                // A transfer burns its fee, a burn its whole amount
+                let burned = match to {
+                    Some(to) => {
+                        self.credit(to, amount);
+                        fee
+                    }
+                    None => amount + fee,
+                };
+                self.total_supply = self.total_supply.saturating_sub(burned);
+            }
+            (Some("2approve"), Some(from), _) => {
+                if let Some(spender) = spender {
+                    self.debit(from, fee);
+                    self.total_supply = self.total_supply.saturating_sub(fee);
+                    let expires_at = tx.field("expires_at").and_then(Value::nat);
+                    let allowance = Allowance {
+                        allowance: amount,
+                        expires_at: expires_at.map(|t| t as u64),
+                    };
+                    self.set_allowance(from, spender, allowance);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<Option<InitArgs>> = const { RefCell::new(None) };
+    static REPLICA: RefCell<Replica> = RefCell::new(Replica::default());
+    // Whether a periodic sync is running
+    static SYNCING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[ic_cdk::init]
+fn init(args: InitArgs) {
+    CONFIG.with(|c| *c.borrow_mut() = Some(args));
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(sync()));
+    ic_cdk_timers::set_timer_interval(SYNC_INTERVAL, || ic_cdk::spawn(sync()));
+}
+
+// The replica is rebuilt from the backend's log, so an upgrade only takes the init argument again
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: InitArgs) {
+    init(args);
+}
+
+fn config() -> InitArgs {
+    CONFIG
+        .with(|c| c.borrow().clone())
+        .expect("share ledger is not initialized")
+}
+
+fn token_id() -> String {
+    config().token_id
+}
+
+async fn backend_call<A, R>(method: &str, args: A) -> Result<R, String>
+where
+    A: ArgumentEncoder,
+    R: CandidType + for<'de> Deserialize<'de>,
+{
+    ic_cdk::call::<A, (R,)>(config().backend, method, args)
+        .await
+        .map(|(result,)| result)
+        .map_err(|(code, message)| {
+            format!("Backend call {} failed ({:?}): {}", method, code, message)
+        })
+}
+
+// Replays the blocks appended to the backend's log since the last sync, then refreshes the
+// metadata, which follows the batch's offer
+async fn catch_up() -> Result<(), String> {
+    let token_id = token_id();
+    loop {
+        let start = REPLICA.with(|r| r.borrow().next_block);
+        let args = vec![GetBlocksArgs {
+            start: Nat::from(start),
+            length: Nat::from(BLOCKS_PER_CALL),
+        }];
+        let result: GetBlocksResult = backend_call("icrc3_get_blocks", (args,)).await?;
+        if result.blocks.is_empty() {
+            break;
+        }
+        REPLICA.with(|r| {
+            let mut replica = r.borrow_mut();
+            // Another sync may have replayed some of these blocks meanwhile
+            for block in &result.blocks {
+                if block.id == replica.next_block {
+                    replica.apply(&token_id, &block.block);
+                    replica.next_block += 1;
+                }
+            }
+        });
+    }
+
+    let metadata = backend_call("shares_icrc1_metadata", (token_id,)).await?;
+    REPLICA.with(|r| r.borrow_mut().metadata = Some(metadata));
+    Ok(())
+}
+
+fn log_sync_error(result: Result<(), String>) {
+    if let Err(message) = result {
+        ic_cdk::println!("Share ledger sync failed: {}", message);
+    }
+}
+
+// Periodic sync, skipped while the previous one is still running. Syncs may overlap with those of
+// forwarded calls: each block is replayed once, by whichever reaches it first.
+async fn sync() {
+    if SYNCING.with(|s| s.replace(true)) {
+        return;
+    }
+    log_sync_error(catch_up().await);
+    SYNCING.with(|s| s.set(false));
+}
+
+// Forwarded calls sync before returning, so that the caller's next reads reflect them
+async fn synced<T>(result: T) -> T {
+    log_sync_error(catch_up().await);
+    result
+}
+
+// Reads the replica, trapping until the first sync completed
+fn read<R>(f: impl FnOnce(&Replica, &[(String, MetadataValue)]) -> R) -> R {
+    REPLICA.with(|r| {
+        let replica = r.borrow();
+        match &replica.metadata {
+            Some(metadata) => f(&replica, metadata),
+            None => ic_cdk::trap("Share ledger is syncing with the backend, retry shortly"),
+        }
+    })
+}
+
+fn metadata_entry(metadata: &[(String, MetadataValue)], key: &str) -> Option<MetadataValue> {
+    metadata
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.clone())
+}
+
+fn metadata_text(key: &str) -> String {
+    read(|_, metadata| match metadata_entry(metadata, key) {
+        Some(MetadataValue::Text(text)) => text,
+        _ => String::new(),
+    })
+}
+
+fn metadata_nat(key: &str) -> u128 {
+    read(|_, metadata| match metadata_entry(metadata, key) {
+        Some(MetadataValue::Nat(nat)) => u128::try_from(nat.0).unwrap_or(0),
+        _ => 0,
+    })
+}
+
+#[ic_cdk::query]
+fn icrc1_name() -> String {
+    metadata_text("icrc1:name")
+}
+
+#[ic_cdk::query]
+fn icrc1_symbol() -> String {
+    metadata_text("icrc1:symbol")
+}
+
+#[ic_cdk::query]
+fn icrc1_decimals() -> u8 {
+    metadata_nat("icrc1:decimals") as u8
+}
+
+#[ic_cdk::query]
+fn icrc1_fee() -> u128 {
+    metadata_nat("icrc1:fee")
+}
+
+#[ic_cdk::query]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    read(|_, metadata| metadata.to_vec())
+}
+
+#[ic_cdk::query]
+fn icrc1_total_supply() -> u128 {
+    read(|replica, _| replica.total_supply)
+}
+
+// Shares are minted from, and burned by transfers to, the backend's default account
+#[ic_cdk::query]
+fn icrc1_minting_account() -> Option<Account> {
+    Some(Account {
+        owner: config().backend,
+        subaccount: None,
+    })
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> u128 {
+    read(|replica, _| {
+        replica
+            .balances
+            .get(&account_key(&account))
+            .copied()
+            .unwrap_or(0)
+    })
+}
+
+// Expired allowances read as zero
+#[ic_cdk::query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    let now = ic_cdk::api::time();
+    read(|replica, _| {
+        replica
+            .allowances
+            .get(&(account_key(&args.account), account_key(&args.spender)))
+            .filter(|allowance| allowance.expires_at.map(|t| t > now).unwrap_or(true))
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc1_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+#[ic_cdk::update]
+async fn icrc1_transfer(arg: TransferArg) -> Result<u128, TransferError> {
+    let result = backend_call("share_ledger_transfer", (ic_cdk::caller(), arg))
+        .await
+        .unwrap_or_else(|message| {
+            Err(TransferError::GenericError {
+                error_code: BACKEND_UNREACHABLE,
+                message,
+            })
+        });
+    synced(result).await
+}
+
+#[ic_cdk::update]
+async fn icrc2_approve(args: ApproveArgs) -> Result<u128, ApproveError> {
+    let result = backend_call("share_ledger_approve", (ic_cdk::caller(), args))
+        .await
+        .unwrap_or_else(|message| {
+            Err(ApproveError::GenericError {
+                error_code: BACKEND_UNREACHABLE,
+                message,
+            })
+        });
+    synced(result).await
+}
+
+#[ic_cdk::update]
+async fn icrc2_transfer_from(args: TransferFromArgs) -> Result<u128, TransferFromError> {
+    let result = backend_call("share_ledger_transfer_from", (ic_cdk::caller(), args))
+        .await
+        .unwrap_or_else(|message| {
+            Err(TransferFromError::GenericError {
+                error_code: BACKEND_UNREACHABLE,
+                message,
+            })
+        });
+    synced(result).await
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::types::subtype::{subtype, Gamma};
+    use candid_parser::utils::{instantiate_candid, service_equal, CandidSource};
+    use std::path::PathBuf;
+
+    const TOKEN: &str = "shares:batch_offer_1";
+
+    fn did_file(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    fn account(n: u8) -> AccountKey {
+        (Principal::from_slice(&[n; 29]), [0u8; 32])
+    }
+
+    fn account_value((owner, subaccount): AccountKey) -> Value {
+        let mut parts = vec![Value::Blob(owner.as_slice().to_vec())];
+        if subaccount != [0u8; 32] {
+            parts.push(Value::Blob(subaccount.to_vec()));
+        }
+        Value::Array(parts)
+    }
+
+    fn nat(n: u128) -> Value {
+        Value::Nat(Nat::from(n))
+    }
+
+    // A block as the backend's log records it
+    fn block(btype: &str, tx: Vec<(&str, Value)>) -> Value {
+        Value::Map(vec![
+            ("btype".to_string(), Value::Text(btype.to_string())),
+            ("ts".to_string(), nat(1)),
+            (
+                "tx".to_string(),
+                Value::Map(tx.into_iter().map(|(k, v)| (k.to_string(), v)).collect()),
+            ),
+        ])
+    }
+
+    fn share_block(btype: &str, token: &str, amount: u128, mut tx: Vec<(&str, Value)>) -> Value {
+        tx.push(("token", Value::Text(token.to_string())));
+        tx.push(("amt", nat(amount)));
+        block(btype, tx)
+    }
+
+    fn balance(replica: &Replica, account: AccountKey) -> u128 {
+        replica.balances.get(&account).copied().unwrap_or(0)
+    }
+
+    fn allowance(replica: &Replica, owner: AccountKey, spender: AccountKey) -> u128 {
+        replica
+            .allowances
+            .get(&(owner, spender))
+            .map(|allowance| allowance.allowance)
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn replays_the_tokens_operations() {
+        let (farmer, holder, spender, recipient) = (account(1), account(2), account(3), account(4));
+        let holder_sub = (holder.0, [7u8; 32]);
+        let blocks = [
+            share_block("1mint", TOKEN, 100, vec![("to", account_value(farmer))]),
+            share_block(
+                "1xfer",
+                TOKEN,
+                30,
+                vec![
+                    ("from", account_value(farmer)),
+                    ("to", account_value(holder_sub)),
+                ],
+            ),
+            share_block(
+                "2approve",
+                TOKEN,
+                20,
+                vec![
+                    ("from", account_value(holder_sub)),
+                    ("spender", account_value(spender)),
+                    ("expires_at", nat(5_000)),
+                ],
+            ),
+            share_block(
+                "2xfer",
+                TOKEN,
+                5,
+                vec![
+                    ("from", account_value(holder_sub)),
+                    ("to", account_value(recipient)),
+                    ("spender", account_value(spender)),
+                ],
+            ),
+            // Burned by the spender, then by the farmer with a transfer to the minting account
+            share_block(
+                "1burn",
+                TOKEN,
+                5,
+                vec![
+                    ("from", account_value(holder_sub)),
+                    ("spender", account_value(spender)),
+                ],
+            ),
+            share_block("1burn", TOKEN, 10, vec![("from", account_value(farmer))]),
+        ];
+        let mut replica = Replica::default();
+        for block in &blocks {
+            replica.apply(TOKEN, block);
+        }
+
+        assert_eq!(replica.total_supply, 85);
+        assert_eq!(balance(&replica, farmer), 60);
+        assert_eq!(balance(&replica, holder_sub), 20);
+        assert_eq!(balance(&replica, holder), 0);
+        assert_eq!(balance(&replica, recipient), 5);
+        assert_eq!(allowance(&replica, holder_sub, spender), 10);
+        assert_eq!(
+            replica.allowances[&(holder_sub, spender)].expires_at,
+            Some(5_000)
+        );
+
+        // Approving zero removes the allowance
+        replica.apply(
+            TOKEN,
+            &share_block(
+                "2approve",
+                TOKEN,
+                0,
+                vec![
+                    ("from", account_value(holder_sub)),
+                    ("spender", account_value(spender)),
+                ],
+            ),
+        );
+        assert!(replica.allowances.is_empty());
+    }
+
+    #[test]
+    fn skips_blocks_of_other_tokens_and_of_the_batch_collection() {
+        let mut replica = Replica::default();
+        replica.apply(
+            TOKEN,
+            &share_block(
+                "1mint",
+                "shares:batch_offer_2",
+                100,
+                vec![("to", account_value(account(1)))],
+            ),
+        );
+        replica.apply(
+            TOKEN,
+            &block(
+                "7mint",
+                vec![("tid", nat(1)), ("to", account_value(account(1)))],
+            ),
+        );
+        assert_eq!(replica.total_supply, 0);
+        assert!(replica.balances.is_empty());
+    }
+
+    #[test]
+    fn interface_matches_share_ledger_did() {
+        service_equal(
+            CandidSource::Text(&__export_service()),
+            CandidSource::File(&did_file("share_ledger.did")),
+        )
+        .expect("share_ledger.did is out of date");
+    }
+
+    // Every standard method has the reference signature and mode, so any ICRC-1 or ICRC-2 client,
+    // including canisters calling from update methods, can use this ledger
+    #[test]
+    fn interface_conforms_to_icrc1_and_icrc2() {
+        let generated = __export_service();
+        for standard in ["standards/ICRC-1.did", "standards/ICRC-2.did"] {
+            let (_, (mut env, service)) =
+                instantiate_candid(CandidSource::Text(&generated)).unwrap();
+            let (_, (reference_env, reference)) =
+                instantiate_candid(CandidSource::File(&did_file(standard))).unwrap();
+            let reference = env.merge_type(reference_env, reference);
+            subtype(&mut Gamma::new(), &env, &service, &reference)
+                .unwrap_or_else(|err| panic!("Not a subtype of {}: {}", standard, err));
+        }
+    }
+}